@@ -0,0 +1,206 @@
+//! A circuit breaker for `circuit_breaker!` and `circuit_breaker_async!`.
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The state of a [`CircuitBreaker`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls go through; consecutive failures are counted.
+    Closed,
+    /// Calls fail fast until the cooldown is over.
+    Open,
+    /// The cooldown is over and a single probe call decides whether to close or reopen.
+    HalfOpen,
+}
+
+/// The error of a call guarded by `circuit_breaker!`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CircuitError<E> {
+    /// The breaker is open (or probing), so the call was not made.
+    Open,
+    /// The call was made and failed.
+    Failed(E),
+}
+
+impl<E: fmt::Display> fmt::Display for CircuitError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CircuitError::Open => write!(f, "circuit breaker is open"),
+            CircuitError::Failed(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for CircuitError<E> {}
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen,
+}
+
+/// Stops calling a flaky dependency after `failure_threshold` consecutive failures: the
+/// breaker opens and calls fail fast with [`CircuitError::Open`] for `open_for`. After that,
+/// one probe call is let through (half-open); its success closes the breaker and its failure
+/// opens it again. State transitions are logged.
+///
+/// The breaker is `Sync`, so it can be shared across threads and tasks in an `Arc` or a
+/// `static`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// use std::time::Duration;
+///
+/// let breaker = CircuitBreaker::new(3, Duration::from_secs(30)).named("payments");
+/// fn charge() -> Result<u32, &'static str> { Err("unavailable") }
+///
+/// for _ in 0..3 {
+///     assert_eq!(circuit_breaker!(breaker, charge()), Err(CircuitError::Failed("unavailable")));
+/// }
+/// assert_eq!(breaker.state(), CircuitState::Open);
+/// assert_eq!(circuit_breaker!(breaker, charge()), Err(CircuitError::Open));
+/// ```
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: &'static str,
+    failure_threshold: u32,
+    open_for: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    /// A closed breaker opening after `failure_threshold` consecutive failures, for `open_for`.
+    pub fn new(failure_threshold: u32, open_for: Duration) -> Self {
+        Self {
+            name: "circuit_breaker",
+            failure_threshold: failure_threshold.max(1),
+            open_for,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Names the breaker in its log events.
+    pub fn named(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
+
+    /// The current state; an open breaker whose cooldown is over reports `HalfOpen`.
+    pub fn state(&self) -> CircuitState {
+        match *self.lock() {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { until } if Instant::now() < until => CircuitState::Open,
+            State::Open { .. } | State::HalfOpen => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Asks to make a call: `None` while the breaker is open or a probe is in flight,
+    /// otherwise a permit to report the outcome with. Dropping the permit without reporting
+    /// (e.g. when a probe future is cancelled) reopens a half-open breaker.
+    pub fn acquire(&self) -> Option<CircuitPermit<'_>> {
+        let mut state = self.lock();
+        match *state {
+            State::Closed { .. } => {}
+            State::Open { until } if Instant::now() < until => return None,
+            State::Open { .. } => {
+                crate::__zirv_log!(
+                    info,
+                    breaker = self.name,
+                    "Circuit breaker {} half-open, probing",
+                    self.name
+                );
+                *state = State::HalfOpen;
+            }
+            State::HalfOpen => return None,
+        }
+        Some(CircuitPermit {
+            breaker: self,
+            done: false,
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn record_success(&self) {
+        let mut state = self.lock();
+        match *state {
+            State::Closed { .. } => {}
+            State::HalfOpen => {
+                crate::__zirv_log!(
+                    info,
+                    breaker = self.name,
+                    "Circuit breaker {} closed",
+                    self.name
+                );
+            }
+            // A call admitted before the breaker opened doesn't close it.
+            State::Open { .. } => return,
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    fn record_failure(&self) {
+        let mut state = self.lock();
+        match *state {
+            State::Closed { failures } if failures + 1 < self.failure_threshold => {
+                *state = State::Closed {
+                    failures: failures + 1,
+                };
+            }
+            State::Closed { .. } | State::HalfOpen => {
+                crate::__zirv_log!(
+                    warn,
+                    breaker = self.name,
+                    open_ms = self.open_for.as_millis() as u64,
+                    "Circuit breaker {} opened for {:?}",
+                    self.name,
+                    self.open_for
+                );
+                *state = State::Open {
+                    until: Instant::now() + self.open_for,
+                };
+            }
+            // A call admitted before the breaker opened doesn't extend the cooldown.
+            State::Open { .. } => {}
+        }
+    }
+}
+
+/// Permission to make one call through a [`CircuitBreaker`], returned by
+/// [`CircuitBreaker::acquire`].
+#[derive(Debug)]
+pub struct CircuitPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    done: bool,
+}
+
+impl CircuitPermit<'_> {
+    /// Reports that the call succeeded, closing a half-open breaker.
+    pub fn success(mut self) {
+        self.done = true;
+        self.breaker.record_success();
+    }
+
+    /// Reports that the call failed.
+    pub fn failure(mut self) {
+        self.done = true;
+        self.breaker.record_failure();
+    }
+}
+
+impl Drop for CircuitPermit<'_> {
+    fn drop(&mut self) {
+        if !self.done && matches!(*self.breaker.lock(), State::HalfOpen) {
+            self.breaker.record_failure();
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Global hooks letting applications observe what the macros do, e.g. to feed metrics.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::timing::DurationFormat;
+
+/// A hook called with the file and line of every error handled by the error macros.
+pub type ErrorHook = fn(&'static str, u32);
+
+/// A sink receiving the label and duration of every measurement made by `time_it!`.
+pub type TimerSink = fn(&str, Duration);
+
+/// An observer called with the label, the number of attempts and whether the last attempt
+/// succeeded at the end of every retry loop of the retry macros.
+pub type RetryObserver = fn(&str, u32, bool);
+
+static ERROR_HOOK: OnceLock<ErrorHook> = OnceLock::new();
+static TIMER_SINK: OnceLock<TimerSink> = OnceLock::new();
+static RETRY_OBSERVER: OnceLock<RetryObserver> = OnceLock::new();
+
+/// Installs a global hook invoked on the error paths of `try_log!` (and its variants, such as
+/// `try_log_into!` and `try_log_none!`), `try_continue!` and `try_break!`, `log_error!` (and
+/// its variants, such as `log_error_async!`) and `unwrap_or_log!` (and its variants, such as
+/// `some_or_log!`), e.g. to increment an error counter.
+///
+/// The hook can only be installed once; later calls return the rejected hook as `Err`.
+/// Without a hook, the error paths behave exactly as before.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// # use zirv_macros::*;
+///
+/// static ERRORS: AtomicUsize = AtomicUsize::new(0);
+///
+/// set_error_hook(|_file, _line| {
+///     ERRORS.fetch_add(1, Ordering::Relaxed);
+/// })
+/// .unwrap();
+///
+/// let _ = log_error!(Err::<u32, &str>("failure"), 0);
+/// assert_eq!(ERRORS.load(Ordering::Relaxed), 1);
+/// ```
+pub fn set_error_hook(hook: ErrorHook) -> Result<(), ErrorHook> {
+    ERROR_HOOK.set(hook)
+}
+
+/// Invokes the error hook, if one is installed.
+#[doc(hidden)]
+pub fn report_error(file: &'static str, line: u32) {
+    if let Some(hook) = ERROR_HOOK.get() {
+        hook(file, line);
+    }
+}
+
+/// Installs a global sink receiving the measurements of `time_it!` and `time_it_async!`
+/// instead of printing them to stdout, e.g. to forward durations to metrics.
+///
+/// The sink can only be installed once; later calls return the rejected sink as `Err`.
+/// Without a sink, the timing macros print `"<label> took <duration>"` as before.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// set_timer_sink(|label, duration| {
+///     eprintln!("timing {}: {:?}", label, duration);
+/// })
+/// .unwrap();
+///
+/// let value = time_it!("startup", { 42 });
+/// assert_eq!(value, 42);
+/// ```
+pub fn set_timer_sink(sink: TimerSink) -> Result<(), TimerSink> {
+    TIMER_SINK.set(sink)
+}
+
+/// Passes a measurement to the timer sink, or prints it if no sink is installed.
+#[doc(hidden)]
+pub fn report_timing(label: &str, duration: Duration) {
+    report_timing_as(label, duration, DurationFormat::Human);
+}
+
+/// Like [`report_timing`], printing the duration in the given format.
+#[doc(hidden)]
+pub fn report_timing_as(label: &str, duration: Duration, format: DurationFormat) {
+    report(label, duration, format, &[]);
+}
+
+/// Like [`report_timing`], forwarding extra labels to the `metrics` histogram.
+#[doc(hidden)]
+pub fn report_timing_labelled(label: &str, duration: Duration, labels: &[(&'static str, String)]) {
+    report(label, duration, DurationFormat::Human, labels);
+}
+
+/// Like [`report_timing`], without recording the measurement, for callers that record it
+/// themselves.
+#[doc(hidden)]
+pub fn report_timing_unrecorded(label: &str, duration: Duration) {
+    emit(label, duration, DurationFormat::Human);
+}
+
+fn report(
+    label: &str,
+    duration: Duration,
+    format: DurationFormat,
+    labels: &[(&'static str, String)],
+) {
+    crate::timing::record_measurement(format_args!("{}", label), duration, labels);
+    emit(label, duration, format);
+}
+
+fn emit(label: &str, duration: Duration, format: DurationFormat) {
+    match TIMER_SINK.get() {
+        Some(sink) => sink(label, duration),
+        None => println!("{} took {}", label, format.format(duration)),
+    }
+}
+
+/// Installs a global observer called once per completed retry loop of `with_retry!`,
+/// `retry_async!` and the macros built on them, e.g. to record an attempts histogram and an
+/// exhaustion counter. The label is the `label: "..."` option of the call, or its `file:line`.
+///
+/// The observer can only be installed once; later calls return the rejected observer as
+/// `Err`. Without an observer, nothing is reported.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// set_retry_observer(|label, attempts, succeeded| {
+///     eprintln!("retry loop {} finished after {} attempts: {}", label, attempts, succeeded);
+/// })
+/// .unwrap();
+///
+/// let result: Result<u32, &str> = with_retry!(3, 10, Ok(42), label: "fetch_invoice");
+/// assert_eq!(result, Ok(42));
+/// ```
+pub fn set_retry_observer(observer: RetryObserver) -> Result<(), RetryObserver> {
+    RETRY_OBSERVER.set(observer)
+}
+
+/// Invokes the retry observer, if one is installed.
+#[doc(hidden)]
+pub fn report_retry(label: &str, attempts: u32, succeeded: bool) {
+    if let Some(observer) = RETRY_OBSERVER.get() {
+        observer(label, attempts, succeeded);
+    }
+}
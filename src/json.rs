@@ -0,0 +1,457 @@
+//! JSON support for `json_merge!`, `json_diff!`, `json_patch!` and `pretty_debug!`.
+
+use std::fmt;
+
+use serde_json::{Map, Value};
+
+/// How `json_merge!` combines two arrays found at the same key, chosen with `arrays: ...`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArrayMerge {
+    /// The array of the second value replaces the first one.
+    #[default]
+    Replace,
+    /// The elements of the second array are appended to those of the first one.
+    Concat,
+    /// Like `Concat`, but leaving out elements equal to one already in the result.
+    Union,
+}
+
+/// Merges the keys of the object `other` into the object `base`, doing nothing unless both
+/// are objects. With `deep`, objects found at the same key are merged recursively instead
+/// of replaced; arrays found at the same key are combined according to `arrays`, at every
+/// depth the merge reaches.
+pub fn merge(base: &mut Value, other: &Value, deep: bool, arrays: ArrayMerge) {
+    let (Some(base), Some(other)) = (base.as_object_mut(), other.as_object()) else {
+        return;
+    };
+    for (key, value) in other {
+        match base.get_mut(key) {
+            Some(existing) => merge_value(existing, value, deep, arrays),
+            None => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Applies `patch` to `target` as a JSON Merge Patch (RFC 7386): a `null` in the patch
+/// removes the key, objects are patched recursively, and any other value replaces the
+/// target, including at the top level.
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(target) = target else {
+        unreachable!("the target was just made an object");
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.shift_remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+fn merge_value(base: &mut Value, other: &Value, deep: bool, arrays: ArrayMerge) {
+    if deep && base.is_object() && other.is_object() {
+        merge(base, other, deep, arrays);
+    } else if let (Value::Array(base), Value::Array(other)) = (&mut *base, other) {
+        merge_arrays(base, other, arrays);
+    } else {
+        *base = other.clone();
+    }
+}
+
+fn merge_arrays(base: &mut Vec<Value>, other: &[Value], arrays: ArrayMerge) {
+    match arrays {
+        ArrayMerge::Replace => *base = other.to_vec(),
+        ArrayMerge::Concat => base.extend_from_slice(other),
+        ArrayMerge::Union => {
+            // Values aren't hashable, but the arrays merged this way are small.
+            let mut merged: Vec<Value> = Vec::with_capacity(base.len() + other.len());
+            for value in base.drain(..).chain(other.iter().cloned()) {
+                if !merged.contains(&value) {
+                    merged.push(value);
+                }
+            }
+            *base = merged;
+        }
+    }
+}
+
+/// A difference between two JSON values found by `json_diff!`, at a leaf or where the
+/// values stop having the same shape.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonDiff {
+    /// The JSON Pointer (RFC 6901) of the difference, empty for the root.
+    pub path: String,
+    /// The value on the left, or `None` if it was added on the right.
+    pub left: Option<Value>,
+    /// The value on the right, or `None` if it was removed from the left.
+    pub right: Option<Value>,
+}
+
+/// What kind of difference a [`JsonDiff`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl JsonDiff {
+    pub fn kind(&self) -> DiffKind {
+        match (&self.left, &self.right) {
+            (None, _) => DiffKind::Added,
+            (_, None) => DiffKind::Removed,
+            _ => DiffKind::Changed,
+        }
+    }
+}
+
+/// Renders the difference on one line, e.g. `~ /user/age: 41 -> 42`, with `+` for added and
+/// `-` for removed values.
+impl fmt::Display for JsonDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = if self.path.is_empty() {
+            "/"
+        } else {
+            &self.path
+        };
+        match (&self.left, &self.right) {
+            (Some(left), Some(right)) => write!(f, "~ {}: {} -> {}", path, left, right),
+            (None, Some(right)) => write!(f, "+ {}: {}", path, right),
+            (Some(left), None) => write!(f, "- {}: {}", path, left),
+            (None, None) => write!(f, "  {}", path),
+        }
+    }
+}
+
+/// Lists the differences between `left` and `right`, recursing into objects and comparing
+/// arrays position by position.
+pub fn diff(left: &Value, right: &Value) -> Vec<JsonDiff> {
+    let mut diffs = Vec::new();
+    diff_at(&mut String::new(), left, right, &mut diffs);
+    diffs
+}
+
+/// Renders differences for logs, one per line as [`JsonDiff`]'s `Display` does.
+pub fn pretty(diffs: &[JsonDiff]) -> String {
+    diffs
+        .iter()
+        .map(JsonDiff::to_string)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn diff_at(path: &mut String, left: &Value, right: &Value, diffs: &mut Vec<JsonDiff>) {
+    match (left, right) {
+        (Value::Object(left), Value::Object(right)) => {
+            for (key, value) in left {
+                with_segment(path, key, |path| match right.get(key) {
+                    Some(other) => diff_at(path, value, other, diffs),
+                    None => diffs.push(change(path, Some(value), None)),
+                });
+            }
+            for (key, value) in right {
+                if !left.contains_key(key) {
+                    with_segment(path, key, |path| {
+                        diffs.push(change(path, None, Some(value)));
+                    });
+                }
+            }
+        }
+        (Value::Array(left), Value::Array(right)) => {
+            for i in 0..left.len().max(right.len()) {
+                with_segment(path, &i.to_string(), |path| {
+                    match (left.get(i), right.get(i)) {
+                        (Some(value), Some(other)) => diff_at(path, value, other, diffs),
+                        (value, other) => diffs.push(change(path, value, other)),
+                    }
+                });
+            }
+        }
+        _ if left != right => diffs.push(change(path, Some(left), Some(right))),
+        _ => {}
+    }
+}
+
+/// Runs `f` with `path` extended by the escaped pointer segment for `key`.
+fn with_segment(path: &mut String, key: &str, f: impl FnOnce(&mut String)) {
+    let len = path.len();
+    path.push('/');
+    path.push_str(&key.replace('~', "~0").replace('/', "~1"));
+    f(path);
+    path.truncate(len);
+}
+
+fn change(path: &str, left: Option<&Value>, right: Option<&Value>) -> JsonDiff {
+    JsonDiff {
+        path: path.to_string(),
+        left: left.cloned(),
+        right: right.cloned(),
+    }
+}
+
+/// Replaces the values of the keys in `keys` with `"***"` at any depth of `value`, arrays
+/// included, matching keys ignoring ASCII case. Used by `pretty_debug!(..., redact: [...])`.
+pub fn redact(value: &mut Value, keys: &[&str]) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if keys
+                    .iter()
+                    .any(|redacted| redacted.eq_ignore_ascii_case(key))
+                {
+                    *value = Value::String("***".to_string());
+                } else {
+                    redact(value, keys);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                redact(item, keys);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Size limits for `pretty_debug!` output, each `None` for no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    /// The number of characters strings are truncated to.
+    pub max_string: Option<usize>,
+    /// The number of elements arrays are truncated to.
+    pub max_array: Option<usize>,
+    /// The number of bytes the whole output is capped at.
+    pub max_bytes: Option<usize>,
+}
+
+impl Limits {
+    pub const DEFAULT_MAX_STRING: usize = 256;
+    pub const DEFAULT_MAX_ARRAY: usize = 20;
+    pub const DEFAULT_MAX_BYTES: usize = 16 * 1024;
+
+    /// Fills the limits that aren't set with the defaults.
+    pub fn or_defaults(self) -> Self {
+        Limits {
+            max_string: self.max_string.or(Some(Self::DEFAULT_MAX_STRING)),
+            max_array: self.max_array.or(Some(Self::DEFAULT_MAX_ARRAY)),
+            max_bytes: self.max_bytes.or(Some(Self::DEFAULT_MAX_BYTES)),
+        }
+    }
+}
+
+/// Truncates the strings and arrays of `value`, at any depth, that exceed `limits`.
+pub fn truncate(value: &mut Value, limits: &Limits) {
+    match value {
+        Value::String(string) => {
+            if let Some(max) = limits.max_string
+                && let Some((cut, _)) = string.char_indices().nth(max)
+            {
+                let dropped = string[cut..].chars().count();
+                string.truncate(cut);
+                string.push_str(&format!("…(+{} chars)", dropped));
+            }
+        }
+        Value::Array(items) => {
+            if let Some(max) = limits.max_array
+                && items.len() > max
+            {
+                let dropped = items.len() - max;
+                items.truncate(max);
+                items.push(Value::String(format!("…(+{} items)", dropped)));
+            }
+            for item in items {
+                truncate(item, limits);
+            }
+        }
+        Value::Object(map) => {
+            for value in map.values_mut() {
+                truncate(value, limits);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Cuts `rendered` down to `limits.max_bytes`, on a character boundary, ending it with the
+/// number of bytes cut.
+pub fn cap(rendered: &mut String, limits: &Limits) {
+    let Some(max) = limits.max_bytes else {
+        return;
+    };
+    if rendered.len() <= max {
+        return;
+    }
+    // Reserve room for the marker as long as it can get, so the result fits in `max`.
+    let reserved = format!("…(+{} bytes)", rendered.len()).len();
+    let mut keep = max.saturating_sub(reserved);
+    while !rendered.is_char_boundary(keep) {
+        keep -= 1;
+    }
+    let marker = format!("…(+{} bytes)", rendered.len() - keep);
+    rendered.truncate(keep);
+    rendered.push_str(&marker);
+}
+
+/// The error of `json_patch!`: which operation of the patch failed and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchError {
+    /// The index of the failed operation in the patch.
+    pub index: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "patch operation {} failed: {}", self.index, self.reason)
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+/// Applies the JSON Patch (RFC 6902) `patch`, an array of `add`, `remove`, `replace`, `move`,
+/// `copy` and `test` operations, to `doc` and returns the result. Stops at the first
+/// operation that fails, logging a failed `test` at debug level.
+pub fn patch(mut doc: Value, patch: &Value) -> Result<Value, PatchError> {
+    let Some(operations) = patch.as_array() else {
+        return Err(PatchError {
+            index: 0,
+            reason: "the patch is not an array".to_string(),
+        });
+    };
+    for (index, operation) in operations.iter().enumerate() {
+        apply(&mut doc, operation).map_err(|reason| PatchError { index, reason })?;
+    }
+    Ok(doc)
+}
+
+fn apply(doc: &mut Value, operation: &Value) -> Result<(), String> {
+    let member = |name: &str| {
+        operation
+            .get(name)
+            .ok_or_else(|| format!("missing `{}` member", name))
+    };
+    let pointer = |name: &str| {
+        member(name)?
+            .as_str()
+            .ok_or_else(|| format!("`{}` is not a string", name))
+    };
+    let op = pointer("op")?;
+    let path = pointer("path")?;
+    match op {
+        "add" => add(doc, path, member("value")?.clone()),
+        "remove" => remove(doc, path).map(drop),
+        "replace" => {
+            let target = doc
+                .pointer_mut(path)
+                .ok_or_else(|| format!("no value at {}", path))?;
+            *target = member("value")?.clone();
+            Ok(())
+        }
+        "move" => {
+            let from = pointer("from")?;
+            if path.starts_with(from) && path[from.len()..].starts_with('/') {
+                return Err(format!("cannot move {} into itself", from));
+            }
+            let value = remove(doc, from)?;
+            add(doc, path, value)
+        }
+        "copy" => {
+            let from = pointer("from")?;
+            let value = doc
+                .pointer(from)
+                .ok_or_else(|| format!("no value at {}", from))?
+                .clone();
+            add(doc, path, value)
+        }
+        "test" => {
+            let expected = member("value")?;
+            let actual = doc.pointer(path);
+            if actual == Some(expected) {
+                return Ok(());
+            }
+            let found = actual.map(Value::to_string);
+            crate::__zirv_log!(
+                debug,
+                path = %path,
+                expected = %expected,
+                actual = found.as_deref(),
+                "JSON Patch test failed at {}: expected {}",
+                path,
+                expected
+            );
+            Err(match actual {
+                Some(actual) => format!("test failed at {}: {} != {}", path, actual, expected),
+                None => format!("test failed at {}: no value", path),
+            })
+        }
+        other => Err(format!("unknown operation `{}`", other)),
+    }
+}
+
+/// Splits a pointer into the pointer of its parent and its unescaped last token.
+fn split_last(path: &str) -> Result<(&str, String), String> {
+    let (parent, token) = path
+        .rsplit_once('/')
+        .ok_or_else(|| format!("invalid pointer {:?}", path))?;
+    Ok((parent, token.replace("~1", "/").replace("~0", "~")))
+}
+
+/// Parses an array index the way `Value::pointer` does, rejecting leading zeros and signs.
+fn parse_index(token: &str) -> Option<usize> {
+    if token.starts_with('+') || (token.starts_with('0') && token.len() > 1) {
+        return None;
+    }
+    token.parse().ok()
+}
+
+fn add(doc: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    if path.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+    let (parent, token) = split_last(path)?;
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => {
+            map.insert(token, value);
+            Ok(())
+        }
+        Some(Value::Array(items)) => {
+            let index = if token == "-" {
+                items.len()
+            } else {
+                parse_index(&token)
+                    .filter(|&index| index <= items.len())
+                    .ok_or_else(|| format!("invalid array index at {}", path))?
+            };
+            items.insert(index, value);
+            Ok(())
+        }
+        Some(_) => Err(format!("cannot add to a scalar at {}", parent)),
+        None => Err(format!("no value at {}", parent)),
+    }
+}
+
+fn remove(doc: &mut Value, path: &str) -> Result<Value, String> {
+    if path.is_empty() {
+        return Err("cannot remove the whole document".to_string());
+    }
+    let (parent, token) = split_last(path)?;
+    let removed = match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => map.shift_remove(&token),
+        Some(Value::Array(items)) => parse_index(&token)
+            .filter(|&index| index < items.len())
+            .map(|index| items.remove(index)),
+        _ => None,
+    };
+    removed.ok_or_else(|| format!("no value at {}", path))
+}
@@ -7,20 +7,66 @@
 //! ## Features
 //!
 //! - **Error Handling & Assertions:**
-//!   - `try_log!`: Evaluates an expression returning a `Result`, logs on error, and returns an error.
-//!   - `unwrap_or_log!`: Unwraps a result and uses a default value if it fails, logging the error.
+//!   - `try_log!`: Evaluates an expression returning a `Result`, logs on error via tracing, and returns an error,
+//!     optionally prefixed with a context message or mapped through a closure.
+//!   - `try_log_into!`: Like `try_log!`, but converts the error with `From` instead of stringifying it.
+//!   - `try_log_anyhow!`: Like `try_log!`, but returns an `anyhow::Error` with context (`anyhow` feature).
+//!   - `try_log_async!`: Awaits a future and behaves like `try_log_into!` on its output.
+//!   - `try_log_http!`: Returns an error `HttpResponse` from an Actix handler (`actix` feature).
+//!   - `try_log_none!` / `try_log_opt!`: Like `try_log!`, but return `None` early for functions returning
+//!     `Option`; `try_log_opt!` also unwraps `Option`s, logging a warning on `None`.
+//!   - `ok_or_return!`: Logs an error and returns a given value from the enclosing function.
+//!   - `try_continue!` / `try_break!`: Log an error and `continue` / `break` the enclosing loop.
+//!   - `tap_err!` / `tap_ok!`: Log the outcome of a `Result` and pass it through unchanged.
+//!   - `trace_result!`: Logs the outcome of a named operation at info or error level and passes the `Result` through.
+//!   - `unwrap_or_log!`: Unwraps a result and uses a default value if it fails, logging a warning.
+//!   - `unwrap_or_default_log!`: Like `unwrap_or_log!`, but uses `Default::default()`.
+//!   - `some_or_log!`: Unwraps an `Option` and uses a default if it is `None`, logging a warning.
+//!   - `unwrap_or_else_log!`: Like `unwrap_or_log!`, but computes the default lazily with a closure.
 //!   - `assert_msg!`: Asserts a condition with a custom error message.
 //!
 //! - **Timing & Instrumentation:**
 //!   - `time_it!`: Measures and logs the execution time of a code block.
+//!   - `time_it_with!`: Like `time_it!`, but also returns the measured `Duration`.
+//!   - `time_it_async!`: Measures and logs the execution time of a future.
 //!   - `log_duration!`: Logs the duration of a code block using tracing.
-//!   - `span_wrap!`: Wraps a block of code inside a tracing span.
-//!   - `call_with_trace!`: Calls a function inside a tracing span.
+//!   - `log_duration_async!`: Logs the duration of a future using tracing.
+//!   - `stopwatch!`: Times the steps of an operation as named laps, logging each and the total.
+//!   - `trace_guard!`: Returns a guard logging entering and leaving the current scope, with the time spent.
+//!   - `deadline!`: Runs a block and returns an error with its result if it overran a budget.
+//!   - `timeout_async!`: Awaits a future with a timeout, logging a warning if it elapses.
+//!   - `span_wrap!`: Wraps a block of code inside a tracing span recording its duration, optionally named at runtime, logging panics or sampled.
+//!   - `span_wrap_async!`: Runs a future inside a tracing span.
+//!   - `spawn_traced!`: Spawns a tokio task inside the current tracing span.
+//!   - `request_id!`: Records a new or propagated request id on the current span and returns it.
+//!   - `record_field!`: Records late-bound values on fields of the current span, reporting fields it cannot record.
+//!   - `log_context!`: Attaches key/value fields to every log emitted inside a block or future.
+//!   - `http_span!`: Creates a span for an Actix request with its method, route, target and request id (`actix` feature).
+//!   - `call_with_trace!`: Calls a function inside a tracing span, optionally recording its arguments and `Result` outcome on it or logging panics.
+//!   - `call_with_trace_async!`: Calls an async function and awaits it inside a tracing span.
+//!
+//! - **Fallbacks:**
+//!   - `log_error!`: Logs an error and substitutes a default value.
+//!   - `log_warn!` / `log_debug!`: Like `log_error!`, but log at warn / debug level.
+//!   - `log_error_with!`: Like `log_error!`, but runs a callback on the error first.
+//!   - `log_error_async!`: Logs an error from a future and awaits a fallback future instead.
+//!   - With the `timing-stats` feature, `timing_stats()` returns cumulative statistics per label.
+//!   - With the `derive` feature, `#[timed]` logs the duration of every call to a function.
+//!   - With the `derive` feature, `#[traced]` runs every call to a function in a span recording its arguments.
+//!   - With the `metrics` feature, `time_it!` and `log_duration!` also record `metrics` histograms.
+//!   - With the `timing-disabled` feature, `time_it!` and `log_duration!` expand to their block alone.
+//!
+//! - **Logging:**
+//!   - `zinfo!` / `zwarn!` / `zerror!`: Log like `tracing`, but with the `zirv` target and file and line fields.
+//!   - Logging goes through `tracing` with the default `tracing` feature. Without it, the `log` feature logs through the `log` crate instead, with the fields appended to the message as `key=value`, and without either the events are written to stderr. The span macros and `#[traced]` require `tracing`.
 //!
 //! - **JSON & Environment Helpers:**
-//!   - `json_merge!`: Merges two JSON objects.
+//!   - `json_merge!`: Merges two or more JSON objects, optionally recursively and replacing, concatenating or deduplicating arrays, or as JSON Merge Patches.
+//!   - `json_diff!`: Lists the added, removed and changed leaves between two JSON values.
+//!   - `json_get!`: Extracts a typed value at a JSON Pointer, falling back to a default.
+//!   - `json_patch!`: Applies a JSON Patch (RFC 6902) to a document, reporting the operation that failed.
 //!   - `parse_env!`: Reads an environment variable with a default fallback.
-//!   - `pretty_debug!`: Pretty-prints a JSON representation of an object.
+//!   - `pretty_debug!`: Logs a pretty-printed JSON representation of an object as one event, or prints it, optionally redacting keys and truncating large values.
 //!
 //! - **SQL Debugging:**
 //!   - `debug_query!`: Logs the full SQL query string before executing it.
@@ -28,6 +74,16 @@
 //! - **Retry Utilities:**
 //!   - `with_retry!`: Synchronously retries an expression a fixed number of times.
 //!   - `retry_async!`: Asynchronously retries an expression a fixed number of times.
+//!   - `retry_until!` / `retry_until_async!`: Retry until an `Instant` deadline instead of a number of attempts.
+//!   - `with_retry_counted!` / `retry_async_counted!`: Also return the number of the attempt that succeeded.
+//!   - `hedge_async!`: Starts parallel attempts of a slow request and takes the first success.
+//!   - `with_retry_catching!`: Retries attempts that panic like failed ones.
+//!   - `circuit_breaker!` / `circuit_breaker_async!`: Fail fast with a `CircuitBreaker` after repeated failures.
+//!   - With the `cancellation` feature, `retry_async!` can be cancelled with a `CancellationToken`.
+//!   - With the `reqwest` feature, `retry_http!` retries HTTP requests, honoring `Retry-After`.
+//!   - With the `sqlx` feature, `retry_db!` retries transient database errors, as decided by `is_transient_db_error`.
+//!   - With the `backoff` feature, `retry_async!(policy: ..., expr)` takes its delays from a `backoff::backoff::Backoff`.
+//!   - With the `uuid` feature, `request_id!` generates UUID v4 request ids.
 //!
 //! ## Usage
 //!
@@ -46,7 +102,33 @@
 
 /// Attempts to evaluate an expression returning a `Result`.
 /// If the result is `Ok`, returns the value.
-/// Otherwise, logs an error with file and line info and returns an error as a `String`.
+/// Otherwise, logs an error via `tracing::error!` (with `error`, `file` and `line` fields)
+/// and returns an error as a `String`.
+/// If the error implements `std::error::Error`, its `source()` chain is logged as well,
+/// one cause per line in the `causes` field.
+/// With the `backtrace` feature enabled and `RUST_BACKTRACE` set, a backtrace is captured
+/// and logged in a separate `backtrace` field.
+///
+/// An optional context message can be passed as a second argument; it is prepended
+/// to both the log line and the returned error, e.g. `"loading user profile: row not found"`.
+///
+/// An error code can be attached with `code = ...` (any expression displaying as the code,
+/// e.g. a `&str` or `String`). It is recorded as the `error_code` field and prefixed onto the
+/// returned error, e.g. `"[DB_TIMEOUT] connection timed out"`.
+///
+/// Trailing `key = value` pairs are forwarded as fields on the tracing event, with the usual
+/// `%` (Display) and `?` (Debug) sigils, e.g. `try_log!(fetch_user(id), op = "fetch_user", user_id = id)`.
+///
+/// The log level defaults to `error` and can be chosen at the call site with a leading
+/// `level:` argument (`error`, `warn`, `info`, `debug` or `trace`), e.g.
+/// `try_log!(level: warn, find_user(id))`. Unknown levels fail to compile.
+///
+/// Alternatively, the second argument can be a closure mapping the original error into the
+/// function's error type, e.g. `try_log!(db_call(), |e| AppError::Database(e))`. The original
+/// error is logged with its `Debug` representation before being mapped, so it only needs
+/// to implement `Debug`. Any other `FnOnce(E) -> F`, such as an enum variant constructor or a
+/// closure held in a variable, is passed with `map_err:`, e.g.
+/// `try_log!(db_call(), map_err: AppError::Database)`.
 ///
 /// # Examples
 ///
@@ -59,479 +141,8526 @@
 ///     Ok(())
 /// }
 /// ```
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// fn load() -> Result<u32, String> {
+///     let value = try_log!(Err::<u32, &str>("row not found"), "loading user profile");
+///     Ok(value)
+/// }
+/// assert_eq!(load().unwrap_err(), "loading user profile: row not found");
+/// ```
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// #[derive(Debug)]
+/// enum AppError { Io(std::io::ErrorKind) }
+///
+/// fn read() -> Result<String, AppError> {
+///     let content = try_log!(std::fs::read_to_string("/does/not/exist"), |e| AppError::Io(e.kind()));
+///     Ok(content)
+/// }
+/// assert!(matches!(read(), Err(AppError::Io(std::io::ErrorKind::NotFound))));
+/// ```
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// fn find(id: u32) -> Result<u32, String> {
+///     let value = try_log!(level: warn, Err::<u32, &str>("not found"), "looking up user");
+///     Ok(value + id)
+/// }
+/// assert!(find(1).is_err());
+/// ```
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// fn fetch_user(id: u32) -> Result<String, String> {
+///     let name = try_log!(Err::<String, &str>("timeout"), op = "fetch_user", user_id = id);
+///     Ok(name)
+/// }
+/// assert_eq!(fetch_user(7).unwrap_err(), "timeout");
+/// ```
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// fn connect() -> Result<(), String> {
+///     try_log!(Err::<(), &str>("connection timed out"), code = "DB_TIMEOUT");
+///     Ok(())
+/// }
+/// assert_eq!(connect().unwrap_err(), "[DB_TIMEOUT] connection timed out");
+/// ```
+///
+/// ```rust,compile_fail
+/// # use zirv_macros::*;
+/// fn find() -> Result<u32, String> {
+///     Ok(try_log!(level: wrn, Err::<u32, &str>("not found")))
+/// }
+/// ```
 #[macro_export]
 macro_rules! try_log {
-    ($expr:expr) => {
+    (level: $level:ident, $expr:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                $crate::__zirv_log!(
+                    $level,
+                    error = ?err,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    backtrace = $crate::__private::capture_backtrace().map($crate::__private::display),
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - {:?}",
+                    file!(),
+                    line!(),
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                return Err(err.to_string());
+            }
+        }
+    };
+    (level: $level:ident, $expr:expr, $(move)? |$err:pat_param| $mapper:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                $crate::__zirv_log!(
+                    $level,
+                    error = ?err,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    backtrace = $crate::__private::capture_backtrace().map($crate::__private::display),
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - {:?}",
+                    file!(),
+                    line!(),
+                    err
+                );
+                let $err = err;
+                $crate::hooks::report_error(file!(), line!());
+                return Err($mapper);
+            }
+        }
+    };
+    (level: $level:ident, $expr:expr, map_err: $f:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                $crate::__zirv_log!(
+                    $level,
+                    error = ?err,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    backtrace = $crate::__private::capture_backtrace().map($crate::__private::display),
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - {:?}",
+                    file!(),
+                    line!(),
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                return Err(($f)(err));
+            }
+        }
+    };
+    (level: $level:ident, $expr:expr, code = $code:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                let code = $code;
+                $crate::__zirv_log!(
+                    $level,
+                    error = ?err,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    backtrace = $crate::__private::capture_backtrace().map($crate::__private::display),
+                    error_code = %code,
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - [{}] {:?}",
+                    file!(),
+                    line!(),
+                    code,
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                return Err(format!("[{}] {}", code, err));
+            }
+        }
+    };
+    (level: $level:ident, $expr:expr, $($key:ident).+ = $($fields:tt)+) => {
         match $expr {
             Ok(val) => val,
             Err(err) => {
-                eprintln!("Error at {}:{} - {:?}", file!(), line!(), err);
+                $crate::__zirv_log!(
+                    $level,
+                    error = ?err,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    backtrace = $crate::__private::capture_backtrace().map($crate::__private::display),
+                    file = file!(),
+                    line = line!(),
+                    $($key).+ = $($fields)+,
+                    "Error at {}:{} - {:?}",
+                    file!(),
+                    line!(),
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
                 return Err(err.to_string());
             }
         }
     };
+    (level: $level:ident, $expr:expr, $context:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                let context = $context;
+                $crate::__zirv_log!(
+                    $level,
+                    error = ?err,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    backtrace = $crate::__private::capture_backtrace().map($crate::__private::display),
+                    context = %context,
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - {}: {:?}",
+                    file!(),
+                    line!(),
+                    context,
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                return Err(format!("{}: {}", context, err));
+            }
+        }
+    };
+    ($expr:expr) => {
+        $crate::try_log!(level: error, $expr)
+    };
+    ($expr:expr, move |$err:pat_param| $mapper:expr) => {
+        $crate::try_log!(level: error, $expr, move |$err| $mapper)
+    };
+    ($expr:expr, |$err:pat_param| $mapper:expr) => {
+        $crate::try_log!(level: error, $expr, |$err| $mapper)
+    };
+    ($expr:expr, map_err: $f:expr) => {
+        $crate::try_log!(level: error, $expr, map_err: $f)
+    };
+    ($expr:expr, code = $code:expr) => {
+        $crate::try_log!(level: error, $expr, code = $code)
+    };
+    ($expr:expr, $($key:ident).+ = $($fields:tt)+) => {
+        $crate::try_log!(level: error, $expr, $($key).+ = $($fields)+)
+    };
+    ($expr:expr, $context:expr) => {
+        $crate::try_log!(level: error, $expr, $context)
+    };
 }
 
-/// Attempts to unwrap a result, returning a default value if an error occurs.
-/// Logs an error with file and line info if the unwrap fails.
+/// Attempts to evaluate an expression returning a `Result`, like [`try_log!`], but keeps the
+/// original error type instead of stringifying it.
+/// On error, logs with file and line info and returns `Err(err.into())`, so the error is
+/// converted through `From` exactly like the `?` operator does.
 ///
 /// # Examples
 ///
 /// ```rust
 /// # use zirv_macros::*;
-/// let value = unwrap_or_log!(Ok::<String, &str>("value".to_string()), "default".to_string());
-/// assert_eq!(value, "value".to_string());
+/// #[derive(Debug)]
+/// struct DbError;
+///
+/// #[derive(Debug)]
+/// enum AppError { Database(DbError) }
+///
+/// impl From<DbError> for AppError {
+///     fn from(err: DbError) -> Self { AppError::Database(err) }
+/// }
+///
+/// fn load() -> Result<u32, AppError> {
+///     let value = try_log_into!(Err::<u32, DbError>(DbError));
+///     Ok(value)
+/// }
+/// assert!(matches!(load(), Err(AppError::Database(DbError))));
 /// ```
 #[macro_export]
-macro_rules! unwrap_or_log {
-    ($expr:expr, $default:expr) => {
+macro_rules! try_log_into {
+    ($expr:expr) => {
         match $expr {
             Ok(val) => val,
             Err(err) => {
-                eprintln!(
-                    "Unwrap failed at {}:{} - {:?}. Using default: {:?}",
+                $crate::__zirv_log!(
+                    error,
+                    error = ?err,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - {:?}",
                     file!(),
                     line!(),
-                    err,
-                    $default
+                    err
                 );
-                $default
+                $crate::hooks::report_error(file!(), line!());
+                return Err(::core::convert::From::from(err));
+            }
+        }
+    };
+    ($expr:expr, $context:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                let context = $context;
+                $crate::__zirv_log!(
+                    error,
+                    error = ?err,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    context = %context,
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - {}: {:?}",
+                    file!(),
+                    line!(),
+                    context,
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                return Err(::core::convert::From::from(err));
             }
         }
     };
 }
 
-/// Measures the execution time of a block of code and prints the duration with the provided label.
+/// Attempts to evaluate an expression returning a `Result`, logging on error like [`try_log!`]
+/// and returning early with an `anyhow::Error` carrying the given context.
+/// Keeps the original error as the source, so backtraces and `{:#}` formatting work as usual.
+///
+/// Only available with the `anyhow` feature enabled.
 ///
 /// # Examples
 ///
 /// ```rust
 /// # use zirv_macros::*;
-/// let result = time_it!("Computation", { 42 });
-/// assert_eq!(result, 42);
+/// fn load() -> anyhow::Result<u32> {
+///     let value = try_log_anyhow!("x".parse::<u32>(), "parsing user id");
+///     Ok(value)
+/// }
+/// let err = load().unwrap_err();
+/// assert!(format!("{:#}", err).starts_with("parsing user id: "));
 /// ```
+#[cfg(feature = "anyhow")]
 #[macro_export]
-macro_rules! time_it {
-    ($label:expr, $block:block) => {{
-        let start = std::time::Instant::now();
-        let result = { $block };
-        let duration = start.elapsed();
-        println!("{} took {:?}", $label, duration);
-        result
-    }};
+macro_rules! try_log_anyhow {
+    ($expr:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                $crate::__zirv_log!(
+                    error,
+                    error = ?err,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - {:?}",
+                    file!(),
+                    line!(),
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                return Err($crate::__private::anyhow::Error::from(err));
+            }
+        }
+    };
+    ($expr:expr, $context:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                let context = $context;
+                $crate::__zirv_log!(
+                    error,
+                    error = ?err,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    context = %context,
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - {}: {:?}",
+                    file!(),
+                    line!(),
+                    context,
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                return Err($crate::__private::anyhow::Error::from(err).context(context));
+            }
+        }
+    };
 }
 
-/// Merges two `serde_json::Value` objects (expected to be JSON objects).
-/// Keys in the second object override those in the first.
+/// Attempts to evaluate an expression returning a `Result` inside an Actix handler.
+/// On error, logs with file and line info and returns early with an `HttpResponse` built
+/// from the given status code (500 by default) and a JSON body `{"error": "<message>"}`.
+///
+/// Only available with the `actix` feature enabled.
 ///
 /// # Examples
 ///
 /// ```rust
 /// # use zirv_macros::*;
-/// use serde_json::json;
-/// let a = json!({ "a": 1, "b": 2 });
-/// let b = json!({ "b": 3, "c": 4 });
-/// let merged = json_merge!(a, b);
-/// assert_eq!(merged["a"], 1);
-/// assert_eq!(merged["b"], 3);
-/// assert_eq!(merged["c"], 4);
+/// use actix_web::{HttpResponse, http::StatusCode};
+///
+/// async fn get_user() -> HttpResponse {
+///     let user = try_log_http!(Err::<String, &str>("user not found"), StatusCode::NOT_FOUND);
+///     HttpResponse::Ok().body(user)
+/// }
 /// ```
+#[cfg(feature = "actix")]
 #[macro_export]
-macro_rules! json_merge {
-    ($base:expr, $other:expr) => {{
-        let mut base = $base;
-        if let (Some(base_obj), Some(other_obj)) = (base.as_object_mut(), $other.as_object()) {
-            for (k, v) in other_obj {
-                base_obj.insert(k.clone(), v.clone());
+macro_rules! try_log_http {
+    ($expr:expr) => {
+        $crate::try_log_http!(
+            $expr,
+            $crate::__private::actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
+        )
+    };
+    ($expr:expr, $status:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                let status = $status;
+                $crate::__zirv_log!(
+                    error,
+                    error = ?err,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    status = %status,
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - {:?}",
+                    file!(),
+                    line!(),
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                return $crate::__private::actix_web::HttpResponse::build(status)
+                    .json(serde_json::json!({ "error": err.to_string() }));
             }
         }
-        base
-    }};
+    };
 }
 
-/// Logs the SQL query string (and optionally its bind parameters) before executing it.
-/// Useful for debugging SQLx queries.
+/// Creates an info-level `http_request` span for an Actix request, taking an `HttpRequest`
+/// or a `ServiceRequest` (e.g. in a middleware), to run the handling of the request in with
+/// [`span_wrap_async!`] or `tracing::Instrument`. The span records:
+///
+/// - `http.method`: the request method.
+/// - `http.route`: the route pattern that matched, e.g. `/users/{id}`, if any.
+/// - `http.target`: the path and query of the request.
+/// - `request_id`: the `x-request-id` header, or a new id from [`new_request_id`] if it is
+///   missing.
+///
+/// Only available with the `actix` and `tracing` features enabled.
 ///
 /// # Examples
 ///
 /// ```rust
 /// # use zirv_macros::*;
-/// // Dummy struct to simulate a query with a sql() method.
-/// struct DummyQuery { sql: &'static str }
-/// impl DummyQuery {
-///     fn sql(&self) -> &str { self.sql }
+/// use actix_web::{HttpRequest, HttpResponse};
+/// use tracing::Instrument;
+///
+/// async fn get_user(req: HttpRequest) -> HttpResponse {
+///     async {
+///         tracing::info!("loading user");
+///         HttpResponse::Ok().finish()
+///     }
+///     .instrument(http_span!(req))
+///     .await
 /// }
-/// let query = DummyQuery { sql: "SELECT * FROM users" };
-/// let _ = debug_query!(query);
 /// ```
+#[cfg(all(feature = "actix", feature = "tracing"))]
 #[macro_export]
-macro_rules! debug_query {
-    ($query:expr) => {{
-        let sql = $query.sql();
-        println!("SQL Query: {}", sql);
-        $query
+macro_rules! http_span {
+    ($req:expr) => {{
+        let req = $crate::__private::AsHttpRequest::http_request(&$req);
+        let request_id = req
+            .headers()
+            .get("x-request-id")
+            .and_then(|value| value.to_str().ok())
+            .map_or_else($crate::new_request_id, str::to_owned);
+        $crate::__private::tracing::info_span!(
+            "http_request",
+            http.method = %req.method(),
+            http.route = req.match_pattern().as_deref(),
+            http.target = req.uri().path_and_query().map_or(req.path(), |target| target.as_str()),
+            request_id = %request_id
+        )
     }};
 }
 
-/// Retries a synchronous expression (returning a `Result`) a specified number of times,
-/// waiting a fixed number of milliseconds between attempts.
+/// Awaits a future resolving to a `Result` and behaves like [`try_log_into!`] on the output:
+/// the value is returned on `Ok`, and on `Err` the error is logged with file and line info
+/// and returned early as `Err(err.into())`.
+/// Intended for async handlers returning typed errors, e.g. `Result<_, actix_web::Error>`.
 ///
 /// # Examples
 ///
 /// ```rust
 /// # use zirv_macros::*;
-/// fn dummy_op() -> Result<u32, &'static str> { Ok(42) }
-/// let result = with_retry!(3, 10, dummy_op());
-/// assert_eq!(result.unwrap(), 42);
+/// async fn fetch(id: u32) -> Result<u32, std::io::Error> { Ok(id) }
+///
+/// async fn handler() -> Result<u32, std::io::Error> {
+///     let a = try_log_async!(fetch(1));
+///     let b = try_log_async!(fetch(2), "fetching second item");
+///     Ok(a + b)
+/// }
+/// # #[tokio::main]
+/// # async fn main() {
+/// assert_eq!(handler().await.unwrap(), 3);
+/// # }
 /// ```
 #[macro_export]
-macro_rules! with_retry {
-    ($retries:expr, $delay_ms:expr, $expr:expr) => {{
-        let mut attempts = 0;
-        loop {
-            match $expr {
-                Ok(val) => break Ok(val),
-                Err(err) => {
-                    attempts += 1;
-                    if attempts >= $retries {
-                        break Err(err);
-                    }
-                    std::thread::sleep(std::time::Duration::from_millis($delay_ms));
-                }
-            }
-        }
-    }};
+macro_rules! try_log_async {
+    ($fut:expr) => {
+        $crate::try_log_into!($fut.await)
+    };
+    ($fut:expr, $context:expr) => {
+        $crate::try_log_into!($fut.await, $context)
+    };
 }
 
-/// Retries an asynchronous expression (returning a `Result`) a specified number of times,
-/// waiting a fixed number of milliseconds between attempts.
-/// Uses `tokio::time::sleep`.
+/// Attempts to evaluate an expression returning a `Result` inside a function returning `Option`.
+/// If the result is `Ok`, returns the value. Otherwise, logs an error like [`try_log!`] and
+/// returns `None` from the enclosing function.
 ///
 /// # Examples
 ///
 /// ```rust
 /// # use zirv_macros::*;
-/// # async fn dummy_async_op() -> Result<u32, &'static str> { Ok(42) }
-/// # #[tokio::main]
-/// # async fn main() {
-/// let result = retry_async!(3, 10, dummy_async_op());
-/// assert_eq!(result.unwrap(), 42);
-/// # }
+/// fn parse_port(s: &str) -> Option<u16> {
+///     let port = try_log_none!(s.parse::<u16>(), "parsing port");
+///     Some(port)
+/// }
+/// assert_eq!(parse_port("8080"), Some(8080));
+/// assert_eq!(parse_port("http"), None);
 /// ```
 #[macro_export]
-macro_rules! retry_async {
-    ($retries:expr, $delay_ms:expr, $async_expr:expr) => {{
-        use std::time::Duration;
-        let mut attempts = 0;
-        loop {
-            match $async_expr.await {
-                Ok(val) => break Ok(val),
-                Err(err) => {
-                    attempts += 1;
-                    if attempts >= $retries {
-                        break Err(err);
-                    }
-                    tokio::time::sleep(Duration::from_millis($delay_ms)).await;
-                }
-            }
+macro_rules! try_log_none {
+    ($expr:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => $crate::__zirv_return_none!(err),
         }
-    }};
+    };
+    ($expr:expr, $context:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => $crate::__zirv_return_none!(err, $context),
+        }
+    };
 }
 
-/// Wraps a block of code in a tracing span with the given name, enabling automatic instrumentation.
+/// Attempts to evaluate an expression returning a `Result`, returning early from the enclosing
+/// function with the given value if it fails. Unlike [`log_error!`], which substitutes a default
+/// and keeps going, this bails out entirely, e.g. with an empty list from a listing endpoint.
+/// On error, logs via `tracing::error!` with file and line info. Works in sync and async fns.
 ///
 /// # Examples
 ///
 /// ```rust
 /// # use zirv_macros::*;
-/// span_wrap!("my_span", {
-///     println!("Inside span");
-/// });
+/// fn list_names(raw: &str) -> Vec<String> {
+///     let count = ok_or_return!(raw.parse::<usize>(), Vec::new());
+///     vec!["name".to_string(); count]
+/// }
+/// assert_eq!(list_names("2").len(), 2);
+/// assert!(list_names("many").is_empty());
 /// ```
 #[macro_export]
-macro_rules! span_wrap {
-    ($span_name:expr, $block:block) => {{
-        let span = tracing::span!(tracing::Level::INFO, $span_name);
-        let _enter = span.enter();
-        $block
-    }};
+macro_rules! ok_or_return {
+    ($expr:expr, $return_value:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                $crate::__zirv_log!(
+                    error,
+                    error = ?err,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - {:?}",
+                    file!(),
+                    line!(),
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                return $return_value;
+            }
+        }
+    };
 }
 
-/// Logs the duration of a code block using tracing.
-/// Executes the block, logs the elapsed time with the provided label, and returns the result.
+/// Attempts to evaluate an expression returning a `Result` inside a loop.
+/// If the result is `Ok`, returns the value. Otherwise, logs a warning via `tracing::warn!`
+/// with file and line info (and an optional message) and `continue`s the enclosing loop.
 ///
 /// # Examples
 ///
 /// ```rust
 /// # use zirv_macros::*;
-/// let result = log_duration!("test", { 42 });
-/// assert_eq!(result, 42);
+/// let mut sum = 0;
+/// for input in ["1", "x", "3"] {
+///     let value: u32 = try_continue!(input.parse::<u32>(), "skipping invalid row");
+///     sum += value;
+/// }
+/// assert_eq!(sum, 4);
 /// ```
 #[macro_export]
-macro_rules! log_duration {
-    ($label:expr, $block:block) => {{
-        let start = std::time::Instant::now();
-        let result = { $block };
-        let elapsed = start.elapsed();
-        tracing::info!("{} took {:?}", $label, elapsed);
+macro_rules! try_continue {
+    ($expr:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                $crate::__zirv_log!(
+                    warn,
+                    error = ?err,
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - {:?}. Continuing",
+                    file!(),
+                    line!(),
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                continue;
+            }
+        }
+    };
+    ($expr:expr, $msg:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                let msg = $msg;
+                $crate::__zirv_log!(
+                    warn,
+                    error = ?err,
+                    context = %msg,
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - {}: {:?}. Continuing",
+                    file!(),
+                    line!(),
+                    msg,
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                continue;
+            }
+        }
+    };
+}
+
+/// Attempts to evaluate an expression returning a `Result` inside a loop.
+/// If the result is `Ok`, returns the value. Otherwise, logs a warning via `tracing::warn!`
+/// with file and line info (and an optional message) and `break`s out of the enclosing loop.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let mut parsed = Vec::new();
+/// for input in ["1", "x", "3"] {
+///     parsed.push(try_break!(input.parse::<u32>()));
+/// }
+/// assert_eq!(parsed, vec![1]);
+/// ```
+#[macro_export]
+macro_rules! try_break {
+    ($expr:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                $crate::__zirv_log!(
+                    warn,
+                    error = ?err,
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - {:?}. Breaking",
+                    file!(),
+                    line!(),
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                break;
+            }
+        }
+    };
+    ($expr:expr, $msg:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                let msg = $msg;
+                $crate::__zirv_log!(
+                    warn,
+                    error = ?err,
+                    context = %msg,
+                    file = file!(),
+                    line = line!(),
+                    "Error at {}:{} - {}: {:?}. Breaking",
+                    file!(),
+                    line!(),
+                    msg,
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                break;
+            }
+        }
+    };
+}
+
+/// Evaluates an `Option` (or a `Result`) inside a function returning `Option`.
+/// On `Some`/`Ok`, returns the value. On `None`, logs a warning via `tracing::warn!` with file
+/// and line info plus the given message; on `Err`, logs the error like [`try_log_none!`] with
+/// the message as context. Either way, it then returns `None` from the enclosing function.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// fn first_even(values: &[u32]) -> Option<u32> {
+///     let value = try_log_opt!(values.iter().find(|v| *v % 2 == 0), "no even value");
+///     Some(*value)
+/// }
+/// assert_eq!(first_even(&[1, 2, 3]), Some(2));
+/// assert_eq!(first_even(&[1, 3]), None);
+///
+/// fn parse(s: &str) -> Option<u32> {
+///     Some(try_log_opt!(s.parse::<u32>(), "invalid number"))
+/// }
+/// assert_eq!(parse("x"), None);
+/// ```
+#[macro_export]
+macro_rules! try_log_opt {
+    ($expr:expr) => {
+        match $crate::__private::OptionLike::into_outcome($expr) {
+            Ok(val) => val,
+            Err(None) => {
+                $crate::__zirv_log!(warn, file = file!(), line = line!(), "Value was None");
+                $crate::hooks::report_error(file!(), line!());
+                return None;
+            }
+            Err(Some(err)) => $crate::__zirv_return_none!(err),
+        }
+    };
+    ($expr:expr, $msg:expr) => {
+        match $crate::__private::OptionLike::into_outcome($expr) {
+            Ok(val) => val,
+            Err(None) => {
+                $crate::__zirv_log!(warn, file = file!(), line = line!(), "{}", $msg);
+                $crate::hooks::report_error(file!(), line!());
+                return None;
+            }
+            Err(Some(err)) => $crate::__zirv_return_none!(err, $msg),
+        }
+    };
+}
+
+/// Logs an error like [`try_log!`] and returns `None` from the enclosing function, for
+/// [`try_log_none!`] and the `Err` path of [`try_log_opt!`].
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_return_none {
+    ($err:expr) => {{
+        let err = $err;
+        $crate::__zirv_log!(
+            error,
+            error = ?err,
+            causes = %$crate::__zirv_error_chain!(err),
+            file = file!(),
+            line = line!(),
+            "Error at {}:{} - {:?}",
+            file!(),
+            line!(),
+            err
+        );
+        $crate::hooks::report_error(file!(), line!());
+        return None;
+    }};
+    ($err:expr, $context:expr) => {{
+        let err = $err;
+        let context = $context;
+        $crate::__zirv_log!(
+            error,
+            error = ?err,
+            causes = %$crate::__zirv_error_chain!(err),
+            context = %context,
+            file = file!(),
+            line = line!(),
+            "Error at {}:{} - {}: {:?}",
+            file!(),
+            line!(),
+            context,
+            err
+        );
+        $crate::hooks::report_error(file!(), line!());
+        return None;
+    }};
+}
+
+/// Logs the error of a `Result` at warn level (with file and line info) without changing it,
+/// returning the original `Result` so it still composes with `?`.
+/// Accepts an optional message and trailing `key = value` tracing fields.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// fn parse(s: &str) -> Result<u32, std::num::ParseIntError> {
+///     let value = tap_err!(s.parse::<u32>(), "invalid number", input = s)?;
+///     Ok(value)
+/// }
+/// assert!(parse("x").is_err());
+/// ```
+#[macro_export]
+macro_rules! tap_err {
+    (@tap $expr:expr, $msg:expr, $($fields:tt)*) => {{
+        let result = $expr;
+        if let Err(err) = &result {
+            $crate::__zirv_log!(
+                warn,
+                error = ?err,
+                file = file!(),
+                line = line!(),
+                $($fields)*
+                "{}",
+                $msg
+            );
+        }
         result
     }};
+    ($expr:expr) => {
+        $crate::tap_err!(@tap $expr, "Result was Err",)
+    };
+    ($expr:expr, $($key:ident).+ = $($fields:tt)+) => {
+        $crate::tap_err!(@tap $expr, "Result was Err", $($key).+ = $($fields)+,)
+    };
+    ($expr:expr, $msg:expr) => {
+        $crate::tap_err!(@tap $expr, $msg,)
+    };
+    ($expr:expr, $msg:expr, $($key:ident).+ = $($fields:tt)+) => {
+        $crate::tap_err!(@tap $expr, $msg, $($key).+ = $($fields)+,)
+    };
 }
 
-/// Calls a function with the provided arguments, wrapping the call in a tracing span with the specified name.
+/// Logs the success of a `Result` at debug level (with file and line info) without changing it,
+/// returning the original `Result` so it still composes with `?`.
+/// Accepts an optional message and trailing `key = value` tracing fields.
+/// The `Ok` value itself is not logged.
 ///
 /// # Examples
 ///
 /// ```rust
 /// # use zirv_macros::*;
-/// fn add(a: i32, b: i32) -> i32 { a + b }
-/// let result = call_with_trace!("processing", add, 2, 3);
-/// assert_eq!(result, 5);
+/// let result = tap_ok!(Ok::<u32, &str>(42), "loaded settings");
+/// assert_eq!(result, Ok(42));
 /// ```
 #[macro_export]
-macro_rules! call_with_trace {
-    ($span_name:expr, $func:expr $(, $args:expr)*) => {{
-        let span = tracing::span!(tracing::Level::INFO, $span_name);
-        let _enter = span.enter();
-        $func($($args),*)
+macro_rules! tap_ok {
+    (@tap $expr:expr, $msg:expr, $($fields:tt)*) => {{
+        let result = $expr;
+        if result.is_ok() {
+            $crate::__zirv_log!(
+                debug,
+                file = file!(),
+                line = line!(),
+                $($fields)*
+                "{}",
+                $msg
+            );
+        }
+        result
     }};
+    ($expr:expr) => {
+        $crate::tap_ok!(@tap $expr, "Result was Ok",)
+    };
+    ($expr:expr, $($key:ident).+ = $($fields:tt)+) => {
+        $crate::tap_ok!(@tap $expr, "Result was Ok", $($key).+ = $($fields)+,)
+    };
+    ($expr:expr, $msg:expr) => {
+        $crate::tap_ok!(@tap $expr, $msg,)
+    };
+    ($expr:expr, $msg:expr, $($key:ident).+ = $($fields:tt)+) => {
+        $crate::tap_ok!(@tap $expr, $msg, $($key).+ = $($fields)+,)
+    };
 }
 
-/// Asserts a condition and logs an error with a custom message if it fails, then panics.
+/// Records the outcome of an operation returning a `Result` and passes the `Result` through
+/// unchanged, so it still composes with `?`. `Ok` emits an info event `succeeded`, `Err` an
+/// error event `failed` with the Debug of the error as the `error` field; both carry the
+/// operation name as the `op` field.
+///
+/// The `Ok` value is not logged unless `log_ok: true` is passed, to avoid leaking data into
+/// logs; it then goes into the `value` field and must implement `Debug`.
 ///
 /// # Examples
 ///
-/// ```rust,should_panic
+/// ```rust
 /// # use zirv_macros::*;
-/// let value = 0;
-/// assert_msg!(value > 0, "Value must be positive");
+/// fn create_invoice(amount: u32) -> Result<u32, String> {
+///     if amount == 0 { Err("empty invoice".to_string()) } else { Ok(amount) }
+/// }
+///
+/// fn bill(amount: u32) -> Result<u32, String> {
+///     let id = trace_result!("create_invoice", create_invoice(amount))?;
+///     Ok(id)
+/// }
+/// assert_eq!(bill(10), Ok(10));
+/// assert!(bill(0).is_err());
+///
+/// let total = trace_result!("sum", Ok::<u32, String>(3), log_ok: true);
+/// assert_eq!(total, Ok(3));
 /// ```
 #[macro_export]
-macro_rules! assert_msg {
-    ($cond:expr, $msg:expr) => {
-        if !$cond {
-            tracing::error!("Assertion failed: {}", $msg);
-            panic!($msg);
+macro_rules! trace_result {
+    (@ok true, $op:expr, $value:ident) => {
+        $crate::__zirv_log!(info, op = $op, value = ?$value, "succeeded")
+    };
+    (@ok false, $op:expr, $value:ident) => {
+        $crate::__zirv_log!(info, op = $op, "succeeded")
+    };
+    ($op:expr, $expr:expr, log_ok: $log_ok:tt) => {{
+        let result = $expr;
+        match &result {
+            #[allow(unused_variables)]
+            Ok(value) => $crate::trace_result!(@ok $log_ok, $op, value),
+            Err(err) => $crate::__zirv_log!(error, op = $op, error = ?err, "failed"),
         }
+        result
+    }};
+    ($op:expr, $expr:expr) => {
+        $crate::trace_result!($op, $expr, log_ok: false)
     };
 }
 
-/// Attempts to evaluate an expression returning a `Result` and logs an error if it fails,
-/// returning a default value instead.
+/// Attempts to unwrap a result, returning a default value if an error occurs.
+/// Logs a warning via `tracing::warn!` (with `error`, `default`, `file` and `line` fields)
+/// if the unwrap fails. With the `println-logging` feature, the message is written to
+/// stderr instead, as in earlier versions.
+/// The default expression is only evaluated on the error path, and at most once.
+///
+/// An optional third argument labels what was being unwrapped; it is included in the message
+/// and recorded as the `context` field.
 ///
 /// # Examples
 ///
 /// ```rust
 /// # use zirv_macros::*;
-/// fn fail_op() -> Result<u32, &'static str> { Err("failure") }
-/// let value = log_error!(fail_op(), 0);
-/// assert_eq!(value, 0);
+/// let value = unwrap_or_log!(Ok::<String, &str>("value".to_string()), "default".to_string());
+/// assert_eq!(value, "value".to_string());
+///
+/// let port = unwrap_or_log!("http".parse::<u16>(), 8080, "parsing PORT header");
+/// assert_eq!(port, 8080);
 /// ```
 #[macro_export]
-macro_rules! log_error {
-    ($expr:expr, $default:expr) => {{
+macro_rules! unwrap_or_log {
+    ($expr:expr, $default:expr) => {
         match $expr {
             Ok(val) => val,
             Err(err) => {
-                tracing::error!("Error: {:?}", err);
-                $default
+                let default = $default;
+                $crate::__zirv_unwrap_failed!(err, default);
+                $crate::hooks::report_error(file!(), line!());
+                default
             }
         }
-    }};
+    };
+    ($expr:expr, $default:expr, $context:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                let default = $default;
+                $crate::__zirv_unwrap_failed!(err, default, $context);
+                $crate::hooks::report_error(file!(), line!());
+                default
+            }
+        }
+    };
 }
 
-/// Attempts to read an environment variable. If the variable is not set,
-/// logs a warning and returns a default value as a String.
+/// Attempts to unwrap a result, returning `Default::default()` if an error occurs.
+/// Logs a warning via `tracing::warn!` with file and line info if the unwrap fails, or
+/// writes it to stderr with the `println-logging` feature, like [`unwrap_or_log!`].
 ///
 /// # Examples
 ///
 /// ```rust
 /// # use zirv_macros::*;
-/// unsafe {
-/// std::env::remove_var("TEST_VAR");
-/// }
-/// let value = parse_env!("TEST_VAR", "default");
-/// assert_eq!(value, "default".to_string());
+/// let count: u32 = unwrap_or_default_log!("many".parse::<u32>());
+/// assert_eq!(count, 0);
 /// ```
 #[macro_export]
-macro_rules! parse_env {
-    ($var:expr, $default:expr) => {{
-        std::env::var($var).unwrap_or_else(|_| {
-            tracing::warn!(
-                "Environment variable {} not set. Using default: {:?}",
-                $var,
-                $default
-            );
-            $default.to_string()
-        })
-    }};
+macro_rules! unwrap_or_default_log {
+    ($expr:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                $crate::__zirv_unwrap_failed!(default: err);
+                $crate::hooks::report_error(file!(), line!());
+                ::core::default::Default::default()
+            }
+        }
+    };
 }
 
-/// Prints a pretty-printed JSON representation of an object that implements Serialize.
+/// Unwraps an `Option`, returning a default value if it is `None`.
+/// Logs a warning via `tracing::warn!` with file and line info and the default used.
+/// The default expression is only evaluated on the `None` path.
 ///
 /// # Examples
 ///
 /// ```rust
 /// # use zirv_macros::*;
-/// let data = serde_json::json!({ "a": 1, "b": 2 });
-/// pretty_debug!(data);
+/// use std::collections::HashMap;
+/// let headers: HashMap<&str, &str> = HashMap::new();
+/// let lang = some_or_log!(headers.get("accept-language").copied(), "en");
+/// assert_eq!(lang, "en");
 /// ```
 #[macro_export]
-macro_rules! pretty_debug {
-    ($obj:expr) => {
-        println!("{}", serde_json::to_string_pretty(&$obj).unwrap())
+macro_rules! some_or_log {
+    ($expr:expr, $default:expr) => {
+        match $expr {
+            Some(val) => val,
+            None => {
+                let default = $default;
+                $crate::__zirv_log!(
+                    warn,
+                    file = file!(),
+                    line = line!(),
+                    "Value was None at {}:{}. Using default: {:?}",
+                    file!(),
+                    line!(),
+                    default
+                );
+                $crate::hooks::report_error(file!(), line!());
+                default
+            }
+        }
     };
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json::json;
-    use std::env;
-    use std::error::Error;
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    use std::time::Duration;
+/// Attempts to unwrap a result, computing a default value with the given closure if an
+/// error occurs. The closure is only invoked on the error path, which makes this the
+/// right choice for defaults that allocate or hit a cache.
+/// Logs like [`unwrap_or_log!`] if the unwrap fails.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let value = unwrap_or_else_log!("x".parse::<u32>(), || 8080);
+/// assert_eq!(value, 8080);
+/// ```
+#[macro_export]
+macro_rules! unwrap_or_else_log {
+    ($expr:expr, $default_fn:expr) => {
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                let default = ($default_fn)();
+                $crate::__zirv_unwrap_failed!(err, default);
+                $crate::hooks::report_error(file!(), line!());
+                default
+            }
+        }
+    };
+}
+
+/// Measures the execution time of a block of code and prints the duration with the provided label.
+/// The label can be a `&str` or a `String`. To send timings somewhere other than stdout
+/// (e.g. a metrics pipeline), install a sink with [`set_timer_sink`].
+///
+/// With `min_ms = ...` (any `u64` expression), the duration is only printed when the block
+/// took longer than the threshold, though faster runs still feed the `timing-stats` registry
+/// and `metrics` histograms. The block's result is returned in all cases.
+///
+/// With a leading `nested`, blocks nested inside each other on the same thread are reported
+/// with composed labels such as `"handler > db"`. Adding `percent = true` also logs the share
+/// of the block's time each directly nested block consumed once it finishes, as one info event
+/// per child with `label`, `child` and `percent` fields.
+///
+/// With `fmt = ...`, the printed duration uses a fixed unit: `ms` (fixed-point milliseconds),
+/// `us` (whole microseconds), `s` (fixed-point seconds) or `human` (the default `Debug` output).
+///
+/// With `sampled: n`, nothing is printed per call. Instead, the last `n` durations of the
+/// label are kept and every `n`th call emits one info event with their `p50_ms`, `p95_ms`
+/// and `p99_ms`, which keeps hot paths visible without flooding the logs.
+///
+/// With a leading `announce`, an info event (`"starting ..."`) is emitted before the block runs
+/// and another (`"finished ... in ..."`, with a `duration_ms` field) once it completes, instead
+/// of printing. Both carry the `label` and an `invocation_id` field so they can be correlated
+/// in live logs of long-running jobs.
+///
+/// With the `metrics` feature, every measurement is also recorded as a `metrics` histogram
+/// (in seconds) named after the label. `labels = ["key" => value, ...]` attaches extra
+/// metric labels; the values can be any `ToString` expressions.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let result = time_it!("Computation", { 42 });
+/// assert_eq!(result, 42);
+///
+/// let result = time_it!("Hot path", min_ms = 100, { 42 });
+/// assert_eq!(result, 42);
+///
+/// // Prints e.g. "Parsing took 0.512ms".
+/// let result = time_it!("Parsing", fmt = ms, { 42 });
+/// assert_eq!(result, 42);
+///
+/// let result = time_it!(announce, "nightly export", { 42 });
+/// assert_eq!(result, 42);
+///
+/// for row in 0..2000 {
+///     time_it!(sampled: 1000, "parse_row", { row * 2 });
+/// }
+///
+/// let result = time_it!("http_request", labels = ["route" => "/users", "status" => 200], { 42 });
+/// assert_eq!(result, 42);
+///
+/// // Prints "handler > db took ...", then "handler took ..." and the breakdown.
+/// let result = time_it!(nested, "handler", percent = true, {
+///     time_it!(nested, "db", { 40 }) + 2
+/// });
+/// assert_eq!(result, 42);
+/// ```
+#[cfg(not(feature = "timing-disabled"))]
+#[macro_export]
+macro_rules! time_it {
+    (sampled: $every:expr, $label:expr, $block:block) => {{
+        let start = std::time::Instant::now();
+        let result = { $block };
+        let duration = start.elapsed();
+        let label: &str = ::core::convert::AsRef::<str>::as_ref(&$label);
+        $crate::timing::record_measurement(format_args!("{}", label), duration, &[]);
+        if let Some(percentiles) = $crate::timing::sample(label, $every, duration) {
+            $crate::__zirv_log!(
+                info,
+                label = %label,
+                samples = percentiles.samples,
+                p50_ms = percentiles.p50.as_secs_f64() * 1000.0,
+                p95_ms = percentiles.p95.as_secs_f64() * 1000.0,
+                p99_ms = percentiles.p99.as_secs_f64() * 1000.0,
+                "{} p50 {:?}, p95 {:?}, p99 {:?} over the last {} calls",
+                label,
+                percentiles.p50,
+                percentiles.p95,
+                percentiles.p99,
+                percentiles.samples
+            );
+        }
+        result
+    }};
+    (announce, $label:expr, $block:block) => {{
+        let label: &str = ::core::convert::AsRef::<str>::as_ref(&$label);
+        let invocation_id = $crate::timing::next_invocation_id();
+        $crate::__zirv_log!(info, label = %label, invocation_id, "starting {}", label);
+        let start = std::time::Instant::now();
+        let result = { $block };
+        let duration = start.elapsed();
+        $crate::timing::record_measurement(format_args!("{}", label), duration, &[]);
+        $crate::__zirv_log!(
+            info,
+            label = %label,
+            invocation_id,
+            duration_ms = duration.as_secs_f64() * 1000.0,
+            "finished {} in {:?}",
+            label,
+            duration
+        );
+        result
+    }};
+    (nested, $label:expr, percent = $percent:expr, $block:block) => {{
+        let timer = $crate::timing::NestedTimer::start(
+            ::core::convert::AsRef::<str>::as_ref(&$label),
+            $percent,
+        );
+        let result = { $block };
+        timer.finish();
+        result
+    }};
+    (nested, $label:expr, $block:block) => {
+        $crate::time_it!(nested, $label, percent = false, $block)
+    };
+    ($label:expr, labels = [$($key:literal => $value:expr),* $(,)?], $block:block) => {{
+        let start = std::time::Instant::now();
+        let result = { $block };
+        let duration = start.elapsed();
+        $crate::hooks::report_timing_labelled(
+            ::core::convert::AsRef::<str>::as_ref(&$label),
+            duration,
+            &[$(($key, ::std::string::ToString::to_string(&$value))),*],
+        );
+        result
+    }};
+    ($label:expr, fmt = $format:ident, $block:block) => {{
+        let start = std::time::Instant::now();
+        let result = { $block };
+        let duration = start.elapsed();
+        $crate::hooks::report_timing_as(
+            ::core::convert::AsRef::<str>::as_ref(&$label),
+            duration,
+            $crate::__zirv_duration_format!($format),
+        );
+        result
+    }};
+    ($label:expr, min_ms = $min_ms:expr, $block:block) => {{
+        let start = std::time::Instant::now();
+        let result = { $block };
+        let duration = start.elapsed();
+        let label = $label;
+        let label = ::core::convert::AsRef::<str>::as_ref(&label);
+        // Fast runs still count towards the statistics and metrics, they just aren't printed.
+        $crate::timing::record_measurement(format_args!("{}", label), duration, &[]);
+        if duration > std::time::Duration::from_millis($min_ms) {
+            $crate::hooks::report_timing_unrecorded(label, duration);
+        }
+        result
+    }};
+    ($label:expr, $block:block) => {{
+        let start = std::time::Instant::now();
+        let result = { $block };
+        let duration = start.elapsed();
+        $crate::hooks::report_timing(::core::convert::AsRef::<str>::as_ref(&$label), duration);
+        result
+    }};
+}
+
+/// With the `timing-disabled` feature, timing is compiled out and `time_it!` expands to its
+/// block alone.
+#[cfg(feature = "timing-disabled")]
+#[macro_export]
+macro_rules! time_it {
+    ($($args:tt)*) => {
+        $crate::__zirv_last_block!($($args)*)
+    };
+}
+
+/// Measures the execution time of a block of code like [`time_it!`], but returns the measured
+/// `Duration` alongside the result as a `(result, Duration)` tuple.
+/// Without a label, nothing is logged and the measurement is only returned.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let (result, elapsed) = time_it_with!("Computation", { 42 });
+/// assert_eq!(result, 42);
+///
+/// let (result, elapsed) = time_it_with!({ 42 });
+/// assert_eq!(result, 42);
+/// println!("took {} µs", elapsed.as_micros());
+/// ```
+#[macro_export]
+macro_rules! time_it_with {
+    ($block:block) => {{
+        let start = std::time::Instant::now();
+        let result = { $block };
+        (result, start.elapsed())
+    }};
+    ($label:expr, $block:block) => {{
+        let start = std::time::Instant::now();
+        let result = { $block };
+        let duration = start.elapsed();
+        $crate::hooks::report_timing(::core::convert::AsRef::<str>::as_ref(&$label), duration);
+        (result, duration)
+    }};
+}
+
+/// Measures the execution time of a future and prints the duration with the provided label
+/// (or passes it to the sink installed with [`set_timer_sink`]). The clock starts right
+/// before the future is first polled, so construction time is not counted. Returns the
+/// future's output.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// # #[tokio::main]
+/// # async fn main() {
+/// let result = time_it_async!("fetch", async { 42 });
+/// assert_eq!(result, 42);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! time_it_async {
+    ($label:expr, $fut:expr) => {{
+        let fut = $fut;
+        let start = std::time::Instant::now();
+        let result = fut.await;
+        let duration = start.elapsed();
+        $crate::hooks::report_timing(::core::convert::AsRef::<str>::as_ref(&$label), duration);
+        result
+    }};
+}
+
+/// Merges two or more `serde_json::Value` objects (expected to be JSON objects), left to
+/// right. Keys in later objects override those in earlier ones. The first object is taken
+/// by value and the others are merged into it in a single pass, reusing its map.
+///
+/// Starting with `deep`, objects found at the same key are merged recursively instead of
+/// replaced. With `arrays: ...`, arrays found at the same key are combined according to a
+/// strategy, at every depth the merge reaches:
+///
+/// - `replace` (the default): the second array replaces the first one.
+/// - `concat`: the elements of the second array are appended to those of the first one.
+/// - `union`: like `concat`, but leaving out elements equal to one already in the result.
+///
+/// Starting with `patch` instead applies the other objects as JSON Merge Patches
+/// (RFC 7386), as received in `PATCH` requests: a `null` removes the key, nested objects are
+/// patched recursively, and any other value, arrays included, replaces the target whole.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// use serde_json::json;
+/// let a = json!({ "a": 1, "b": 2 });
+/// let b = json!({ "b": 3, "c": 4 });
+/// let merged = json_merge!(a, b);
+/// assert_eq!(merged["a"], 1);
+/// assert_eq!(merged["b"], 3);
+/// assert_eq!(merged["c"], 4);
+///
+/// let env = json!({ "c": 5 });
+/// let merged = json_merge!(merged, json!({ "a": 0 }), env);
+/// assert_eq!(merged, json!({ "a": 0, "b": 3, "c": 5 }));
+///
+/// let defaults = json!({ "server": { "port": 80, "middleware": ["log"] } });
+/// let config = json!({ "server": { "middleware": ["auth", "log"] } });
+/// let merged = json_merge!(deep, arrays: union, defaults, config);
+/// assert_eq!(
+///     merged,
+///     json!({ "server": { "port": 80, "middleware": ["log", "auth"] } })
+/// );
+///
+/// let user = json!({ "name": "Ada", "email": "ada@example.com", "prefs": { "theme": "dark" } });
+/// let patch = json!({ "email": null, "prefs": { "lang": "en" } });
+/// let patched = json_merge!(patch, user, patch);
+/// assert_eq!(patched, json!({ "name": "Ada", "prefs": { "theme": "dark", "lang": "en" } }));
+/// ```
+#[macro_export]
+macro_rules! json_merge {
+    (@merge $deep:expr, $arrays:ident, $base:expr $(, $other:expr)+) => {{
+        let mut base = $base;
+        $(
+            $crate::json::merge(
+                &mut base,
+                &$other,
+                $deep,
+                $crate::__zirv_array_merge!($arrays),
+            );
+        )+
+        base
+    }};
+    (patch, $base:expr $(, $patch:expr)+) => {{
+        let mut base = $base;
+        $($crate::json::merge_patch(&mut base, &$patch);)+
+        base
+    }};
+    (deep, arrays: $arrays:ident, $base:expr $(, $other:expr)+) => {
+        $crate::json_merge!(@merge true, $arrays, $base $(, $other)+)
+    };
+    (deep, $base:expr $(, $other:expr)+) => {
+        $crate::json_merge!(@merge true, replace, $base $(, $other)+)
+    };
+    (arrays: $arrays:ident, $base:expr $(, $other:expr)+) => {
+        $crate::json_merge!(@merge false, $arrays, $base $(, $other)+)
+    };
+    ($base:expr $(, $other:expr)+) => {
+        $crate::json_merge!(@merge false, replace, $base $(, $other)+)
+    };
+}
+
+/// Lists the differences between two `serde_json::Value`s as a `Vec<JsonDiff>`, each with
+/// the JSON Pointer of a leaf that was added, removed or changed and the values on either
+/// side. Objects are compared key by key and arrays position by position; identical values
+/// yield an empty list. [`json::pretty`] renders the list for logs, one difference per line.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// use serde_json::json;
+/// let expected = json!({ "user": { "name": "Ada", "age": 36 }, "tags": ["a"] });
+/// let actual = json!({ "user": { "name": "Ada", "age": 37 }, "tags": ["a", "b"] });
+/// let diffs = json_diff!(expected, actual);
+/// assert_eq!(
+///     json::pretty(&diffs),
+///     "~ /user/age: 36 -> 37\n+ /tags/1: \"b\""
+/// );
+/// assert!(json_diff!(expected, expected).is_empty());
+/// ```
+#[macro_export]
+macro_rules! json_diff {
+    ($left:expr, $right:expr) => {
+        $crate::json::diff(&$left, &$right)
+    };
+}
+
+/// Looks up a value in a `serde_json::Value` by JSON Pointer (RFC 6901, e.g.
+/// `/user/address/zip`, with `~0` and `~1` escaping `~` and `/`) and extracts it with one of
+/// `Value`'s accessors (`as_str`, `as_i64`, `as_u64`, `as_f64`, `as_bool`, ...), or
+/// deserializes it with `as: Type`. Returns the default, after logging a debug event, when
+/// nothing is at the pointer or the value there doesn't have the expected type.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// use serde_json::json;
+/// let order = json!({ "user": { "address": { "zip": "90210" } }, "items": [{ "qty": 2 }] });
+/// assert_eq!(json_get!(order, "/user/address/zip", as_str, "00000"), "90210");
+/// assert_eq!(json_get!(order, "/items/0/qty", as_i64, 1), 2);
+/// assert_eq!(json_get!(order, "/items/1/qty", as_i64, 1), 1);
+/// assert!(!json_get!(order, "/user/address/zip", as_bool, false));
+///
+/// use std::collections::HashMap;
+/// let address = json_get!(order, "/user/address", as: HashMap<String, String>, HashMap::new());
+/// assert_eq!(address["zip"], "90210");
+/// ```
+#[macro_export]
+macro_rules! json_get {
+    (@missing $pointer:expr) => {
+        $crate::__zirv_log!(
+            debug,
+            pointer = %$pointer,
+            "No JSON value at {}, using the default",
+            $pointer
+        )
+    };
+    ($value:expr, $pointer:expr, as: $ty:ty, $default:expr) => {{
+        let pointer: &str = $pointer;
+        match $value.pointer(pointer) {
+            Some(found) => match serde_json::from_value::<$ty>(found.clone()) {
+                Ok(value) => value,
+                Err(err) => {
+                    $crate::__zirv_log!(
+                        debug,
+                        pointer = %pointer,
+                        error = %err,
+                        "JSON value at {} is not a {}, using the default: {}",
+                        pointer,
+                        stringify!($ty),
+                        err
+                    );
+                    $default
+                }
+            },
+            None => {
+                $crate::json_get!(@missing pointer);
+                $default
+            }
+        }
+    }};
+    ($value:expr, $pointer:expr, $accessor:ident, $default:expr) => {{
+        let pointer: &str = $pointer;
+        match $value.pointer(pointer) {
+            Some(found) => match found.$accessor() {
+                Some(value) => value,
+                None => {
+                    $crate::__zirv_log!(
+                        debug,
+                        pointer = %pointer,
+                        "JSON value at {} failed {}, using the default",
+                        pointer,
+                        stringify!($accessor)
+                    );
+                    $default
+                }
+            },
+            None => {
+                $crate::json_get!(@missing pointer);
+                $default
+            }
+        }
+    }};
+}
+
+/// Applies a JSON Patch (RFC 6902), a `serde_json::Value` array of `add`, `remove`,
+/// `replace`, `move`, `copy` and `test` operations, to a document taken by value. Returns
+/// the patched document, or a [`PatchError`] with the index of the first operation that
+/// failed and why, e.g. a path that doesn't exist. Failed `test` operations are also logged
+/// at debug level.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// use serde_json::json;
+/// let doc = json!({ "name": "Ada", "roles": ["user"] });
+/// let patch = json!([
+///     { "op": "test", "path": "/name", "value": "Ada" },
+///     { "op": "add", "path": "/roles/-", "value": "admin" },
+///     { "op": "move", "from": "/name", "path": "/full_name" }
+/// ]);
+/// let patched = json_patch!(doc.clone(), patch).unwrap();
+/// assert_eq!(patched, json!({ "roles": ["user", "admin"], "full_name": "Ada" }));
+///
+/// let err = json_patch!(doc, json!([{ "op": "remove", "path": "/email" }])).unwrap_err();
+/// assert_eq!(err.index, 0);
+/// assert_eq!(err.to_string(), "patch operation 0 failed: no value at /email");
+/// ```
+#[macro_export]
+macro_rules! json_patch {
+    ($doc:expr, $patch:expr) => {
+        $crate::json::patch($doc, &$patch)
+    };
+}
+
+/// Logs the SQL query string (and optionally its bind parameters) before executing it.
+/// Useful for debugging SQLx queries.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// // Dummy struct to simulate a query with a sql() method.
+/// struct DummyQuery { sql: &'static str }
+/// impl DummyQuery {
+///     fn sql(&self) -> &str { self.sql }
+/// }
+/// let query = DummyQuery { sql: "SELECT * FROM users" };
+/// let _ = debug_query!(query);
+/// ```
+#[macro_export]
+macro_rules! debug_query {
+    ($query:expr) => {{
+        let sql = $query.sql();
+        println!("SQL Query: {}", sql);
+        $query
+    }};
+}
+
+/// Retries a synchronous expression (returning a `Result`) a specified number of times,
+/// waiting a fixed number of milliseconds between attempts.
+/// Every failed attempt that will be retried is logged at warn level with the attempt
+/// number, the number of attempts, the next delay and the error (`Debug`), and running out
+/// of attempts is logged at error level.
+///
+/// With `backoff: base, max: cap` instead of a fixed delay, the delay starts at `base`
+/// milliseconds and doubles after every failed attempt, saturating at `cap` milliseconds.
+///
+/// Adding `jitter` before the expression randomizes each delay uniformly between half of it
+/// and all of it, so many callers failing together don't retry in lockstep.
+///
+/// `backoff: decorrelated(base: ms, cap: ms)` uses "decorrelated jitter" instead: each delay
+/// is random between `base` and three times the previous delay, capped at `cap`, which
+/// spreads retries of many clients better than jittered exponential backoff.
+///
+/// `delay_fn: |attempt| ...` computes each delay in milliseconds (`u64`) from the 1-based
+/// number (`u32`) of the attempt that just failed, for any other delay curve.
+///
+/// With `delays: [...]` (any `IntoIterator<Item = u64>`) instead of the count and delay, the
+/// given delays in milliseconds are used in order, for one more attempt than there are delays.
+///
+/// Instead of the count and delay, a [`RetryPolicy`] can be passed, as in
+/// `with_retry!(policy, expr)`, to reuse the same parameters at several call sites.
+///
+/// With `forever` instead of the count, as in `with_retry!(forever, backoff: 100, max: 30_000,
+/// connect())`, the expression is retried until it succeeds (strictly, up to `u32::MAX`
+/// times), e.g. to wait for a database at startup. The macro still returns a `Result`, since
+/// `if:` and `max_elapsed_ms:` can still end the loop with an error.
+///
+/// Starting with `all_errors`, as in `with_retry!(all_errors, 3, 100, expr)`, the macro
+/// returns `Err(RetryErrors<E>)` holding the error of every attempt in order instead of only
+/// the last one.
+///
+/// Options can follow the expression:
+/// - `if: |err| ...` only retries errors the predicate (taking `&E`) accepts; any other error
+///   is returned immediately without using up the remaining attempts.
+/// - `max_elapsed_ms: n` caps the total time spent: the last error is returned instead of
+///   sleeping when the next attempt would start more than `n` milliseconds after the first.
+/// - `on_retry: |attempt, err| ...` is called with the 1-based attempt number (`u32`) and
+///   `&E` after every failed attempt that will be retried, i.e. all but the last.
+/// - `quiet: true` turns off the warn and error events.
+/// - `label: "..."` names the call for the observer installed with [`set_retry_observer`],
+///   which is told the number of attempts and the outcome once the loop is done.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// fn dummy_op() -> Result<u32, &'static str> { Ok(42) }
+/// let result = with_retry!(3, 10, dummy_op());
+/// assert_eq!(result.unwrap(), 42);
+///
+/// // Waits 10ms, then 20ms, then 40ms between attempts.
+/// let result = with_retry!(4, backoff: 10, max: 1000, dummy_op());
+/// assert_eq!(result.unwrap(), 42);
+///
+/// let result = with_retry!(4, backoff: 10, max: 1000, jitter, dummy_op());
+/// assert_eq!(result.unwrap(), 42);
+///
+/// let result = with_retry!(4, backoff: decorrelated(base: 10, cap: 1000), dummy_op());
+/// assert_eq!(result.unwrap(), 42);
+///
+/// let result = with_retry!(5, delay_fn: |attempt| 100 * attempt as u64, dummy_op());
+/// assert_eq!(result.unwrap(), 42);
+///
+/// let result = with_retry!(delays: [0, 100, 500, 5000], dummy_op());
+/// assert_eq!(result.unwrap(), 42);
+///
+/// fn is_transient(err: &&str) -> bool { *err == "connection reset" }
+/// let result: Result<u32, &str> = with_retry!(5, 10, Err("unique violation"), if: is_transient);
+/// assert_eq!(result, Err("unique violation"));
+///
+/// let result: Result<u32, &str> = with_retry!(100, 10, Err("down"), max_elapsed_ms: 50);
+/// assert!(result.is_err());
+///
+/// let result: Result<u32, &str> = with_retry!(3, 10, Err("refused"), on_retry: |attempt, err| {
+///     eprintln!("attempt {}/3 failed: {}", attempt, err);
+/// });
+/// assert!(result.is_err());
+///
+/// let result = with_retry!(forever, backoff: 10, max: 1000, dummy_op());
+/// assert_eq!(result.unwrap(), 42);
+///
+/// let result: Result<u32, RetryErrors<&str>> = with_retry!(all_errors, 2, 10, Err("refused"));
+/// assert_eq!(result.unwrap_err().0, vec!["refused", "refused"]);
+/// ```
+#[macro_export]
+macro_rules! with_retry {
+    (@run $retries:expr, $delay:expr, $expr:expr, [$($opts:tt)*]) => {{
+        $crate::__zirv_retry_option!(@check [$($opts)*]);
+        $crate::__zirv_retry_option!(@with cancel, [$($opts)*], token => {
+            compile_error!("`cancel` is only supported by retry_async!")
+        }, {});
+        $crate::__zirv_retry_option!(@with attempt_timeout_ms, [$($opts)*], timeout => {
+            compile_error!("`attempt_timeout_ms` is only supported by retry_async!")
+        }, {});
+        $crate::__zirv_retry_option!(@with sleep_with, [$($opts)*], sleep => {
+            compile_error!("`sleep_with` is only supported by retry_async!")
+        }, {});
+        let retries = $retries as u32;
+        #[allow(unused_mut)]
+        let mut delay = $delay;
+        #[allow(unused_mut)]
+        let mut should_retry = $crate::retry::predicate(
+            $crate::__zirv_retry_option!(if, [$($opts)*], |_: &_| true)
+        );
+        #[allow(unused_mut)]
+        let mut on_retry = $crate::retry::on_retry(
+            $crate::__zirv_retry_option!(on_retry, [$($opts)*], |_: u32, _: &_| {})
+        );
+        let max_elapsed: Option<std::time::Duration> =
+            $crate::__zirv_retry_option!(max_elapsed_ms, [$($opts)*], None);
+        let quiet: bool = $crate::__zirv_retry_option!(quiet, [$($opts)*], false);
+        let label: &str =
+            $crate::__zirv_retry_option!(label, [$($opts)*], concat!(file!(), ":", line!()));
+        #[allow(unused_mut, unused_variables, clippy::let_unit_value)]
+        let mut errors = $crate::__zirv_retry_option!(@with all_errors, [$($opts)*], _collect => {
+            Vec::new()
+        }, {});
+        let start = std::time::Instant::now();
+        let mut attempts: u32 = 0;
+        let result = loop {
+            #[allow(unused_braces)]
+            let outcome = $crate::__zirv_retry_option!(@with catch_panics, [$($opts)*], _catch => {
+                $crate::retry::catch_panic(|| $expr)
+            }, {
+                $expr
+            });
+            match outcome {
+                Ok(val) => break Ok($crate::__zirv_retry_option!(@with counted, [$($opts)*], _count => {
+                    (val, attempts + 1)
+                }, {
+                    val
+                })),
+                Err(err) => {
+                    attempts += 1;
+                    if !should_retry(&err) {
+                        break Err($crate::with_retry!(@failed errors, err, [$($opts)*]));
+                    }
+                    let exhausted = attempts >= retries;
+                    let next_delay = if exhausted {
+                        std::time::Duration::ZERO
+                    } else {
+                        delay(attempts)
+                    };
+                    if exhausted
+                        || max_elapsed
+                            .is_some_and(|max_elapsed| start.elapsed() + next_delay >= max_elapsed)
+                    {
+                        if !quiet {
+                            $crate::__zirv_retry_log!(exhausted, attempts, err);
+                        }
+                        break Err($crate::with_retry!(@failed errors, err, [$($opts)*]));
+                    }
+                    on_retry(attempts, &err);
+                    if !quiet {
+                        $crate::__zirv_retry_log!(retrying, attempts, retries, next_delay, err);
+                    }
+                    $crate::__zirv_retry_option!(@with all_errors, [$($opts)*], _collect => {
+                        errors.push(err);
+                    }, {});
+                    $crate::retry::sleep(next_delay);
+                }
+            }
+        };
+        // Failed attempts are counted as they happen, a successful one only here.
+        $crate::hooks::report_retry(label, attempts + result.is_ok() as u32, result.is_ok());
+        result
+    }};
+    (@failed $errors:ident, $err:ident, [$($opts:tt)*]) => {
+        $crate::__zirv_retry_option!(@with all_errors, [$($opts)*], _collect => {
+            $errors.push($err);
+            $crate::RetryErrors($errors)
+        }, {
+            $err
+        })
+    };
+    (all_errors, $($rest:tt)*) => {
+        $crate::with_retry!($($rest)*, all_errors: true)
+    };
+    (forever, $($rest:tt)*) => {
+        $crate::with_retry!(u32::MAX, $($rest)*)
+    };
+    (delays: $delays:expr, $expr:expr $(, $opt:tt: $value:expr)*) => {{
+        let delays: Vec<u64> = ::core::iter::IntoIterator::into_iter($delays).collect();
+        $crate::with_retry!(
+            @run delays.len() as u32 + 1,
+            |attempt: u32| std::time::Duration::from_millis(delays[attempt as usize - 1]),
+            $expr,
+            [$($opt: $value),*]
+        )
+    }};
+    ($policy:expr, $expr:expr $(, $opt:tt: $value:expr)*) => {{
+        let policy: $crate::RetryPolicy = $policy;
+        $crate::with_retry!(
+            @run policy.max_attempts(),
+            |attempt| policy.delay(attempt),
+            $expr,
+            [$($opt: $value),*]
+        )
+    }};
+    ($retries:expr, delay_fn: $delay_fn:expr, $expr:expr $(, $opt:tt: $value:expr)*) => {{
+        #[allow(unused_mut)]
+        let mut delay_fn = $crate::retry::delay_fn($delay_fn);
+        $crate::with_retry!(
+            @run $retries,
+            |attempt| std::time::Duration::from_millis(delay_fn(attempt)),
+            $expr,
+            [$($opt: $value),*]
+        )
+    }};
+    ($retries:expr, backoff: decorrelated(base: $base_ms:expr, cap: $cap_ms:expr), $expr:expr $(, $opt:tt: $value:expr)*) => {{
+        let base = std::time::Duration::from_millis($base_ms);
+        let cap = std::time::Duration::from_millis($cap_ms);
+        let mut previous = base;
+        $crate::with_retry!(
+            @run $retries,
+            |_| {
+                previous = $crate::retry::decorrelated_jitter(base, cap, previous);
+                previous
+            },
+            $expr,
+            [$($opt: $value),*]
+        )
+    }};
+    ($retries:expr, backoff: $base_ms:expr, max: $max_ms:expr, jitter, $expr:expr $(, $opt:tt: $value:expr)*) => {{
+        let (base_ms, max_ms): (u64, u64) = ($base_ms, $max_ms);
+        $crate::with_retry!(
+            @run $retries,
+            |attempt| $crate::retry::jitter($crate::retry::exponential_delay(base_ms, max_ms, attempt)),
+            $expr,
+            [$($opt: $value),*]
+        )
+    }};
+    ($retries:expr, $delay_ms:expr, jitter, $expr:expr $(, $opt:tt: $value:expr)*) => {
+        $crate::with_retry!(
+            @run $retries,
+            |_| $crate::retry::jitter(std::time::Duration::from_millis($delay_ms)),
+            $expr,
+            [$($opt: $value),*]
+        )
+    };
+    ($retries:expr, backoff: $base_ms:expr, max: $max_ms:expr, $expr:expr $(, $opt:tt: $value:expr)*) => {{
+        let (base_ms, max_ms): (u64, u64) = ($base_ms, $max_ms);
+        $crate::with_retry!(
+            @run $retries,
+            |attempt| $crate::retry::exponential_delay(base_ms, max_ms, attempt),
+            $expr,
+            [$($opt: $value),*]
+        )
+    }};
+    ($retries:expr, $delay_ms:expr, $expr:expr $(, $opt:tt: $value:expr)*) => {
+        $crate::with_retry!(
+            @run $retries,
+            |_| std::time::Duration::from_millis($delay_ms),
+            $expr,
+            [$($opt: $value),*]
+        )
+    };
+}
+
+/// Retries an asynchronous expression (returning a `Result`) a specified number of times,
+/// waiting a fixed number of milliseconds between attempts.
+/// Sleeps with `tokio::time::sleep` unless `sleep_with: |delay| ...` passes a function
+/// returning a future that sleeps for the given `Duration`, which lets other runtimes such as
+/// async-std or smol use the macro (`cancel:` and `attempt_timeout_ms:` still need tokio).
+/// Supports the same `backoff: base, max: cap`, `jitter`,
+/// `backoff: decorrelated(base: ms, cap: ms)` and `delay_fn: |attempt| ...` delays,
+/// `forever` mode, `delays: [...]` schedules, [`RetryPolicy`] form and trailing options
+/// (such as `if: ...`) as [`with_retry!`], and logs failed attempts the same way.
+///
+/// With the `cancellation` feature, `cancel: token` (a `tokio_util::sync::CancellationToken`)
+/// races every attempt and delay against the token. The macro then returns
+/// `Result<T, RetryError<E>>`: `RetryError::Cancelled` as soon as the token is cancelled, or
+/// `RetryError::Exhausted(err)` with the last error.
+///
+/// With the `backoff` feature, `policy: backoff` drives the delays with any
+/// `backoff::backoff::Backoff` implementation (reset before the first attempt), retrying
+/// until its `next_backoff()` returns `None`.
+///
+/// `attempt_timeout_ms: ms` wraps every attempt in `tokio::time::timeout`, so a hung attempt
+/// doesn't block the loop: a timed-out attempt is logged and retried after the usual delay
+/// like a failed one (without consulting `if:` or `on_retry:`). The macro then returns
+/// `Result<T, RetryError<E>>` as well, with `RetryError::TimedOut(budget)` when the last
+/// attempt timed out.
+///
+/// The expression is evaluated again for every attempt. When it needs to move values it
+/// can't give up (e.g. a request body), pass a closure building a fresh future instead, such
+/// as `|| client.post(url).body(body.clone()).send()`: it is created once and called once
+/// per attempt.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// # async fn dummy_async_op() -> Result<u32, &'static str> { Ok(42) }
+/// # #[tokio::main]
+/// # async fn main() {
+/// let result = retry_async!(3, 10, dummy_async_op());
+/// assert_eq!(result.unwrap(), 42);
+///
+/// let result = retry_async!(5, backoff: 100, max: 5000, dummy_async_op());
+/// assert_eq!(result.unwrap(), 42);
+///
+/// let result = retry_async!(5, 100, jitter, dummy_async_op());
+/// assert_eq!(result.unwrap(), 42);
+///
+/// # async fn send(body: String) -> Result<usize, &'static str> { Ok(body.len()) }
+/// let body = String::from("payload");
+/// let result = retry_async!(3, 100, move || send(body.clone()));
+/// assert_eq!(result.unwrap(), 7);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! retry_async {
+    (@run $retries:expr, $delay:tt, [$($setup:tt)*], $attempt:block, [$($opts:tt)*]) => {{
+        $crate::__zirv_retry_option!(@check [$($opts)*]);
+        $crate::__zirv_retry_option!(@with all_errors, [$($opts)*], _collect => {
+            compile_error!("`all_errors` is only supported by with_retry!")
+        }, {});
+        let retries = $retries as u32;
+        #[allow(unused_mut)]
+        let mut delay = $delay;
+        $($setup)*
+        #[allow(unused_mut)]
+        let mut should_retry = $crate::retry::predicate(
+            $crate::__zirv_retry_option!(if, [$($opts)*], |_: &_| true)
+        );
+        #[allow(unused_mut)]
+        let mut on_retry = $crate::retry::on_retry(
+            $crate::__zirv_retry_option!(on_retry, [$($opts)*], |_: u32, _: &_| {})
+        );
+        let max_elapsed: Option<std::time::Duration> =
+            $crate::__zirv_retry_option!(max_elapsed_ms, [$($opts)*], None);
+        let quiet: bool = $crate::__zirv_retry_option!(quiet, [$($opts)*], false);
+        let label: &str =
+            $crate::__zirv_retry_option!(label, [$($opts)*], concat!(file!(), ":", line!()));
+        #[allow(unused_mut)]
+        let mut sleep = $crate::retry::sleeper(
+            $crate::__zirv_retry_option!(sleep_with, [$($opts)*], $crate::retry::sleep_async)
+        );
+        let start = std::time::Instant::now();
+        let mut attempts: u32 = 0;
+        let result = loop {
+            let outcome = $crate::__zirv_retry_option!(@with cancel, [$($opts)*], token => {
+                tokio::select! {
+                    _ = $crate::retry::cancelled(token) => break Err($crate::RetryError::Cancelled),
+                    outcome = $crate::retry_async!(@timed $attempt, [$($opts)*]) => outcome,
+                }
+            }, {
+                $crate::retry_async!(@timed $attempt, [$($opts)*]).await
+            });
+            let outcome = $crate::__zirv_retry_option!(@with attempt_timeout_ms, [$($opts)*], timeout => {
+                match outcome {
+                    Ok(outcome) => outcome,
+                    Err(_) => {
+                        attempts += 1;
+                        let next_delay = if attempts < retries {
+                            $crate::retry::NextDelay::next_delay(delay(attempts)).filter(|next_delay| {
+                                !max_elapsed.is_some_and(|max_elapsed| start.elapsed() + *next_delay >= max_elapsed)
+                            })
+                        } else {
+                            None
+                        };
+                        let err = format_args!("attempt timed out after {:?}", timeout);
+                        let Some(next_delay) = next_delay else {
+                            if !quiet {
+                                $crate::__zirv_retry_log!(exhausted, attempts, err);
+                            }
+                            break Err($crate::RetryError::TimedOut(timeout));
+                        };
+                        if !quiet {
+                            $crate::__zirv_retry_log!(retrying, attempts, retries, next_delay, err);
+                        }
+                        $crate::retry_async!(@sleep sleep, next_delay, [$($opts)*]);
+                        continue;
+                    }
+                }
+            }, {
+                outcome
+            });
+            match outcome {
+                Ok(val) => break Ok($crate::__zirv_retry_option!(@with counted, [$($opts)*], _count => {
+                    (val, attempts + 1)
+                }, {
+                    val
+                })),
+                Err(err) => {
+                    attempts += 1;
+                    if !should_retry(&err) {
+                        break Err($crate::__zirv_retry_option!(@exhausted [$($opts)*], err));
+                    }
+                    // The delay before the next attempt, or `None` when the loop should give up.
+                    let next_delay = if attempts < retries {
+                        $crate::retry::NextDelay::next_delay(delay(attempts)).filter(|next_delay| {
+                            !max_elapsed.is_some_and(|max_elapsed| start.elapsed() + *next_delay >= max_elapsed)
+                        })
+                    } else {
+                        None
+                    };
+                    let Some(next_delay) = next_delay else {
+                        if !quiet {
+                            $crate::__zirv_retry_log!(exhausted, attempts, err);
+                        }
+                        break Err($crate::__zirv_retry_option!(@exhausted [$($opts)*], err));
+                    };
+                    on_retry(attempts, &err);
+                    if !quiet {
+                        $crate::__zirv_retry_log!(retrying, attempts, retries, next_delay, err);
+                    }
+                    $crate::retry_async!(@sleep sleep, next_delay, [$($opts)*]);
+                }
+            }
+        };
+        // Failed attempts are counted as they happen, a successful one only here.
+        $crate::hooks::report_retry(label, attempts + result.is_ok() as u32, result.is_ok());
+        result
+    }};
+    (@timed $attempt:block, [$($opts:tt)*]) => {
+        $crate::__zirv_retry_option!(@with attempt_timeout_ms, [$($opts)*], timeout => {
+            tokio::time::timeout(timeout, $attempt)
+        }, {
+            $attempt
+        })
+    };
+    (@sleep $sleep:ident, $delay:expr, [$($opts:tt)*]) => {
+        $crate::__zirv_retry_option!(@with cancel, [$($opts)*], token => {
+            tokio::select! {
+                _ = $crate::retry::cancelled(token) => break Err($crate::RetryError::Cancelled),
+                _ = $sleep($delay) => {}
+            }
+        }, {
+            $sleep($delay).await;
+        });
+    };
+    (@attempt $retries:expr, $delay:tt, move || $body:expr $(, $opt:tt: $value:expr)*) => {
+        $crate::retry_async!(
+            @run $retries,
+            $delay,
+            [#[allow(unused_mut)] let mut make_attempt = move || $body;],
+            { make_attempt() },
+            [$($opt: $value),*]
+        )
+    };
+    (@attempt $retries:expr, $delay:tt, || $body:expr $(, $opt:tt: $value:expr)*) => {
+        $crate::retry_async!(
+            @run $retries,
+            $delay,
+            [#[allow(unused_mut)] let mut make_attempt = || $body;],
+            { make_attempt() },
+            [$($opt: $value),*]
+        )
+    };
+    (@attempt $retries:expr, $delay:tt, $async_expr:expr $(, $opt:tt: $value:expr)*) => {
+        $crate::retry_async!(@run $retries, $delay, [], { $async_expr }, [$($opt: $value),*])
+    };
+    (forever, $($rest:tt)*) => {
+        $crate::retry_async!(u32::MAX, $($rest)*)
+    };
+    (policy: $backoff:expr, $($rest:tt)*) => {{
+        let mut backoff = $crate::retry::backoff($backoff);
+        $crate::retry_async!(
+            @attempt u32::MAX,
+            { |_| $crate::retry::next_backoff(&mut backoff) },
+            $($rest)*
+        )
+    }};
+    (delays: $delays:expr, $($rest:tt)*) => {{
+        let delays: Vec<u64> = ::core::iter::IntoIterator::into_iter($delays).collect();
+        $crate::retry_async!(
+            @attempt delays.len() as u32 + 1,
+            { |attempt: u32| std::time::Duration::from_millis(delays[attempt as usize - 1]) },
+            $($rest)*
+        )
+    }};
+    ($policy:expr, move || $body:expr $(, $opt:tt: $value:expr)*) => {{
+        let policy: $crate::RetryPolicy = $policy;
+        $crate::retry_async!(
+            @attempt policy.max_attempts(),
+            { |attempt| policy.delay(attempt) },
+            move || $body $(, $opt: $value)*
+        )
+    }};
+    ($policy:expr, || $body:expr $(, $opt:tt: $value:expr)*) => {{
+        let policy: $crate::RetryPolicy = $policy;
+        $crate::retry_async!(
+            @attempt policy.max_attempts(),
+            { |attempt| policy.delay(attempt) },
+            || $body $(, $opt: $value)*
+        )
+    }};
+    ($policy:expr, $async_expr:expr $(, $opt:tt: $value:expr)*) => {{
+        let policy: $crate::RetryPolicy = $policy;
+        $crate::retry_async!(
+            @attempt policy.max_attempts(),
+            { |attempt| policy.delay(attempt) },
+            $async_expr $(, $opt: $value)*
+        )
+    }};
+    ($retries:expr, delay_fn: $delay_fn:expr, $($rest:tt)*) => {{
+        #[allow(unused_mut)]
+        let mut delay_fn = $crate::retry::delay_fn($delay_fn);
+        $crate::retry_async!(
+            @attempt $retries,
+            { |attempt| std::time::Duration::from_millis(delay_fn(attempt)) },
+            $($rest)*
+        )
+    }};
+    ($retries:expr, backoff: decorrelated(base: $base_ms:expr, cap: $cap_ms:expr), $($rest:tt)*) => {{
+        let base = std::time::Duration::from_millis($base_ms);
+        let cap = std::time::Duration::from_millis($cap_ms);
+        let mut previous = base;
+        $crate::retry_async!(
+            @attempt $retries,
+            {
+                |_| {
+                    previous = $crate::retry::decorrelated_jitter(base, cap, previous);
+                    previous
+                }
+            },
+            $($rest)*
+        )
+    }};
+    ($retries:expr, backoff: $base_ms:expr, max: $max_ms:expr, jitter, $($rest:tt)*) => {{
+        let (base_ms, max_ms): (u64, u64) = ($base_ms, $max_ms);
+        $crate::retry_async!(
+            @attempt $retries,
+            { |attempt| $crate::retry::jitter($crate::retry::exponential_delay(base_ms, max_ms, attempt)) },
+            $($rest)*
+        )
+    }};
+    ($retries:expr, $delay_ms:expr, jitter, $($rest:tt)*) => {
+        $crate::retry_async!(
+            @attempt $retries,
+            { |_| $crate::retry::jitter(std::time::Duration::from_millis($delay_ms)) },
+            $($rest)*
+        )
+    };
+    ($retries:expr, backoff: $base_ms:expr, max: $max_ms:expr, $($rest:tt)*) => {{
+        let (base_ms, max_ms): (u64, u64) = ($base_ms, $max_ms);
+        $crate::retry_async!(
+            @attempt $retries,
+            { |attempt| $crate::retry::exponential_delay(base_ms, max_ms, attempt) },
+            $($rest)*
+        )
+    }};
+    ($retries:expr, $delay_ms:expr, $($rest:tt)*) => {
+        $crate::retry_async!(
+            @attempt $retries,
+            { |_| std::time::Duration::from_millis($delay_ms) },
+            $($rest)*
+        )
+    };
+}
+
+/// Like [`with_retry!`], returning `Result<(T, u32), E>` where the `u32` is the 1-based
+/// number of the attempt that succeeded, e.g. for SLO reporting.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let mut calls = 0;
+/// let result = with_retry_counted!(3, 10, {
+///     calls += 1;
+///     if calls < 2 { Err("busy") } else { Ok("done") }
+/// });
+/// assert_eq!(result, Ok(("done", 2)));
+/// ```
+#[macro_export]
+macro_rules! with_retry_counted {
+    ($($args:tt)*) => {
+        $crate::with_retry!($($args)*, counted: true)
+    };
+}
+
+/// Retries a synchronous expression (returning a `Result`) until an `Instant` deadline
+/// instead of a number of attempts, waiting the given milliseconds between attempts. A retry
+/// whose delay would end past the deadline is skipped and the last error returned, so the
+/// expression is evaluated at least once but never started after the deadline.
+///
+/// Takes the same trailing options as [`with_retry!`].
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// use std::time::{Duration, Instant};
+///
+/// fn connect() -> Result<u32, &'static str> { Err("refused") }
+/// let deadline = Instant::now() + Duration::from_millis(50);
+/// let result = retry_until!(deadline, 10, connect());
+/// assert_eq!(result, Err("refused"));
+/// assert!(Instant::now() < deadline + Duration::from_millis(10));
+/// ```
+#[macro_export]
+macro_rules! retry_until {
+    ($deadline:expr, $delay_ms:expr, $($rest:tt)*) => {{
+        let deadline: std::time::Instant = $deadline;
+        $crate::with_retry!(
+            forever,
+            $delay_ms,
+            $($rest)*,
+            max_elapsed_ms: deadline.saturating_duration_since(std::time::Instant::now()).as_millis() as u64
+        )
+    }};
+}
+
+/// Like [`retry_until!`] for an asynchronous expression, with the same forms and options as
+/// [`retry_async!`].
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// use std::time::{Duration, Instant};
+///
+/// # async fn connect() -> Result<u32, &'static str> { Ok(42) }
+/// # #[tokio::main]
+/// # async fn main() {
+/// let deadline = Instant::now() + Duration::from_secs(5);
+/// let result = retry_until_async!(deadline, 100, connect());
+/// assert_eq!(result, Ok(42));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! retry_until_async {
+    ($deadline:expr, $delay_ms:expr, $($rest:tt)*) => {{
+        let deadline: std::time::Instant = $deadline;
+        $crate::retry_async!(
+            forever,
+            $delay_ms,
+            $($rest)*,
+            max_elapsed_ms: deadline.saturating_duration_since(std::time::Instant::now()).as_millis() as u64
+        )
+    }};
+}
+
+/// Like [`with_retry!`], but a panicking attempt counts as a failed one instead of unwinding
+/// the caller: every attempt runs in `std::panic::catch_unwind`, a panic is logged at error
+/// level with its message (when the payload is a `&str` or `String`) and the attempt is
+/// retried as usual.
+///
+/// The macro returns `Result<T, RetryError<E>>`: `RetryError::Panicked(message)` when the
+/// last attempt panicked, or `RetryError::Exhausted(err)` when it failed; `if:` and
+/// `on_retry:` see that `RetryError` as well. The expression is wrapped in
+/// `AssertUnwindSafe`, so it doesn't need to be `UnwindSafe`, but state it mutates may be left
+/// half-updated by a panic. The panic hook still runs, so panics are still printed to stderr.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// fn parse(input: &str) -> Result<u32, std::num::ParseIntError> {
+///     if input.is_empty() { panic!("empty input"); }
+///     input.parse()
+/// }
+///
+/// let result = with_retry_catching!(3, 10, parse("42"));
+/// assert_eq!(result, Ok(42));
+///
+/// let result = with_retry_catching!(2, 10, parse(""));
+/// assert_eq!(result, Err(RetryError::Panicked("empty input".to_string())));
+/// ```
+#[macro_export]
+macro_rules! with_retry_catching {
+    ($($args:tt)*) => {
+        $crate::with_retry!($($args)*, catch_panics: true)
+    };
+}
+
+/// Like [`retry_async!`], returning `Result<(T, u32), E>` where the `u32` is the 1-based
+/// number of the attempt that succeeded.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// # async fn dummy_async_op() -> Result<u32, &'static str> { Ok(42) }
+/// # #[tokio::main]
+/// # async fn main() {
+/// let result = retry_async_counted!(3, 10, dummy_async_op());
+/// assert_eq!(result, Ok((42, 1)));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! retry_async_counted {
+    ($($args:tt)*) => {
+        $crate::retry_async!($($args)*, counted: true)
+    };
+}
+
+/// Hedges an asynchronous request: when the first attempt hasn't finished after `after_ms`
+/// milliseconds, an identical one is started alongside it, and so on up to `max_parallel`
+/// attempts in flight, and the first attempt to succeed wins. The attempts still running are
+/// then dropped, which cancels them. An attempt failing early starts the next one right away;
+/// when all `max_parallel` attempts fail, the last error is returned.
+///
+/// Takes a closure building a fresh future for every attempt, like the closure form of
+/// [`retry_async!`], and uses tokio's timer.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// # async fn read_replica() -> Result<u32, &'static str> { Ok(42) }
+/// # #[tokio::main]
+/// # async fn main() {
+/// let result = hedge_async!(after_ms: 50, max_parallel: 2, || read_replica());
+/// assert_eq!(result, Ok(42));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! hedge_async {
+    (after_ms: $after_ms:expr, max_parallel: $max_parallel:expr, $make_attempt:expr) => {
+        $crate::retry::hedge(
+            std::time::Duration::from_millis($after_ms),
+            $max_parallel,
+            $make_attempt,
+        )
+        .await
+    };
+}
+
+/// Guards a call (an expression returning a `Result`) with a [`CircuitBreaker`]: while the
+/// breaker is open the expression is not evaluated and the macro returns
+/// `Err(CircuitError::Open)`, otherwise its outcome is recorded and an error is returned as
+/// `CircuitError::Failed(err)`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// let breaker = Arc::new(CircuitBreaker::new(5, Duration::from_secs(10)));
+/// fn lookup() -> Result<u32, &'static str> { Ok(42) }
+/// assert_eq!(circuit_breaker!(breaker, lookup()), Ok(42));
+/// ```
+#[macro_export]
+macro_rules! circuit_breaker {
+    ($breaker:expr, $expr:expr) => {
+        match $crate::CircuitBreaker::acquire(&$breaker) {
+            Some(permit) => match $expr {
+                Ok(val) => {
+                    permit.success();
+                    Ok(val)
+                }
+                Err(err) => {
+                    permit.failure();
+                    Err($crate::CircuitError::Failed(err))
+                }
+            },
+            None => Err($crate::CircuitError::Open),
+        }
+    };
+}
+
+/// Like [`circuit_breaker!`] for a future, which is only awaited when the breaker lets the
+/// call through. A half-open probe cancelled before it finishes counts as a failure.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// use std::time::Duration;
+///
+/// static BREAKER: std::sync::LazyLock<CircuitBreaker> =
+///     std::sync::LazyLock::new(|| CircuitBreaker::new(5, Duration::from_secs(10)));
+/// # async fn fetch() -> Result<u32, &'static str> { Ok(42) }
+/// # #[tokio::main]
+/// # async fn main() {
+/// assert_eq!(circuit_breaker_async!(BREAKER, fetch()), Ok(42));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! circuit_breaker_async {
+    ($breaker:expr, $future:expr) => {
+        match $crate::CircuitBreaker::acquire(&$breaker) {
+            Some(permit) => match $future.await {
+                Ok(val) => {
+                    permit.success();
+                    Ok(val)
+                }
+                Err(err) => {
+                    permit.failure();
+                    Err($crate::CircuitError::Failed(err))
+                }
+            },
+            None => Err($crate::CircuitError::Open),
+        }
+    };
+}
+
+/// Retries an HTTP request made with `reqwest` (requires the `reqwest` feature), honoring
+/// the server's `Retry-After` header.
+///
+/// Takes a closure building the request future, called once per attempt. Responses with
+/// `429 Too Many Requests` or `503 Service Unavailable` and network errors (connect, timeout
+/// and send failures) are retried: after the full delay from `Retry-After` (seconds or an
+/// HTTP-date) when the server sent one, otherwise with exponential backoff from 100ms capped
+/// at 30s, or `backoff: base, max: cap` when given. Any other response, including other 4xx
+/// statuses, is returned right away. Once the attempts are used up the last response or error
+/// is returned, so the macro evaluates to `reqwest::Result<reqwest::Response>` like `send()`.
+///
+/// `max_retry_after_ms: ...` bounds how long the server may make us wait: a `Retry-After`
+/// longer than that stops retrying and returns the response instead of retrying early.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use zirv_macros::*;
+/// # #[tokio::main]
+/// # async fn main() -> reqwest::Result<()> {
+/// let client = reqwest::Client::new();
+/// let url = "http://localhost:8080/status";
+///
+/// let response = retry_http!(5, || client.get(url).send())?;
+/// println!("{}", response.status());
+///
+/// let response = retry_http!(5, backoff: 500, max: 10_000, || client.get(url).send())?;
+/// println!("{}", response.status());
+///
+/// // Returns the 429 as is if the server asks us to wait more than a minute.
+/// let response = retry_http!(5, max_retry_after_ms: 60_000, || client.get(url).send())?;
+/// println!("{}", response.status());
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "reqwest")]
+#[macro_export]
+macro_rules! retry_http {
+    (@run $retries:expr, $base_ms:expr, $max_ms:expr, $max_retry_after_ms:expr, $make_request:expr) => {{
+        let retries = $retries as u32;
+        let (base_ms, max_ms): (u64, u64) = ($base_ms, $max_ms);
+        let max_retry_after: ::core::option::Option<::std::time::Duration> =
+            ::core::option::Option::map($max_retry_after_ms, ::std::time::Duration::from_millis);
+        #[allow(unused_mut)]
+        let mut make_request = $make_request;
+        let mut attempts: u32 = 0;
+        let result = loop {
+            let result: ::core::result::Result<reqwest::Response, reqwest::Error> =
+                make_request().await;
+            attempts += 1;
+            let Some(retry) = $crate::retry::http_retry(&result) else {
+                break result;
+            };
+            if attempts >= retries {
+                $crate::__zirv_retry_log!(exhausted, attempts, format_args!("{}", retry.reason));
+                break result;
+            }
+            let delay = match (retry.retry_after, max_retry_after) {
+                (Some(delay), Some(max)) if delay > max => {
+                    $crate::__zirv_log!(
+                        error,
+                        attempts,
+                        retry_after_ms = delay.as_millis() as u64,
+                        "Giving up after {} attempts: Retry-After of {:?} exceeds {:?}: {}",
+                        attempts,
+                        delay,
+                        max,
+                        retry.reason
+                    );
+                    break result;
+                }
+                (Some(delay), _) => delay,
+                (None, _) => $crate::retry::exponential_delay(base_ms, max_ms, attempts),
+            };
+            $crate::__zirv_retry_log!(
+                retrying,
+                attempts,
+                retries,
+                delay,
+                format_args!("{}", retry.reason)
+            );
+            $crate::retry::sleep_async(delay).await;
+        };
+        $crate::hooks::report_retry(
+            concat!(file!(), ":", line!()),
+            attempts,
+            result.as_ref().is_ok_and(|response| response.status().is_success()),
+        );
+        result
+    }};
+    ($retries:expr, backoff: $base_ms:expr, max: $max_ms:expr, max_retry_after_ms: $cap:expr, $make_request:expr) => {
+        $crate::retry_http!(@run $retries, $base_ms, $max_ms, Some::<u64>($cap), $make_request)
+    };
+    ($retries:expr, backoff: $base_ms:expr, max: $max_ms:expr, $make_request:expr) => {
+        $crate::retry_http!(@run $retries, $base_ms, $max_ms, None::<u64>, $make_request)
+    };
+    ($retries:expr, max_retry_after_ms: $cap:expr, $make_request:expr) => {
+        $crate::retry_http!(@run $retries, 100, 30_000, Some::<u64>($cap), $make_request)
+    };
+    ($retries:expr, $make_request:expr) => {
+        $crate::retry_http!(@run $retries, 100, 30_000, None::<u64>, $make_request)
+    };
+}
+
+/// Retries a database operation with `sqlx` (requires the `sqlx` feature): [`retry_async!`]
+/// with exponential backoff starting at the given milliseconds (capped at 30s) that only
+/// retries errors [`is_transient_db_error`] accepts, returning any other error right away.
+///
+/// Takes the same expressions (including closures building a fresh future) and trailing
+/// options as `retry_async!`, except `if:`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// # async fn transfer(from: u32, to: u32) -> Result<(), sqlx::Error> { Ok(()) }
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), sqlx::Error> {
+/// // Retries serialization failures and deadlocks after 100ms, then 200ms.
+/// retry_db!(3, 100, transfer(1, 2))?;
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "sqlx")]
+#[macro_export]
+macro_rules! retry_db {
+    ($retries:expr, $base_ms:expr, $($rest:tt)*) => {
+        $crate::retry_async!(
+            $retries,
+            backoff: $base_ms,
+            max: 30_000,
+            $($rest)*,
+            if: $crate::is_transient_db_error
+        )
+    };
+}
+
+/// Wraps a block of code in a tracing span with the given name, enabling automatic instrumentation.
+///
+/// The span is at info level unless a level is given first, as in
+/// `span_wrap!(level: trace, "name", { ... })`. Unknown levels fail to compile.
+///
+/// Don't use it around code containing `.await`: the span stays entered while the task is
+/// suspended, so unrelated code running on the thread in the meantime ends up in it. Use
+/// [`span_wrap_async!`] instead, which instruments the future; passing an `async` block to
+/// `span_wrap!` fails to compile with a hint to do so.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// span_wrap!("my_span", {
+///     println!("Inside span");
+/// });
+///
+/// let sum = span_wrap!(level: trace, "hot_loop", {
+///     (0..10).sum::<u32>()
+/// });
+/// assert_eq!(sum, 45);
+/// ```
+///
+/// Span names must be string literals. For a name computed at runtime, use `dynamic:`: the
+/// span is named `"dynamic"` and the runtime name goes into its `otel.name` field, which
+/// OpenTelemetry exporters use as the span name instead. Other subscribers, such as the `fmt`
+/// one, still show `dynamic`, and filters by span name can only match `dynamic`.
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let job_id = 42;
+/// span_wrap!(dynamic: format!("job-{}", job_id), {
+///     println!("Inside job span");
+/// });
+/// ```
+///
+/// When the block completes, the time it took is recorded in whole milliseconds as the span's
+/// `duration_ms` field, for backends that don't keep span close timestamps. Starting with
+/// `with_duration` also returns it, as a `(value, Duration)` tuple.
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let (sum, elapsed) = span_wrap!(with_duration, level: debug, "hot_loop", {
+///     (0..10).sum::<u32>()
+/// });
+/// assert_eq!(sum, 45);
+/// assert!(elapsed < std::time::Duration::from_secs(1));
+/// ```
+///
+/// Starting with `catch` logs a panic of the block as an error event inside the span, with
+/// the panic message in the `panic` field, before resuming it. The block then runs in a
+/// closure, so a `return` or `?` in it exits the block rather than the enclosing function.
+///
+/// ```rust,should_panic
+/// # use zirv_macros::*;
+/// span_wrap!(catch, level: debug, "request", {
+///     panic!("boom");
+/// });
+/// ```
+///
+/// For very hot code, `sample: rate` only creates the span for roughly that fraction of the
+/// calls (any `f64` expression, so it can come from configuration) and runs the bare block
+/// otherwise, sparing the cost of the span and its fields. A rate of `0.0` or less never
+/// creates it and `1.0` or more always does.
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let rate = 0.01;
+/// let sum: u64 = (0..1000u64).map(|i| span_wrap!(sample: rate, level: trace, "hot_path", { i })).sum();
+/// assert_eq!(sum, 499_500);
+/// ```
+///
+/// Only available with the `tracing` feature enabled, as it is by default.
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! span_wrap {
+    (@wrap [$($catch:ident)?] $span:expr, $block:block) => {{
+        let span = $span;
+        let _enter = span.enter();
+        let start = std::time::Instant::now();
+        let result = $crate::span_wrap!(@run [$($catch)?] $block);
+        let elapsed = start.elapsed();
+        span.record("duration_ms", elapsed.as_millis() as u64);
+        (result, elapsed)
+    }};
+    (@run [] $block:block) => {
+        $block
+    };
+    (@run [catch] $block:block) => {
+        $crate::__private::log_panics(|| $block)
+    };
+    (@opts $catch:tt with_duration, level: $level:ident, dynamic: $name:expr, async $($future:tt)*) => {
+        compile_error!("span_wrap! can't instrument an async block, use span_wrap_async! instead")
+    };
+    (@opts $catch:tt with_duration, level: $level:ident, dynamic: $name:expr, $block:block) => {
+        $crate::span_wrap!(
+            @wrap $catch
+            $crate::__private::tracing::span!(
+                $crate::__zirv_level!($level),
+                "dynamic",
+                otel.name = %$name,
+                duration_ms = $crate::__private::tracing::field::Empty
+            ),
+            $block
+        )
+    };
+    (@opts $catch:tt with_duration, level: $level:ident, $span_name:expr, async $($future:tt)*) => {
+        compile_error!("span_wrap! can't instrument an async block, use span_wrap_async! instead")
+    };
+    (@opts $catch:tt with_duration, level: $level:ident, $span_name:expr, $block:block) => {
+        $crate::span_wrap!(
+            @wrap $catch
+            $crate::__private::tracing::span!(
+                $crate::__zirv_level!($level),
+                $span_name,
+                duration_ms = $crate::__private::tracing::field::Empty
+            ),
+            $block
+        )
+    };
+    (@opts $catch:tt with_duration, $($rest:tt)*) => {
+        $crate::span_wrap!(@opts $catch with_duration, level: info, $($rest)*)
+    };
+    (@opts $catch:tt level: $level:ident, $($rest:tt)*) => {
+        $crate::span_wrap!(@opts $catch with_duration, level: $level, $($rest)*).0
+    };
+    (@opts $catch:tt $($rest:tt)*) => {
+        $crate::span_wrap!(@opts $catch with_duration, level: info, $($rest)*).0
+    };
+    (catch, $($rest:tt)*) => {
+        $crate::span_wrap!(@opts [catch] $($rest)*)
+    };
+    (sample: $rate:expr, level: $level:ident, $span_name:expr, $block:block) => {{
+        // The block is expanded once, so items declared in it are shared by both paths.
+        let span = $crate::__private::sampled($rate).then(|| {
+            $crate::__private::tracing::span!(
+                $crate::__zirv_level!($level),
+                $span_name,
+                duration_ms = $crate::__private::tracing::field::Empty
+            )
+        });
+        let _enter = span.as_ref().map($crate::__private::tracing::Span::enter);
+        let start = span.as_ref().map(|_| std::time::Instant::now());
+        let result = $block;
+        if let (Some(span), Some(start)) = (&span, start) {
+            span.record("duration_ms", start.elapsed().as_millis() as u64);
+        }
+        result
+    }};
+    (sample: $rate:expr, $span_name:expr, $block:block) => {
+        $crate::span_wrap!(sample: $rate, level: info, $span_name, $block)
+    };
+    ($($rest:tt)*) => {
+        $crate::span_wrap!(@opts [] $($rest)*)
+    };
+}
+
+/// Runs a future inside a tracing span with the given name and returns its output, like
+/// [`span_wrap!`] for async code. The future is instrumented with the span (entered only
+/// while the future is polled) rather than holding an entered guard across `.await`s, so
+/// events emitted inside it are parented correctly even when the task moves between threads.
+///
+/// The span is at info level unless a level is given first, as in
+/// `span_wrap_async!(level: debug, "name", async { ... })`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// # async fn load_user(id: u32) -> String { format!("user {}", id) }
+/// # #[tokio::main]
+/// # async fn main() {
+/// let user = span_wrap_async!("load_user", async {
+///     let user = load_user(7).await;
+///     tracing::info!("loaded");
+///     user
+/// });
+/// assert_eq!(user, "user 7");
+/// # }
+/// ```
+///
+/// Like with [`span_wrap!`], starting with `catch` logs a panic of the future inside the span
+/// before resuming it.
+///
+/// Only available with the `tracing` feature enabled, as it is by default.
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! span_wrap_async {
+    (catch, level: $level:ident, $span_name:expr, $future:expr) => {
+        $crate::span_wrap_async!(level: $level, $span_name, $crate::__private::log_panics_async($future))
+    };
+    (catch, $span_name:expr, $future:expr) => {
+        $crate::span_wrap_async!(catch, level: info, $span_name, $future)
+    };
+    (level: $level:ident, $span_name:expr, $future:expr) => {
+        $crate::__private::tracing::Instrument::instrument(
+            $future,
+            $crate::__private::tracing::span!($crate::__zirv_level!($level), $span_name),
+        )
+        .await
+    };
+    ($span_name:expr, $future:expr) => {
+        $crate::span_wrap_async!(level: info, $span_name, $future)
+    };
+}
+
+/// Spawns a future on the tokio runtime inside the current tracing span and returns its
+/// `JoinHandle`. A plain `tokio::spawn` runs the task outside the span it was spawned from,
+/// so its events lose their parent; this instruments the future with
+/// `tracing::Span::current()` first. Pass `span: ...` to use another span instead.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// # #[tokio::main]
+/// # async fn main() {
+/// let handle = span_wrap!("request", {
+///     spawn_traced!(async {
+///         tracing::info!("sending email");
+///         1
+///     })
+/// });
+/// assert_eq!(handle.await.unwrap(), 1);
+///
+/// let handle = spawn_traced!(span: tracing::info_span!("cleanup"), async {
+///     tracing::info!("cleaning up");
+/// });
+/// handle.await.unwrap();
+/// # }
+/// ```
+///
+/// Only available with the `tracing` feature enabled, as it is by default.
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! spawn_traced {
+    (span: $span:expr, $future:expr) => {
+        tokio::spawn($crate::__private::tracing::Instrument::instrument($future, $span))
+    };
+    ($future:expr) => {
+        $crate::spawn_traced!(span: $crate::__private::tracing::Span::current(), $future)
+    };
+}
+
+/// Runs a block inside a `log_context` span holding the given fields, so that every tracing
+/// event emitted inside it, including those of the other macros of this crate, carries them
+/// (as far as the subscriber shows span fields, as the `fmt` one does). Fields use the
+/// syntax of `tracing`, `%` and `?` sigils included, and nested contexts add up.
+///
+/// For async code, pass an `async` block instead: the future is instrumented with the span,
+/// like [`span_wrap_async!`] does, and awaited.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// # fn process(tenant: &str) -> Result<u32, String> { Err(format!("{} is suspended", tenant)) }
+/// # #[tokio::main]
+/// # async fn main() {
+/// let tenant_id = "acme";
+/// let processed = log_context!(tenant_id = %tenant_id, batch = 3, {
+///     log_error!(process(tenant_id), 0)
+/// });
+/// assert_eq!(processed, 0);
+///
+/// let processed = log_context!(tenant_id = "acme", async {
+///     tokio::task::yield_now().await;
+///     1
+/// });
+/// assert_eq!(processed, 1);
+/// # }
+/// ```
+///
+/// Only available with the `tracing` feature enabled, as it is by default.
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! log_context {
+    (@fields [$($fields:tt)*] , async $($future:tt)+) => {
+        $crate::__private::tracing::Instrument::instrument(
+            async $($future)+,
+            $crate::__private::tracing::info_span!("log_context", $($fields)*),
+        )
+        .await
+    };
+    (@fields [$($fields:tt)*] , $block:block) => {{
+        let span = $crate::__private::tracing::info_span!("log_context", $($fields)*);
+        let _enter = span.enter();
+        $block
+    }};
+    (@fields [$($fields:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::log_context!(@fields [$($fields)* $next] $($rest)*)
+    };
+    ($($input:tt)+) => {
+        $crate::log_context!(@fields [] $($input)+)
+    };
+}
+
+/// Returns a request id for correlating logs, after recording it as the `request_id` field
+/// of the current span. Without an argument, a new id is generated with [`new_request_id`]
+/// (a UUID v4 with the `uuid` feature); pass an existing id, e.g. from an incoming
+/// `x-request-id` header, to propagate it instead.
+///
+/// Like `log_duration!(record: ...)`, the field is only recorded if the current span declares
+/// it, e.g. as `request_id = tracing::field::Empty`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let span = tracing::info_span!("request", request_id = tracing::field::Empty);
+/// let _enter = span.enter();
+///
+/// let id = request_id!();
+/// assert!(!id.is_empty());
+///
+/// let incoming = Some("4f1c2a");
+/// let id = match incoming {
+///     Some(header) => request_id!(header),
+///     None => request_id!(),
+/// };
+/// assert_eq!(id, "4f1c2a");
+/// ```
+///
+/// Only available with the `tracing` feature enabled, as it is by default.
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! request_id {
+    () => {
+        $crate::request_id!($crate::new_request_id())
+    };
+    ($id:expr) => {{
+        let id: String = ($id).into();
+        $crate::__private::tracing::Span::current().record("request_id", id.as_str());
+        id
+    }};
+}
+
+/// Records late-bound values on fields of the current span, e.g. a row count only known
+/// once a query returns. The span must declare each field up front, e.g. as
+/// `rows_returned = tracing::field::Empty`; rather than silently dropping the value like
+/// `Span::record` does, a field that isn't declared, or recording outside of any span, is
+/// reported with a debug event naming the field.
+///
+/// Takes a single `"field", value` pair or any number of `"field" => value` pairs. Values
+/// can be anything `tracing` records, such as integers, strings or
+/// `tracing::field::display(...)`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let span = tracing::info_span!(
+///     "query",
+///     rows_returned = tracing::field::Empty,
+///     cache_hit = tracing::field::Empty
+/// );
+/// let _enter = span.enter();
+///
+/// let rows = vec![1, 2, 3];
+/// record_field!("rows_returned", rows.len());
+/// record_field!("rows_returned" => rows.len(), "cache_hit" => false);
+/// ```
+///
+/// Only available with the `tracing` feature enabled, as it is by default.
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! record_field {
+    ($key:expr, $value:expr $(,)?) => {
+        $crate::record_field!($key => $value)
+    };
+    ($($key:expr => $value:expr),+ $(,)?) => {{
+        let span = $crate::__private::tracing::Span::current();
+        $($crate::__private::record_field(&span, $key, $value);)+
+    }};
+}
+
+/// Logs the duration of a code block using tracing.
+/// Executes the block, logs the elapsed time with the provided label, and returns the result.
+/// Besides the human-readable message, the event carries `label` and `duration_ms` (an `f64`)
+/// fields so latencies can be aggregated from structured logs.
+///
+/// With `min_ms = ...` (any `u64` expression), the event is only emitted when the block
+/// took longer than the threshold, which keeps hot paths from flooding the logs. Faster runs
+/// are still recorded in the `timing-stats` registry and `metrics` histograms.
+/// With `warn_after_ms = ...`, the event is always emitted, but at warn level instead of
+/// info when the block took longer than the threshold.
+/// With `fmt = ...`, the message renders the duration like [`time_it!`] does
+/// (`ms`, `us`, `s` or `human`); the `duration_ms` field is unaffected.
+/// With the `metrics` feature, durations are recorded as histograms like [`time_it!`] does,
+/// and `labels = ["key" => value, ...]` attaches extra metric labels.
+///
+/// With `record: "field"`, no event is emitted: the duration is recorded in whole
+/// milliseconds on the field of the current span, which must declare it (e.g. as
+/// `tracing::field::Empty`). Outside of any span, it falls back to an info event labelled
+/// with the field name.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let result = log_duration!("test", { 42 });
+/// assert_eq!(result, 42);
+///
+/// let threshold_ms: u64 = 100;
+/// let result = log_duration!("slow only", min_ms = threshold_ms, { 42 });
+/// assert_eq!(result, 42);
+///
+/// let result = log_duration!("db query", warn_after_ms = 500, { 42 });
+/// assert_eq!(result, 42);
+///
+/// let result = log_duration!("render", fmt = us, { 42 });
+/// assert_eq!(result, 42);
+///
+/// let result = log_duration!("db_query", labels = ["table" => "users"], { 42 });
+/// assert_eq!(result, 42);
+/// ```
+///
+#[cfg_attr(feature = "tracing", doc = "```rust")]
+#[cfg_attr(not(feature = "tracing"), doc = "```ignore")]
+/// # use zirv_macros::*;
+/// let span = tracing::info_span!("handler", db_ms = tracing::field::Empty);
+/// let _enter = span.enter();
+/// let result = log_duration!(record: "db_ms", { 42 });
+/// assert_eq!(result, 42);
+/// ```
+#[cfg(not(feature = "timing-disabled"))]
+#[macro_export]
+macro_rules! log_duration {
+    (record: $field:expr, $block:block) => {{
+        let start = std::time::Instant::now();
+        let result = { $block };
+        let elapsed = start.elapsed();
+        $crate::__zirv_record_duration!($field, elapsed);
+        result
+    }};
+    ($label:expr, warn_after_ms = $warn_after_ms:expr, $block:block) => {{
+        let start = std::time::Instant::now();
+        let result = { $block };
+        let elapsed = start.elapsed();
+        if elapsed > std::time::Duration::from_millis($warn_after_ms) {
+            $crate::__zirv_duration_event!(warn, $label, elapsed);
+        } else {
+            $crate::__zirv_duration_event!(info, $label, elapsed);
+        }
+        result
+    }};
+    ($label:expr, labels = [$($key:literal => $value:expr),* $(,)?], $block:block) => {{
+        let start = std::time::Instant::now();
+        let result = { $block };
+        let elapsed = start.elapsed();
+        $crate::__zirv_duration_event!(
+            info,
+            $label,
+            elapsed,
+            labels = [$(($key, ::std::string::ToString::to_string(&$value))),*]
+        );
+        result
+    }};
+    ($label:expr, fmt = $format:ident, $block:block) => {{
+        let start = std::time::Instant::now();
+        let result = { $block };
+        let elapsed = start.elapsed();
+        $crate::__zirv_duration_event!(
+            info,
+            $label,
+            elapsed,
+            $crate::__zirv_duration_format!($format)
+        );
+        result
+    }};
+    ($label:expr, min_ms = $min_ms:expr, $block:block) => {{
+        let start = std::time::Instant::now();
+        let result = { $block };
+        let elapsed = start.elapsed();
+        let label = $label;
+        // Fast runs still count towards the statistics and metrics, they just aren't logged.
+        $crate::timing::record_measurement(format_args!("{}", label), elapsed, &[]);
+        if elapsed > std::time::Duration::from_millis($min_ms) {
+            $crate::__zirv_duration_event!(@unrecorded info, label, elapsed);
+        }
+        result
+    }};
+    ($label:expr, $block:block) => {{
+        let start = std::time::Instant::now();
+        let result = { $block };
+        let elapsed = start.elapsed();
+        $crate::__zirv_duration_event!(info, $label, elapsed);
+        result
+    }};
+}
+
+/// With the `timing-disabled` feature, timing is compiled out and `log_duration!` expands to its
+/// block alone.
+#[cfg(feature = "timing-disabled")]
+#[macro_export]
+macro_rules! log_duration {
+    ($($args:tt)*) => {
+        $crate::__zirv_last_block!($($args)*)
+    };
+}
+
+/// Logs the duration of a future using tracing, like [`log_duration!`] does for a block.
+/// The clock starts right before the future is first polled, so construction time is not
+/// counted. The duration is logged whatever the future resolves to, and its output
+/// (including an `Err`) is returned unchanged.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// # #[tokio::main]
+/// # async fn main() {
+/// let result = log_duration_async!("fetch", async { 42 });
+/// assert_eq!(result, 42);
+///
+/// let result: Result<u32, String> = log_duration_async!("fetch", async { Err("timeout".to_string()) });
+/// assert!(result.is_err());
+/// # }
+/// ```
+#[macro_export]
+macro_rules! log_duration_async {
+    ($label:expr, $fut:expr) => {{
+        let fut = $fut;
+        let start = std::time::Instant::now();
+        let result = fut.await;
+        let elapsed = start.elapsed();
+        $crate::__zirv_duration_event!(info, $label, elapsed);
+        result
+    }};
+}
+
+/// Starts a [`Stopwatch`](timing::Stopwatch) for timing the steps of an operation.
+/// Call `lap("name")` after each step; `finish()` (or dropping the stopwatch) logs one
+/// event per lap with its name and duration, then one with the total. The stopwatch can be
+/// held across `.await` points.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let mut sw = stopwatch!("import");
+/// let rows = vec![1, 2, 3];
+/// sw.lap("parse");
+/// let sum: i32 = rows.iter().sum();
+/// sw.lap("insert");
+/// let total = sw.finish();
+/// assert_eq!(sum, 6);
+/// assert!(total.as_secs() < 1);
+/// ```
+#[macro_export]
+macro_rules! stopwatch {
+    ($label:expr) => {
+        $crate::timing::Stopwatch::start($label)
+    };
+}
+
+/// Returns a [`TraceGuard`](timing::TraceGuard) that logs entering the current scope now and
+/// leaving it, with the time spent, when dropped, both at debug level. Unlike a block-based
+/// macro, it works unchanged in scopes with several early `return`s or `?`s.
+///
+/// Fields can follow the label, as `key = value` (formatted with `Debug`), `key = %value`
+/// (with `Display`) or `key = ?value`; they are formatted once, at creation.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// fn rebuild_index(shard: u32, docs: &[&str]) -> Option<usize> {
+///     let _guard = trace_guard!("rebuild_index", shard = shard);
+///     if docs.is_empty() {
+///         return None;
+///     }
+///     Some(docs.len())
+/// }
+/// assert_eq!(rebuild_index(3, &["a", "b"]), Some(2));
+/// assert_eq!(rebuild_index(3, &[]), None);
+/// ```
+#[macro_export]
+macro_rules! trace_guard {
+    (@fields [$($fields:expr,)*]) => {
+        vec![$($fields),*]
+    };
+    (@fields [$($fields:expr,)*] $key:ident = % $value:expr $(, $($rest:tt)*)?) => {
+        $crate::trace_guard!(
+            @fields [$($fields,)* (stringify!($key), format!("{}", $value)),] $($($rest)*)?
+        )
+    };
+    (@fields [$($fields:expr,)*] $key:ident = ? $value:expr $(, $($rest:tt)*)?) => {
+        $crate::trace_guard!(
+            @fields [$($fields,)* (stringify!($key), format!("{:?}", $value)),] $($($rest)*)?
+        )
+    };
+    (@fields [$($fields:expr,)*] $key:ident = $value:expr $(, $($rest:tt)*)?) => {
+        $crate::trace_guard!(
+            @fields [$($fields,)* (stringify!($key), format!("{:?}", $value)),] $($($rest)*)?
+        )
+    };
+    ($label:expr $(, $($fields:tt)*)?) => {
+        $crate::timing::TraceGuard::enter($label, $crate::trace_guard!(@fields [] $($($fields)*)?))
+    };
+}
+
+/// Runs a block with a latency budget in milliseconds (any `u64` expression). The block
+/// always runs to completion: this detects overruns rather than preventing them.
+/// Returns `Ok(result)` if it finished within the budget, or
+/// `Err(DeadlineExceeded { elapsed, budget, result })` if it did not.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// assert_eq!(deadline!(200, { 42 }), Ok(42));
+///
+/// let err = deadline!(0, {
+///     std::thread::sleep(std::time::Duration::from_millis(2));
+///     42
+/// })
+/// .unwrap_err();
+/// assert_eq!(err.result, 42);
+/// println!("{}", err);
+/// ```
+#[macro_export]
+macro_rules! deadline {
+    ($budget_ms:expr, $block:block) => {{
+        let budget = std::time::Duration::from_millis($budget_ms);
+        let start = std::time::Instant::now();
+        let result = { $block };
+        let elapsed = start.elapsed();
+        if elapsed > budget {
+            Err($crate::DeadlineExceeded {
+                elapsed,
+                budget,
+                result,
+            })
+        } else {
+            Ok(result)
+        }
+    }};
+}
+
+/// Awaits a future with a timeout in milliseconds (any `u64` expression), using
+/// `tokio::time::timeout`. If the budget elapses, the future is dropped, a warning is logged
+/// with the budget, file and line, and a [`TimeoutError`] is returned.
+///
+/// With two arguments, returns `Result<T, TimeoutError>` where `T` is the future's output.
+/// For futures that already return `Result<T, E>`, pass a third argument mapping the
+/// `TimeoutError` into `E`: the macro then returns the future's own `Result<T, E>` instead
+/// of nesting it.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// # use std::time::Duration;
+/// # #[derive(Debug)]
+/// # enum ApiError { Timeout(TimeoutError) }
+/// # #[tokio::main]
+/// # async fn main() {
+/// let value = timeout_async!(1000, async { 42 });
+/// assert_eq!(value, Ok(42));
+///
+/// let slow = timeout_async!(10, tokio::time::sleep(Duration::from_secs(5)));
+/// assert!(slow.is_err());
+///
+/// let response: Result<u32, ApiError> =
+///     timeout_async!(1000, async { Ok(7) }, ApiError::Timeout);
+/// assert_eq!(response.unwrap(), 7);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! timeout_async {
+    (@elapsed $budget:expr) => {{
+        $crate::__zirv_log!(
+            warn,
+            budget_ms = $budget.as_millis() as u64,
+            file = file!(),
+            line = line!(),
+            "Timed out after {:?} at {}:{}",
+            $budget,
+            file!(),
+            line!()
+        );
+        $crate::TimeoutError { budget: $budget }
+    }};
+    ($budget_ms:expr, $fut:expr, $map:expr) => {{
+        let budget = std::time::Duration::from_millis($budget_ms);
+        match tokio::time::timeout(budget, $fut).await {
+            Ok(result) => result,
+            Err(_) => Err(($map)($crate::timeout_async!(@elapsed budget))),
+        }
+    }};
+    ($budget_ms:expr, $fut:expr) => {{
+        let budget = std::time::Duration::from_millis($budget_ms);
+        match tokio::time::timeout(budget, $fut).await {
+            Ok(value) => Ok(value),
+            Err(_) => Err($crate::timeout_async!(@elapsed budget)),
+        }
+    }};
+}
+
+/// Calls a function with the provided arguments, wrapping the call in a tracing span with the specified name.
+///
+/// Like [`span_wrap!`], the span is at info level unless a level is given first.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// fn add(a: i32, b: i32) -> i32 { a + b }
+/// let result = call_with_trace!("processing", add, 2, 3);
+/// assert_eq!(result, 5);
+///
+/// let result = call_with_trace!(level: debug, "processing", add, 2, 3);
+/// assert_eq!(result, 5);
+/// ```
+///
+/// For functions returning a `Result`, start with `result` to record the outcome on the span:
+/// `error = false` on `Ok`, or `error = true` and the error's Debug output as `error.message`
+/// on `Err`, along with an error event inside the span.
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// fn parse(input: &str) -> Result<i32, std::num::ParseIntError> { input.parse() }
+/// assert_eq!(call_with_trace!(result, "parsing", parse, "42"), Ok(42));
+/// assert!(call_with_trace!(result, level: debug, "parsing", parse, "forty-two").is_err());
+/// ```
+///
+/// To record arguments on the span, pass them as `args: [...]` instead. Arguments written as
+/// `name = value` become span fields (with the `%` and `?` sigils of `tracing` to record them
+/// with `Display` or `Debug`) and are passed on to the function; plain arguments are only
+/// passed on, so they don't need to implement either. Each argument is evaluated once.
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// struct Image;
+/// fn resize(image: Image, path: &str, width: u32) -> u32 { width }
+/// let path = "cat.png";
+/// let width = call_with_trace!("resize", resize, args: [Image, path = %path, width = 640]);
+/// assert_eq!(width, 640);
+/// ```
+///
+/// Like [`span_wrap!`], starting with `catch` logs a panic of the function as an error event
+/// inside the span, with the panic message in the `panic` field, before resuming it.
+///
+/// ```rust,should_panic
+/// # use zirv_macros::*;
+/// fn parse(input: &str) -> i32 { input.parse().unwrap() }
+/// call_with_trace!(catch, "parsing", parse, "forty-two");
+/// ```
+///
+/// Only available with the `tracing` feature enabled, as it is by default.
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! call_with_trace {
+    (@run [] $call:expr) => {
+        $call
+    };
+    (@run [catch] $call:expr) => {
+        $crate::__private::log_panics(|| $call)
+    };
+    (@call plain $catch:tt, $level:ident, $span_name:expr, $func:expr, [$($args:expr,)*] [$($fields:tt)*]) => {{
+        let span = $crate::__private::tracing::span!($crate::__zirv_level!($level), $span_name, $($fields)*);
+        let _enter = span.enter();
+        $crate::call_with_trace!(@run $catch $func($($args),*))
+    }};
+    (@call result $catch:tt, $level:ident, $span_name:expr, $func:expr, [$($args:expr,)*] [$($fields:tt)*]) => {{
+        let span = $crate::__private::tracing::span!(
+            $crate::__zirv_level!($level),
+            $span_name,
+            $($fields)*
+            error = $crate::__private::tracing::field::Empty,
+            error.message = $crate::__private::tracing::field::Empty
+        );
+        let _enter = span.enter();
+        let result = $crate::call_with_trace!(@run $catch $func($($args),*));
+        $crate::__zirv_record_result!(span, result);
+        result
+    }};
+    (@opts $catch:tt result, level: $level:ident, $span_name:expr, $func:expr, args: [$($args:tt)*]) => {
+        $crate::__zirv_trace_args!(sync [result $catch, $level, $span_name, $func] [] [] $($args)*)
+    };
+    (@opts $catch:tt result, level: $level:ident, $span_name:expr, $func:expr $(, $args:expr)*) => {
+        $crate::call_with_trace!(@call result $catch, $level, $span_name, $func, [$($args,)*] [])
+    };
+    (@opts $catch:tt result, $span_name:expr, $($rest:tt)*) => {
+        $crate::call_with_trace!(@opts $catch result, level: info, $span_name, $($rest)*)
+    };
+    (@opts $catch:tt level: $level:ident, $span_name:expr, $func:expr, args: [$($args:tt)*]) => {
+        $crate::__zirv_trace_args!(sync [plain $catch, $level, $span_name, $func] [] [] $($args)*)
+    };
+    (@opts $catch:tt level: $level:ident, $span_name:expr, $func:expr $(, $args:expr)*) => {
+        $crate::call_with_trace!(@call plain $catch, $level, $span_name, $func, [$($args,)*] [])
+    };
+    (@opts $catch:tt $span_name:expr, $($rest:tt)*) => {
+        $crate::call_with_trace!(@opts $catch level: info, $span_name, $($rest)*)
+    };
+    (catch, $($rest:tt)*) => {
+        $crate::call_with_trace!(@opts [catch] $($rest)*)
+    };
+    ($($rest:tt)*) => {
+        $crate::call_with_trace!(@opts [] $($rest)*)
+    };
+}
+
+/// Calls an async function with the provided arguments and awaits the returned future inside
+/// a tracing span with the specified name, so everything the function does, not just
+/// creating the future, happens in the span. For method calls, pass a closure such as
+/// `|| client.fetch(id)`.
+///
+/// Like [`call_with_trace!`], the span is at info level unless a level is given first.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// async fn add(a: i32, b: i32) -> i32 { a + b }
+/// # #[tokio::main]
+/// # async fn main() {
+/// let result = call_with_trace_async!("processing", add, 2, 3);
+/// assert_eq!(result, 5);
+///
+/// let numbers = vec![1, 2, 3];
+/// let result = call_with_trace_async!(level: debug, "summing", || async { numbers.iter().sum::<i32>() });
+/// assert_eq!(result, 6);
+/// # }
+/// ```
+///
+/// As with [`call_with_trace!`], starting with `result` records the outcome of a function
+/// returning a `Result` on the span, `args: [...]` records arguments on it, and starting with
+/// `catch` logs a panic of the function or its future inside the span before resuming it.
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// async fn parse(input: &str) -> Result<i32, std::num::ParseIntError> { input.parse() }
+/// # #[tokio::main]
+/// # async fn main() {
+/// assert_eq!(call_with_trace_async!(result, "parsing", parse, "42"), Ok(42));
+/// # }
+/// ```
+///
+/// Only available with the `tracing` feature enabled, as it is by default.
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! call_with_trace_async {
+    (@run [] $future:expr) => {
+        $future
+    };
+    (@run [catch] $future:expr) => {
+        $crate::__private::log_panics_async($future)
+    };
+    (@call plain $catch:tt, $level:ident, $span_name:expr, $func:expr, [$($args:expr,)*] [$($fields:tt)*]) => {{
+        let span = $crate::__private::tracing::span!($crate::__zirv_level!($level), $span_name, $($fields)*);
+        let future = {
+            let _enter = span.enter();
+            $crate::call_with_trace!(@run $catch $func($($args),*))
+        };
+        $crate::__private::tracing::Instrument::instrument(
+            $crate::call_with_trace_async!(@run $catch future),
+            span,
+        )
+        .await
+    }};
+    (@call result $catch:tt, $level:ident, $span_name:expr, $func:expr, [$($args:expr,)*] [$($fields:tt)*]) => {{
+        let span = $crate::__private::tracing::span!(
+            $crate::__zirv_level!($level),
+            $span_name,
+            $($fields)*
+            error = $crate::__private::tracing::field::Empty,
+            error.message = $crate::__private::tracing::field::Empty
+        );
+        let future = {
+            let _enter = span.enter();
+            $crate::call_with_trace!(@run $catch $func($($args),*))
+        };
+        let result = $crate::__private::tracing::Instrument::instrument(
+            $crate::call_with_trace_async!(@run $catch future),
+            span.clone(),
+        )
+        .await;
+        $crate::__zirv_record_result!(span, result);
+        result
+    }};
+    (@opts $catch:tt result, level: $level:ident, $span_name:expr, $func:expr, args: [$($args:tt)*]) => {
+        $crate::__zirv_trace_args!(async [result $catch, $level, $span_name, $func] [] [] $($args)*)
+    };
+    (@opts $catch:tt result, level: $level:ident, $span_name:expr, $func:expr $(, $args:expr)*) => {
+        $crate::call_with_trace_async!(@call result $catch, $level, $span_name, $func, [$($args,)*] [])
+    };
+    (@opts $catch:tt result, $span_name:expr, $($rest:tt)*) => {
+        $crate::call_with_trace_async!(@opts $catch result, level: info, $span_name, $($rest)*)
+    };
+    (@opts $catch:tt level: $level:ident, $span_name:expr, $func:expr, args: [$($args:tt)*]) => {
+        $crate::__zirv_trace_args!(async [plain $catch, $level, $span_name, $func] [] [] $($args)*)
+    };
+    (@opts $catch:tt level: $level:ident, $span_name:expr, $func:expr $(, $args:expr)*) => {
+        $crate::call_with_trace_async!(@call plain $catch, $level, $span_name, $func, [$($args,)*] [])
+    };
+    (@opts $catch:tt $span_name:expr, $($rest:tt)*) => {
+        $crate::call_with_trace_async!(@opts $catch level: info, $span_name, $($rest)*)
+    };
+    (catch, $($rest:tt)*) => {
+        $crate::call_with_trace_async!(@opts [catch] $($rest)*)
+    };
+    ($($rest:tt)*) => {
+        $crate::call_with_trace_async!(@opts [] $($rest)*)
+    };
+}
+
+/// Asserts a condition and logs an error with a custom message if it fails, then panics.
+///
+/// # Examples
+///
+/// ```rust,should_panic
+/// # use zirv_macros::*;
+/// let value = 0;
+/// assert_msg!(value > 0, "Value must be positive");
+/// ```
+#[macro_export]
+macro_rules! assert_msg {
+    ($cond:expr, $msg:expr) => {
+        if !$cond {
+            $crate::__zirv_log!(error, "Assertion failed: {}", $msg);
+            panic!($msg);
+        }
+    };
+}
+
+/// Emits an event with the `zirv` target and `file` and `line` fields, for [`zinfo!`],
+/// [`zwarn!`] and [`zerror!`].
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_event {
+    ($level:ident, $($arg:tt)+) => {
+        $crate::__zirv_log!(
+            target: "zirv",
+            $level,
+            file = file!(),
+            line = line!(),
+            $($arg)+
+        )
+    };
+}
+
+/// Logs an info event like `tracing::info!`, with the same field and format syntax, but with
+/// the `zirv` target and `file` and `line` fields, so framework-level logs can be filtered
+/// with a single directive such as `zirv=debug`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let tenant = "acme";
+/// zinfo!("service started");
+/// zinfo!(tenant, port = 8080, "listening on port {}", 8080);
+/// ```
+#[macro_export]
+macro_rules! zinfo {
+    ($($arg:tt)+) => {
+        $crate::__zirv_event!(info, $($arg)+)
+    };
+}
+
+/// Like [`zinfo!`], but logs at warn level.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// zwarn!(retries = 3, "cache is stale");
+/// ```
+#[macro_export]
+macro_rules! zwarn {
+    ($($arg:tt)+) => {
+        $crate::__zirv_event!(warn, $($arg)+)
+    };
+}
+
+/// Like [`zinfo!`], but logs at error level.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let err = "connection refused";
+/// zerror!(error = %err, "failed to connect to {}", "db");
+/// ```
+#[macro_export]
+macro_rules! zerror {
+    ($($arg:tt)+) => {
+        $crate::__zirv_event!(error, $($arg)+)
+    };
+}
+
+/// Attempts to evaluate an expression returning a `Result` and logs an error if it fails,
+/// returning a default value instead.
+/// The error's `source()` chain, if any, is logged in the `causes` field.
+/// With the `backtrace` feature enabled and `RUST_BACKTRACE` set, a backtrace is logged
+/// in the `backtrace` field.
+///
+/// The event is emitted at error level by default; a leading `level:` argument (`error`,
+/// `warn`, `info`, `debug` or `trace`) selects another level for expected failures.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// fn fail_op() -> Result<u32, &'static str> { Err("failure") }
+/// let value = log_error!(fail_op(), 0);
+/// assert_eq!(value, 0);
+/// let value = log_error!(level: warn, fail_op(), 1);
+/// assert_eq!(value, 1);
+/// ```
+#[macro_export]
+macro_rules! log_error {
+    (level: $level:ident, $expr:expr, $default:expr) => {{
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                $crate::__zirv_log!(
+                    $level,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    backtrace = $crate::__private::capture_backtrace().map($crate::__private::display),
+                    "Error: {:?}",
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                $default
+            }
+        }
+    }};
+    ($expr:expr, $default:expr) => {
+        $crate::log_error!(level: error, $expr, $default)
+    };
+}
+
+/// Like [`log_error!`], but logs at warn level. Meant for routine, recoverable failures such
+/// as a stale cache or missing optional config.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let value = log_warn!(Err::<u32, &str>("stale cache"), 0);
+/// assert_eq!(value, 0);
+/// ```
+#[macro_export]
+macro_rules! log_warn {
+    ($expr:expr, $default:expr) => {
+        $crate::log_error!(level: warn, $expr, $default)
+    };
+}
+
+/// Like [`log_error!`], but logs at debug level. Meant for expected failures that are only
+/// interesting while debugging.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let value = log_debug!(Err::<u32, &str>("cache miss"), 0);
+/// assert_eq!(value, 0);
+/// ```
+#[macro_export]
+macro_rules! log_debug {
+    ($expr:expr, $default:expr) => {
+        $crate::log_error!(level: debug, $expr, $default)
+    };
+}
+
+/// Like [`log_error!`], but also runs a callback on the error before substituting the default,
+/// e.g. to bump a metric or push the failed item to a dead-letter queue.
+/// The callback receives the error by value after it has been logged; its return value is ignored.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let mut failures = Vec::new();
+/// let value = log_error_with!(Err::<u32, &str>("failure"), 0, |err| failures.push(err));
+/// assert_eq!(value, 0);
+/// assert_eq!(failures, vec!["failure"]);
+/// ```
+#[macro_export]
+macro_rules! log_error_with {
+    ($expr:expr, $default:expr, $callback:expr) => {{
+        match $expr {
+            Ok(val) => val,
+            Err(err) => {
+                $crate::__zirv_log!(
+                    error,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    backtrace = $crate::__private::capture_backtrace().map($crate::__private::display),
+                    "Error: {:?}",
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                let _ = ($callback)(err);
+                $default
+            }
+        }
+    }};
+}
+
+/// Awaits a future resolving to a `Result` and logs an error if it fails, then awaits the
+/// fallback future instead. The fallback is only constructed and awaited on the error path,
+/// and both branches must produce the same type.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// async fn fetch_primary() -> Result<String, &'static str> { Err("timeout") }
+/// async fn read_cache() -> String { "cached".to_string() }
+/// # #[tokio::main]
+/// # async fn main() {
+/// let value = log_error_async!(fetch_primary(), read_cache());
+/// assert_eq!(value, "cached");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! log_error_async {
+    ($primary:expr, $fallback:expr) => {{
+        match $primary.await {
+            Ok(val) => val,
+            Err(err) => {
+                $crate::__zirv_log!(
+                    error,
+                    causes = %$crate::__zirv_error_chain!(err),
+                    backtrace = $crate::__private::capture_backtrace().map($crate::__private::display),
+                    "Error: {:?}",
+                    err
+                );
+                $crate::hooks::report_error(file!(), line!());
+                $fallback.await
+            }
+        }
+    }};
+}
+
+/// Attempts to read an environment variable. If the variable is not set,
+/// logs a warning and returns a default value as a String.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// unsafe {
+/// std::env::remove_var("TEST_VAR");
+/// }
+/// let value = parse_env!("TEST_VAR", "default");
+/// assert_eq!(value, "default".to_string());
+/// ```
+#[macro_export]
+macro_rules! parse_env {
+    ($var:expr, $default:expr) => {{
+        std::env::var($var).unwrap_or_else(|_| {
+            $crate::__zirv_log!(
+                warn,
+                "Environment variable {} not set. Using default: {:?}",
+                $var,
+                $default
+            );
+            $default.to_string()
+        })
+    }};
+}
+
+/// Logs a pretty-printed JSON representation of an object that implements Serialize, as a
+/// debug event carrying it in a `json` field, so the lines of the JSON stay together in one
+/// event. `level: ...` logs at another level instead; starting with `stdout` prints the JSON
+/// to stdout, for local debugging.
+///
+/// It never panics: if the object fails to serialize (e.g. a map with non-string keys), the
+/// error is logged at warn level and nothing else is output. With `debug_fallback`, the
+/// object's pretty `Debug` output is output in place of the JSON instead.
+///
+/// With `redact: [...]` after the object, the values of the listed keys are replaced with
+/// `"***"` at any depth, arrays included, matching keys ignoring ASCII case. The `Debug`
+/// fallback isn't redacted.
+///
+/// To keep huge payloads from flooding the logs, `max_string: n` truncates strings to `n`
+/// characters, appending `…(+N chars)`, `max_array: n` keeps the first `n` elements of
+/// arrays, replacing the rest with a `"…(+N items)"` element, and `max_bytes: n` caps the
+/// whole output at `n` bytes, ending it with `…(+N bytes)`. `limits` applies the defaults
+/// of [`json::Limits`] (256 characters, 20 elements and 16 KiB) to those not given.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// let data = serde_json::json!({ "a": 1, "b": 2 });
+/// pretty_debug!(data);
+/// pretty_debug!(level: info, data);
+/// pretty_debug!(stdout, data);
+///
+/// let login = serde_json::json!({ "user": "ada", "Password": "hunter2" });
+/// pretty_debug!(login, redact: ["password", "token"]);
+///
+/// let body = serde_json::json!({ "items": vec![0; 10_000] });
+/// pretty_debug!(body, limits, max_array: 5);
+///
+/// let by_point = std::collections::HashMap::from([((0, 0), "origin")]);
+/// pretty_debug!(debug_fallback, by_point);
+/// ```
+#[macro_export]
+macro_rules! pretty_debug {
+    (@opts $sink:tt $fallback:tt stdout, $($rest:tt)+) => {
+        $crate::pretty_debug!(@opts stdout $fallback $($rest)+)
+    };
+    (@opts $sink:tt $fallback:tt level: $level:ident, $($rest:tt)+) => {
+        $crate::pretty_debug!(@opts [$level] $fallback $($rest)+)
+    };
+    (@opts $sink:tt $fallback:tt debug_fallback, $($rest:tt)+) => {
+        $crate::pretty_debug!(@opts $sink [debug_fallback] $($rest)+)
+    };
+    (@opts $sink:tt [$($fallback:ident)?] $obj:expr $(, $($post:tt)*)?) => {{
+        let obj = &$obj;
+        #[allow(unused_mut)]
+        let mut limits = $crate::json::Limits::default();
+        let rendered = match serde_json::to_value(obj) {
+            #[allow(unused_mut)]
+            Ok(mut value) => {
+                $($crate::pretty_debug!(@post value limits $($post)*);)?
+                $crate::json::truncate(&mut value, &limits);
+                Some(format!("{:#}", value))
+            }
+            Err(err) => {
+                $crate::__zirv_log!(
+                    warn,
+                    error = %err,
+                    "Could not serialize {} for pretty_debug!: {}",
+                    stringify!($obj),
+                    err
+                );
+                $crate::pretty_debug!(@fallback [$($fallback)?] obj)
+            }
+        };
+        if let Some(mut rendered) = rendered {
+            $crate::json::cap(&mut rendered, &limits);
+            $crate::pretty_debug!(@emit $sink stringify!($obj), rendered);
+        }
+    }};
+    (@post $value:ident $limits:ident redact: [$($key:expr),* $(,)?] $(, $($rest:tt)*)?) => {
+        $crate::json::redact(&mut $value, &[$($key),*]);
+        $($crate::pretty_debug!(@post $value $limits $($rest)*);)?
+    };
+    (@post $value:ident $limits:ident limits $(, $($rest:tt)*)?) => {
+        $limits = $limits.or_defaults();
+        $($crate::pretty_debug!(@post $value $limits $($rest)*);)?
+    };
+    (@post $value:ident $limits:ident max_string: $max:expr $(, $($rest:tt)*)?) => {
+        $limits.max_string = Some($max);
+        $($crate::pretty_debug!(@post $value $limits $($rest)*);)?
+    };
+    (@post $value:ident $limits:ident max_array: $max:expr $(, $($rest:tt)*)?) => {
+        $limits.max_array = Some($max);
+        $($crate::pretty_debug!(@post $value $limits $($rest)*);)?
+    };
+    (@post $value:ident $limits:ident max_bytes: $max:expr $(, $($rest:tt)*)?) => {
+        $limits.max_bytes = Some($max);
+        $($crate::pretty_debug!(@post $value $limits $($rest)*);)?
+    };
+    (@post $value:ident $limits:ident) => {};
+    (@fallback [] $obj:ident) => {
+        None
+    };
+    (@fallback [debug_fallback] $obj:ident) => {
+        Some(format!("{:#?}", $obj))
+    };
+    (@emit stdout $label:expr, $rendered:ident) => {
+        println!("{}", $rendered)
+    };
+    (@emit [$level:ident] $label:expr, $rendered:ident) => {
+        $crate::__zirv_log!($level, json = %$rendered, "{}", $label)
+    };
+    ($($input:tt)+) => {
+        $crate::pretty_debug!(@opts [debug] [] $($input)+)
+    };
+}
+
+pub mod circuit_breaker;
+pub use circuit_breaker::{CircuitBreaker, CircuitError, CircuitPermit, CircuitState};
+
+pub mod hooks;
+
+pub use hooks::{
+    ErrorHook, RetryObserver, TimerSink, set_error_hook, set_retry_observer, set_timer_sink,
+};
+
+pub mod json;
+pub use json::{JsonDiff, PatchError};
+
+pub mod request_id;
+pub use request_id::new_request_id;
+
+pub mod retry;
+#[cfg(feature = "sqlx")]
+pub use retry::is_transient_db_error;
+pub use retry::{RetryError, RetryErrors, RetryPolicy};
+pub mod timing;
+pub use timing::{DeadlineExceeded, TimeoutError};
+
+#[cfg(feature = "timing-stats")]
+pub use timing::{TimingStat, reset_timing_stats, timing_stats};
+
+#[cfg(feature = "derive")]
+pub use zirv_macros_derive::{timed, traced};
+
+// Lets `#[timed]` and `#[traced]` expansions, which name `::zirv_macros`, resolve in this crate's own tests.
+#[cfg(all(test, feature = "derive"))]
+extern crate self as zirv_macros;
+
+/// Maps a level keyword (`error`, `warn`, `info`, `debug`, `trace`) to the matching level
+/// constant of the logging backend, failing to compile on anything else.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_level {
+    (error) => {
+        $crate::__private::level::ERROR
+    };
+    (warn) => {
+        $crate::__private::level::WARN
+    };
+    (info) => {
+        $crate::__private::level::INFO
+    };
+    (debug) => {
+        $crate::__private::level::DEBUG
+    };
+    (trace) => {
+        $crate::__private::level::TRACE
+    };
+    ($other:ident) => {
+        compile_error!(concat!(
+            "unknown log level `",
+            stringify!($other),
+            "`, expected one of: error, warn, info, debug, trace"
+        ))
+    };
+}
+
+/// Emits an event through the logging backend, taking a level keyword, an optional
+/// `target:` and then the fields and message in the syntax of `tracing::event!`.
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_log {
+    (target: $target:expr, $level:ident, $($arg:tt)+) => {
+        $crate::__private::tracing::event!(target: $target, $crate::__zirv_level!($level), $($arg)+)
+    };
+    ($level:ident, $($arg:tt)+) => {
+        $crate::__private::tracing::event!($crate::__zirv_level!($level), $($arg)+)
+    };
+}
+
+/// Emits an event through the `log` crate or stderr, taking the same input as with `tracing`.
+/// The fields are appended to the message as `key=value` pairs, formatted with `Display`
+/// with the `%` sigil and with `Debug` otherwise; `None` values without a sigil are left out,
+/// as `tracing` does.
+#[cfg(not(feature = "tracing"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_log {
+    (@fields $target:tt $level:ident [$($fields:tt)*] $message:literal $($args:tt)*) => {{
+        #[allow(unused_imports)]
+        use $crate::__private::{FieldViaDebug as _, FieldViaOption as _};
+        #[allow(unused_mut)]
+        let mut fields = String::new();
+        $($crate::__zirv_log!(@field fields $fields);)*
+        $crate::__zirv_emit!(
+            $target,
+            $level,
+            format_args!("{}{}", format_args!($message $($args)*), fields)
+        )
+    }};
+    (@fields $target:tt $level:ident [$($fields:tt)*] $($key:ident).+ = % $value:expr $(, $($rest:tt)*)?) => {
+        $crate::__zirv_log!(@fields $target $level [$($fields)* [display, stringify!($($key).+), $value]] $($($rest)*)?)
+    };
+    (@fields $target:tt $level:ident [$($fields:tt)*] $($key:ident).+ = ? $value:expr $(, $($rest:tt)*)?) => {
+        $crate::__zirv_log!(@fields $target $level [$($fields)* [debug, stringify!($($key).+), $value]] $($($rest)*)?)
+    };
+    (@fields $target:tt $level:ident [$($fields:tt)*] $($key:ident).+ = $value:expr $(, $($rest:tt)*)?) => {
+        $crate::__zirv_log!(@fields $target $level [$($fields)* [value, stringify!($($key).+), $value]] $($($rest)*)?)
+    };
+    (@fields $target:tt $level:ident [$($fields:tt)*] % $key:ident $(, $($rest:tt)*)?) => {
+        $crate::__zirv_log!(@fields $target $level [$($fields)* [display, stringify!($key), $key]] $($($rest)*)?)
+    };
+    (@fields $target:tt $level:ident [$($fields:tt)*] ? $key:ident $(, $($rest:tt)*)?) => {
+        $crate::__zirv_log!(@fields $target $level [$($fields)* [debug, stringify!($key), $key]] $($($rest)*)?)
+    };
+    (@fields $target:tt $level:ident [$($fields:tt)*] $key:ident $(, $($rest:tt)*)?) => {
+        $crate::__zirv_log!(@fields $target $level [$($fields)* [value, stringify!($key), $key]] $($($rest)*)?)
+    };
+    (@field $out:ident [display, $key:expr, $value:expr]) => {
+        $crate::__private::display_field(&mut $out, $key, &$value)
+    };
+    (@field $out:ident [debug, $key:expr, $value:expr]) => {
+        $crate::__private::debug_field(&mut $out, $key, &$value)
+    };
+    (@field $out:ident [value, $key:expr, $value:expr]) => {
+        (&$crate::__private::Field(&$value)).write_field(&mut $out, $key)
+    };
+    (target: $target:expr, $level:ident, $($arg:tt)+) => {
+        $crate::__zirv_log!(@fields [$target] $level [] $($arg)+)
+    };
+    ($level:ident, $($arg:tt)+) => {
+        $crate::__zirv_log!(@fields [module_path!()] $level [] $($arg)+)
+    };
+}
+
+/// Writes a formatted event through the `log` crate.
+#[cfg(all(feature = "log", not(feature = "tracing")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_emit {
+    ([$target:expr], $level:ident, $message:expr) => {
+        $crate::__private::log::log!(target: $target, $crate::__zirv_level!($level), "{}", $message)
+    };
+}
+
+/// Writes a formatted event to stderr, prefixed with its level.
+#[cfg(not(any(feature = "log", feature = "tracing")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_emit {
+    ([$target:expr], $level:ident, $message:expr) => {
+        eprintln!("[{}] {}", $crate::__zirv_level!($level), $message)
+    };
+}
+
+/// Records the duration of `log_duration!(record: ...)` on the field of the current span,
+/// falling back to an info event outside of any span.
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_record_duration {
+    ($field:expr, $elapsed:expr) => {{
+        let elapsed = $elapsed;
+        let span = $crate::__private::tracing::Span::current();
+        if span.is_none() {
+            $crate::__zirv_duration_event!(info, $field, elapsed);
+        } else {
+            let field: &str = $field;
+            $crate::timing::record_measurement(format_args!("{}", field), elapsed, &[]);
+            span.record(field, elapsed.as_millis() as u64);
+        }
+    }};
+}
+
+/// Without `tracing`, there are no spans to record the duration of `log_duration!(record: ...)`
+/// on, so it is logged as an info event labelled with the field name.
+#[cfg(not(feature = "tracing"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_record_duration {
+    ($field:expr, $elapsed:expr) => {
+        $crate::__zirv_duration_event!(info, $field, $elapsed)
+    };
+}
+
+/// Splits the `args: [...]` list of `call_with_trace!` and `call_with_trace_async!` into the
+/// call's arguments and the span's fields, binding each argument once so that recording it
+/// doesn't evaluate it twice, then hands both back to the macro's `@call` arm.
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_trace_args {
+    (sync [$($head:tt)*] [$($args:expr,)*] [$($fields:tt)*]) => {
+        $crate::call_with_trace!(@call $($head)*, [$($args,)*] [$($fields)*])
+    };
+    (async [$($head:tt)*] [$($args:expr,)*] [$($fields:tt)*]) => {
+        $crate::call_with_trace_async!(@call $($head)*, [$($args,)*] [$($fields)*])
+    };
+    ($mode:ident $head:tt [$($args:expr,)*] [$($fields:tt)*] $name:ident = % $value:expr $(, $($rest:tt)*)?) => {
+        match $value {
+            arg => $crate::__zirv_trace_args!(
+                $mode $head [$($args,)* arg,] [$($fields)* $name = %arg,] $($($rest)*)?
+            ),
+        }
+    };
+    ($mode:ident $head:tt [$($args:expr,)*] [$($fields:tt)*] $name:ident = ? $value:expr $(, $($rest:tt)*)?) => {
+        match $value {
+            arg => $crate::__zirv_trace_args!(
+                $mode $head [$($args,)* arg,] [$($fields)* $name = ?arg,] $($($rest)*)?
+            ),
+        }
+    };
+    ($mode:ident $head:tt [$($args:expr,)*] [$($fields:tt)*] $name:ident = $value:expr $(, $($rest:tt)*)?) => {
+        match $value {
+            arg => $crate::__zirv_trace_args!(
+                $mode $head [$($args,)* arg,] [$($fields)* $name = arg,] $($($rest)*)?
+            ),
+        }
+    };
+    ($mode:ident $head:tt [$($args:expr,)*] [$($fields:tt)*] $value:expr $(, $($rest:tt)*)?) => {
+        $crate::__zirv_trace_args!($mode $head [$($args,)* $value,] [$($fields)*] $($($rest)*)?)
+    };
+}
+
+/// Records the outcome of a `Result` on a span created with empty `error` and
+/// `error.message` fields, emitting an error event inside the span on `Err`.
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_record_result {
+    ($span:ident, $result:ident) => {
+        match &$result {
+            Ok(_) => {
+                $span.record("error", false);
+            }
+            Err(err) => {
+                $span.record("error", true);
+                $span.record("error.message", $crate::__private::tracing::field::debug(err));
+                $crate::__private::tracing::error!(
+                    parent: &$span,
+                    error = ?err,
+                    "Traced call failed: {:?}",
+                    err
+                );
+            }
+        }
+    };
+}
+
+/// Logs a failed unwrap for `unwrap_or_log!`, `unwrap_or_else_log!` and
+/// `unwrap_or_default_log!` via tracing.
+#[cfg(not(feature = "println-logging"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_unwrap_failed {
+    (default: $err:expr) => {
+        $crate::__zirv_log!(
+            warn,
+            error = ?$err,
+            file = file!(),
+            line = line!(),
+            "Unwrap failed at {}:{} - {:?}. Using Default::default()",
+            file!(),
+            line!(),
+            $err
+        )
+    };
+    ($err:expr, $default:expr) => {
+        $crate::__zirv_log!(
+            warn,
+            error = ?$err,
+            default = ?$default,
+            file = file!(),
+            line = line!(),
+            "Unwrap failed at {}:{} - {:?}. Using default: {:?}",
+            file!(),
+            line!(),
+            $err,
+            $default
+        )
+    };
+    ($err:expr, $default:expr, $context:expr) => {{
+        let context = $context;
+        $crate::__zirv_log!(
+            warn,
+            error = ?$err,
+            default = ?$default,
+            context = %context,
+            file = file!(),
+            line = line!(),
+            "Unwrap failed at {}:{} - {}: {:?}. Using default: {:?}",
+            file!(),
+            line!(),
+            context,
+            $err,
+            $default
+        )
+    }};
+}
+
+/// Logs a failed unwrap for `unwrap_or_log!`, `unwrap_or_else_log!` and
+/// `unwrap_or_default_log!` to stderr.
+#[cfg(feature = "println-logging")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_unwrap_failed {
+    (default: $err:expr) => {
+        eprintln!(
+            "Unwrap failed at {}:{} - {:?}. Using Default::default()",
+            file!(),
+            line!(),
+            $err
+        )
+    };
+    ($err:expr, $default:expr) => {
+        eprintln!(
+            "Unwrap failed at {}:{} - {:?}. Using default: {:?}",
+            file!(),
+            line!(),
+            $err,
+            $default
+        )
+    };
+    ($err:expr, $default:expr, $context:expr) => {
+        eprintln!(
+            "Unwrap failed at {}:{} - {}: {:?}. Using default: {:?}",
+            file!(),
+            line!(),
+            $context,
+            $err,
+            $default
+        )
+    };
+}
+
+/// Emits the timing event shared by the duration macros, with `label` and `duration_ms` fields.
+/// `@unrecorded` only logs, for callers that record the measurement themselves.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_duration_event {
+    (@unrecorded $level:ident, $label:expr, $elapsed:expr) => {{
+        let label = $label;
+        let elapsed = $elapsed;
+        $crate::__zirv_log!(
+            $level,
+            label = %label,
+            duration_ms = elapsed.as_secs_f64() * 1000.0,
+            "{} took {:?}",
+            label,
+            elapsed
+        );
+    }};
+    ($level:ident, $label:expr, $elapsed:expr $(, labels = [$($labels:expr),*])?) => {{
+        let label = $label;
+        let elapsed = $elapsed;
+        $crate::timing::record_measurement(
+            format_args!("{}", label),
+            elapsed,
+            &[$($($labels),*)?],
+        );
+        $crate::__zirv_duration_event!(@unrecorded $level, label, elapsed);
+    }};
+    ($level:ident, $label:expr, $elapsed:expr, $format:expr) => {{
+        let label = $label;
+        let elapsed = $elapsed;
+        $crate::timing::record_measurement(format_args!("{}", label), elapsed, &[]);
+        $crate::__zirv_log!(
+            $level,
+            label = %label,
+            duration_ms = elapsed.as_secs_f64() * 1000.0,
+            "{} took {}",
+            label,
+            $crate::timing::DurationFormat::format($format, elapsed)
+        );
+    }};
+}
+
+/// Expands to the last token tree of its input, the block of a timing macro invocation.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_last_block {
+    ($block:block) => {
+        $block
+    };
+    ($head:tt $($rest:tt)+) => {
+        $crate::__zirv_last_block!($($rest)+)
+    };
+}
+
+/// Looks up a trailing option of the retry macros, or expands to the default if it is
+/// absent. `@check` rejects unknown options at compile time, `@with` expands one of two
+/// blocks depending on whether an option is present, and `@exhausted` wraps the final
+/// error in [`RetryError`] when the loop can end for other reasons.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_retry_option {
+    (@check []) => {};
+    (@check [if: $value:expr $(, $($rest:tt)*)?]) => {
+        $crate::__zirv_retry_option!(@check [$($($rest)*)?])
+    };
+    (@check [max_elapsed_ms: $value:expr $(, $($rest:tt)*)?]) => {
+        $crate::__zirv_retry_option!(@check [$($($rest)*)?])
+    };
+    (@check [on_retry: $value:expr $(, $($rest:tt)*)?]) => {
+        $crate::__zirv_retry_option!(@check [$($($rest)*)?])
+    };
+    (@check [cancel: $value:expr $(, $($rest:tt)*)?]) => {
+        $crate::__zirv_retry_option!(@check [$($($rest)*)?])
+    };
+    (@check [quiet: $value:expr $(, $($rest:tt)*)?]) => {
+        $crate::__zirv_retry_option!(@check [$($($rest)*)?])
+    };
+    (@check [attempt_timeout_ms: $value:expr $(, $($rest:tt)*)?]) => {
+        $crate::__zirv_retry_option!(@check [$($($rest)*)?])
+    };
+    (@check [all_errors: $value:expr $(, $($rest:tt)*)?]) => {
+        $crate::__zirv_retry_option!(@check [$($($rest)*)?])
+    };
+    (@check [label: $value:expr $(, $($rest:tt)*)?]) => {
+        $crate::__zirv_retry_option!(@check [$($($rest)*)?])
+    };
+    (@check [sleep_with: $value:expr $(, $($rest:tt)*)?]) => {
+        $crate::__zirv_retry_option!(@check [$($($rest)*)?])
+    };
+    (@check [counted: $value:expr $(, $($rest:tt)*)?]) => {
+        $crate::__zirv_retry_option!(@check [$($($rest)*)?])
+    };
+    (@check [catch_panics: $value:expr $(, $($rest:tt)*)?]) => {
+        $crate::__zirv_retry_option!(@check [$($($rest)*)?])
+    };
+    (@check [$other:tt: $value:expr $(, $($rest:tt)*)?]) => {
+        compile_error!(concat!(
+            "unknown retry option `",
+            stringify!($other),
+            "`, expected one of: if, max_elapsed_ms, on_retry, cancel, quiet, attempt_timeout_ms, all_errors, label, sleep_with, counted, catch_panics"
+        ))
+    };
+    (@with cancel, [cancel: $value:expr $(, $($rest:tt)*)?], $name:ident => $then:block, $else:block) => {{
+        let $name = &$value;
+        $then
+    }};
+    (@with attempt_timeout_ms, [attempt_timeout_ms: $value:expr $(, $($rest:tt)*)?], $name:ident => $then:block, $else:block) => {{
+        let $name = std::time::Duration::from_millis($value);
+        $then
+    }};
+    (@with sleep_with, [sleep_with: $value:expr $(, $($rest:tt)*)?], $name:ident => $then:block, $else:block) => {
+        $then
+    };
+    (@with catch_panics, [catch_panics: $value:expr $(, $($rest:tt)*)?], $name:ident => $then:block, $else:block) => {
+        $then
+    };
+    (@with counted, [counted: $value:expr $(, $($rest:tt)*)?], $name:ident => $then:block, $else:block) => {
+        $then
+    };
+    (@with all_errors, [all_errors: $value:expr $(, $($rest:tt)*)?], $name:ident => $then:block, $else:block) => {
+        $then
+    };
+    (@with $key:tt, [], $name:ident => $then:block, $else:block) => {
+        $else
+    };
+    (@with $key:tt, [$other:tt: $value:expr $(, $($rest:tt)*)?], $name:ident => $then:block, $else:block) => {
+        $crate::__zirv_retry_option!(@with $key, [$($($rest)*)?], $name => $then, $else)
+    };
+    (@exhausted [cancel: $value:expr $(, $($rest:tt)*)?], $err:expr) => {
+        $crate::RetryError::Exhausted($err)
+    };
+    (@exhausted [attempt_timeout_ms: $value:expr $(, $($rest:tt)*)?], $err:expr) => {
+        $crate::RetryError::Exhausted($err)
+    };
+    (@exhausted [], $err:expr) => {
+        $err
+    };
+    (@exhausted [$other:tt: $value:expr $(, $($rest:tt)*)?], $err:expr) => {
+        $crate::__zirv_retry_option!(@exhausted [$($($rest)*)?], $err)
+    };
+    (if, [if: $value:expr $(, $($rest:tt)*)?], $default:expr) => {
+        $value
+    };
+    (max_elapsed_ms, [max_elapsed_ms: $value:expr $(, $($rest:tt)*)?], $default:expr) => {
+        Some(std::time::Duration::from_millis($value))
+    };
+    (on_retry, [on_retry: $value:expr $(, $($rest:tt)*)?], $default:expr) => {
+        $value
+    };
+    (quiet, [quiet: $value:expr $(, $($rest:tt)*)?], $default:expr) => {
+        $value
+    };
+    (label, [label: $value:expr $(, $($rest:tt)*)?], $default:expr) => {
+        $value
+    };
+    (sleep_with, [sleep_with: $value:expr $(, $($rest:tt)*)?], $default:expr) => {
+        $value
+    };
+    ($key:tt, [], $default:expr) => {
+        $default
+    };
+    ($key:tt, [$other:tt: $value:expr $(, $($rest:tt)*)?], $default:expr) => {
+        $crate::__zirv_retry_option!($key, [$($($rest)*)?], $default)
+    };
+}
+
+/// Maps a format keyword (`ms`, `us`, `s`, `human`) to the matching
+/// `DurationFormat` variant, failing to compile on anything else.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_duration_format {
+    (ms) => {
+        $crate::timing::DurationFormat::Millis
+    };
+    (us) => {
+        $crate::timing::DurationFormat::Micros
+    };
+    (s) => {
+        $crate::timing::DurationFormat::Secs
+    };
+    (human) => {
+        $crate::timing::DurationFormat::Human
+    };
+    ($other:ident) => {
+        compile_error!(concat!(
+            "unknown duration format `",
+            stringify!($other),
+            "`, expected one of: ms, us, s, human"
+        ))
+    };
+}
+
+/// Maps an array strategy keyword (`replace`, `concat`, `union`) to the matching
+/// `ArrayMerge` variant, failing to compile on anything else.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_array_merge {
+    (replace) => {
+        $crate::json::ArrayMerge::Replace
+    };
+    (concat) => {
+        $crate::json::ArrayMerge::Concat
+    };
+    (union) => {
+        $crate::json::ArrayMerge::Union
+    };
+    ($other:ident) => {
+        compile_error!(concat!(
+            "unknown array merge strategy `",
+            stringify!($other),
+            "`, expected one of: replace, concat, union"
+        ))
+    };
+}
+
+/// Renders an error with `Debug`, or a placeholder for error types that don't implement it.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_error_debug {
+    ($err:expr) => {{
+        #[allow(unused_imports)]
+        use $crate::__private::{DebugViaDebug as _, DebugViaPlaceholder as _};
+        (&$crate::__private::ErrorDebug(&$err)).render()
+    }};
+}
+
+/// Logs a failed attempt of the retry macros, at warn level when it will be retried and at
+/// error level when the retry budget is exhausted.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_retry_log {
+    (retrying, $attempt:expr, $max_attempts:expr, $delay:expr, $err:expr) => {{
+        let error = $crate::__zirv_error_debug!($err);
+        // `forever` and `policy:` loops have no meaningful attempt budget.
+        if $max_attempts == u32::MAX {
+            $crate::__zirv_log!(
+                warn,
+                attempt = $attempt,
+                delay_ms = $delay.as_millis() as u64,
+                error = %error,
+                "Attempt {} failed, retrying in {:?}: {}",
+                $attempt,
+                $delay,
+                error
+            );
+        } else {
+            $crate::__zirv_log!(
+                warn,
+                attempt = $attempt,
+                max_attempts = $max_attempts,
+                delay_ms = $delay.as_millis() as u64,
+                error = %error,
+                "Attempt {}/{} failed, retrying in {:?}: {}",
+                $attempt,
+                $max_attempts,
+                $delay,
+                error
+            );
+        }
+    }};
+    (exhausted, $attempt:expr, $err:expr) => {{
+        let error = $crate::__zirv_error_debug!($err);
+        $crate::__zirv_log!(
+            error,
+            attempts = $attempt,
+            error = %error,
+            "Giving up after {} attempts: {}",
+            $attempt,
+            error
+        );
+    }};
+}
+
+/// Renders the `source()` chain of an error as indexed lines (`0: ...`, `1: ...`),
+/// following boxed `dyn Error`s and `anyhow::Error` too, or an empty string for error types
+/// that don't implement `std::error::Error`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __zirv_error_chain {
+    ($err:expr) => {{
+        #[allow(unused_imports)]
+        use $crate::__private::{ChainViaBoxed as _, ChainViaDebug as _, ChainViaError as _};
+        (&&&$crate::__private::ErrorChain(&$err)).causes()
+    }};
+}
+
+#[doc(hidden)]
+pub mod __private {
+    #[cfg(feature = "actix")]
+    pub use actix_web;
+    #[cfg(feature = "anyhow")]
+    pub use anyhow;
+    #[cfg(all(feature = "log", not(feature = "tracing")))]
+    pub use log;
+    #[cfg(feature = "tracing")]
+    pub use tracing;
+    #[cfg(feature = "tracing")]
+    pub use tracing::field::display;
+
+    /// Records a field of `record_field!` on `span`, logging a debug event instead if there
+    /// is no span or it doesn't declare the field.
+    #[cfg(feature = "tracing")]
+    pub fn record_field(span: &tracing::Span, key: &str, value: impl tracing::Value) {
+        if span.has_field(key) {
+            span.record(key, value);
+        } else if let Some(metadata) = span.metadata() {
+            tracing::debug!(
+                field = key,
+                span = metadata.name(),
+                "Not recording field {}: span {} doesn't declare it",
+                key,
+                metadata.name()
+            );
+        } else {
+            tracing::debug!(
+                field = key,
+                "Not recording field {}: there is no current span",
+                key
+            );
+        }
+    }
+
+    /// The levels `__zirv_level!` maps to, for the enabled logging backend.
+    #[cfg(feature = "tracing")]
+    pub mod level {
+        pub use tracing::Level;
+
+        pub const ERROR: Level = Level::ERROR;
+        pub const WARN: Level = Level::WARN;
+        pub const INFO: Level = Level::INFO;
+        pub const DEBUG: Level = Level::DEBUG;
+        pub const TRACE: Level = Level::TRACE;
+    }
+
+    /// The levels `__zirv_level!` maps to, for the enabled logging backend.
+    #[cfg(all(feature = "log", not(feature = "tracing")))]
+    pub mod level {
+        pub use log::Level;
+
+        pub const ERROR: Level = Level::Error;
+        pub const WARN: Level = Level::Warn;
+        pub const INFO: Level = Level::Info;
+        pub const DEBUG: Level = Level::Debug;
+        pub const TRACE: Level = Level::Trace;
+    }
+
+    /// The levels `__zirv_level!` maps to, for the enabled logging backend.
+    #[cfg(not(any(feature = "log", feature = "tracing")))]
+    pub mod level {
+        pub const ERROR: &str = "ERROR";
+        pub const WARN: &str = "WARN";
+        pub const INFO: &str = "INFO";
+        pub const DEBUG: &str = "DEBUG";
+        pub const TRACE: &str = "TRACE";
+    }
+
+    /// Records a value with `Display` without `tracing`, in place of `tracing::field::display`.
+    #[cfg(not(feature = "tracing"))]
+    pub fn display<T: std::fmt::Display>(value: T) -> DisplayValue<T> {
+        DisplayValue(value)
+    }
+
+    /// A value debug-formatted with its `Display` implementation.
+    #[cfg(not(feature = "tracing"))]
+    pub struct DisplayValue<T>(T);
+
+    #[cfg(not(feature = "tracing"))]
+    impl<T: std::fmt::Display> std::fmt::Debug for DisplayValue<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    /// Appends a `%` field of an event to its message, without `tracing`.
+    #[cfg(not(feature = "tracing"))]
+    pub fn display_field(out: &mut String, key: &str, value: &dyn std::fmt::Display) {
+        use std::fmt::Write;
+        let _ = write!(out, " {}={}", key, value);
+    }
+
+    /// Appends a `?` field of an event to its message, without `tracing`.
+    #[cfg(not(feature = "tracing"))]
+    pub fn debug_field(out: &mut String, key: &str, value: &dyn std::fmt::Debug) {
+        use std::fmt::Write;
+        let _ = write!(out, " {}={:?}", key, value);
+    }
+
+    /// A field of an event without a sigil, appended to its message without `tracing`.
+    #[cfg(not(feature = "tracing"))]
+    pub struct Field<'a, T: ?Sized>(pub &'a T);
+
+    #[cfg(not(feature = "tracing"))]
+    pub trait FieldViaOption {
+        fn write_field(&self, out: &mut String, key: &str);
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    impl<T: std::fmt::Debug> FieldViaOption for Field<'_, Option<T>> {
+        fn write_field(&self, out: &mut String, key: &str) {
+            if let Some(value) = self.0 {
+                debug_field(out, key, value);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub trait FieldViaDebug {
+        fn write_field(&self, out: &mut String, key: &str);
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    impl<T: std::fmt::Debug + ?Sized> FieldViaDebug for &Field<'_, T> {
+        fn write_field(&self, out: &mut String, key: &str) {
+            debug_field(out, key, &self.0);
+        }
+    }
+
+    /// Normalizes `Option` and `Result` values for the macros that accept either.
+    pub trait OptionLike {
+        type Value;
+        type Error;
+
+        /// Returns the value, or `Err` holding the error for a `Result` and `None` for an
+        /// `Option`.
+        fn into_outcome(self) -> Result<Self::Value, Option<Self::Error>>;
+    }
+
+    impl<T> OptionLike for Option<T> {
+        type Value = T;
+        type Error = std::convert::Infallible;
+
+        fn into_outcome(self) -> Result<T, Option<std::convert::Infallible>> {
+            self.ok_or(None)
+        }
+    }
+
+    impl<T, E> OptionLike for Result<T, E> {
+        type Value = T;
+        type Error = E;
+
+        fn into_outcome(self) -> Result<T, Option<E>> {
+            self.map_err(Some)
+        }
+    }
+
+    /// Captures a backtrace for the error macros when the `backtrace` feature is enabled and
+    /// backtraces are turned on via `RUST_LIB_BACKTRACE` or `RUST_BACKTRACE`, like
+    /// `std::backtrace::Backtrace::capture` (but without caching the environment).
+    #[cfg(feature = "backtrace")]
+    pub fn capture_backtrace() -> Option<std::backtrace::Backtrace> {
+        let enabled = std::env::var_os("RUST_LIB_BACKTRACE")
+            .or_else(|| std::env::var_os("RUST_BACKTRACE"))
+            .is_some_and(|value| value != "0");
+        enabled.then(std::backtrace::Backtrace::force_capture)
+    }
+
+    #[cfg(not(feature = "backtrace"))]
+    #[inline(always)]
+    pub fn capture_backtrace() -> Option<std::backtrace::Backtrace> {
+        None
+    }
+
+    /// Wrapper used to pick between [`DebugViaDebug`] and [`DebugViaPlaceholder`] through
+    /// autoref specialization.
+    pub struct ErrorDebug<'a, T: ?Sized>(pub &'a T);
+
+    pub trait DebugViaDebug {
+        fn render(&self) -> String;
+    }
+
+    impl<T: std::fmt::Debug + ?Sized> DebugViaDebug for ErrorDebug<'_, T> {
+        fn render(&self) -> String {
+            format!("{:?}", self.0)
+        }
+    }
+
+    pub trait DebugViaPlaceholder {
+        fn render(&self) -> String;
+    }
+
+    impl<T: ?Sized> DebugViaPlaceholder for &ErrorDebug<'_, T> {
+        fn render(&self) -> String {
+            String::from("<error>")
+        }
+    }
+
+    /// Wrapper used to pick between [`ChainViaError`], [`ChainViaBoxed`] and [`ChainViaDebug`]
+    /// through autoref specialization, so boxed errors and error types without a
+    /// `std::error::Error` impl still work.
+    pub struct ErrorChain<'a, T: ?Sized>(pub &'a T);
+
+    fn render_causes(err: &dyn std::error::Error) -> String {
+        std::iter::successors(err.source(), |cause| cause.source())
+            .enumerate()
+            .map(|(index, cause)| format!("{}: {}", index, cause))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub trait ChainViaError {
+        fn causes(&self) -> String;
+    }
+
+    impl<T: std::error::Error> ChainViaError for &&ErrorChain<'_, T> {
+        fn causes(&self) -> String {
+            render_causes(self.0)
+        }
+    }
+
+    /// Covers the boxed trait objects and `anyhow::Error`, which don't implement
+    /// `std::error::Error` themselves.
+    pub trait ChainViaBoxed {
+        fn causes(&self) -> String;
+    }
+
+    impl ChainViaBoxed for &ErrorChain<'_, Box<dyn std::error::Error>> {
+        fn causes(&self) -> String {
+            render_causes(self.0.as_ref())
+        }
+    }
+
+    impl ChainViaBoxed for &ErrorChain<'_, Box<dyn std::error::Error + Send + Sync>> {
+        fn causes(&self) -> String {
+            render_causes(self.0.as_ref())
+        }
+    }
+
+    #[cfg(feature = "anyhow")]
+    impl ChainViaBoxed for &ErrorChain<'_, anyhow::Error> {
+        fn causes(&self) -> String {
+            render_causes(self.0.as_ref())
+        }
+    }
+
+    pub trait ChainViaDebug {
+        fn causes(&self) -> String;
+    }
+
+    impl<T: ?Sized> ChainViaDebug for ErrorChain<'_, T> {
+        fn causes(&self) -> String {
+            String::new()
+        }
+    }
+
+    /// Returns the message of a panic payload, if it is a string.
+    pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+        if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "<non-string panic payload>".to_string()
+        }
+    }
+
+    fn log_panic(payload: Box<dyn std::any::Any + Send>) -> ! {
+        let message = panic_message(&*payload);
+        crate::__zirv_log!(error, panic = %message, "Panicked: {}", message);
+        std::panic::resume_unwind(payload)
+    }
+
+    /// Decides whether `span_wrap!(sample: rate, ...)` creates its span this time.
+    pub fn sampled(rate: f64) -> bool {
+        if rate >= 1.0 {
+            true
+        } else if rate > 0.0 {
+            // The top 53 bits of a random number as a uniform float in [0, 1).
+            ((crate::retry::random_u64() >> 11) as f64 / (1u64 << 53) as f64) < rate
+        } else {
+            false
+        }
+    }
+
+    /// Runs the block of `span_wrap!(catch, ...)`, logging a panic before resuming it.
+    pub fn log_panics<R>(block: impl FnOnce() -> R) -> R {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(block))
+            .unwrap_or_else(|payload| log_panic(payload))
+    }
+
+    /// Awaits the future of `span_wrap_async!(catch, ...)`, logging a panic before resuming it.
+    pub async fn log_panics_async<F: std::future::Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        std::future::poll_fn(|cx| {
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| future.as_mut().poll(cx)))
+                .unwrap_or_else(|payload| log_panic(payload))
+        })
+        .await
+    }
+
+    /// Gives `http_span!` the `HttpRequest` behind the requests it accepts.
+    #[cfg(feature = "actix")]
+    pub trait AsHttpRequest {
+        fn http_request(&self) -> &actix_web::HttpRequest;
+    }
+
+    #[cfg(feature = "actix")]
+    impl AsHttpRequest for actix_web::HttpRequest {
+        fn http_request(&self) -> &actix_web::HttpRequest {
+            self
+        }
+    }
+
+    #[cfg(feature = "actix")]
+    impl AsHttpRequest for actix_web::dev::ServiceRequest {
+        fn http_request(&self) -> &actix_web::HttpRequest {
+            self.request()
+        }
+    }
+
+    #[cfg(feature = "actix")]
+    impl<T: AsHttpRequest + ?Sized> AsHttpRequest for &T {
+        fn http_request(&self) -> &actix_web::HttpRequest {
+            (**self).http_request()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod test_support;
+
+/// Tests of the macros with the `log` backend or without any backend, writing to stderr.
+#[cfg(all(test, not(feature = "tracing")))]
+mod backend_tests {
+    #[cfg(feature = "log")]
+    mod capture {
+        use std::cell::RefCell;
+
+        thread_local! {
+            static RECORDS: RefCell<Vec<(log::Level, String, String)>> = const { RefCell::new(Vec::new()) };
+        }
+
+        struct Logger;
+
+        impl log::Log for Logger {
+            fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record<'_>) {
+                let entry = (
+                    record.level(),
+                    record.target().to_string(),
+                    record.args().to_string(),
+                );
+                RECORDS.with(|records| records.borrow_mut().push(entry));
+            }
+
+            fn flush(&self) {}
+        }
+
+        /// Runs `f` and returns the `(level, target, message)` of each record it logged on
+        /// the current thread.
+        pub fn records(f: impl FnOnce()) -> Vec<(log::Level, String, String)> {
+            static LOGGER: Logger = Logger;
+            let _ = log::set_logger(&LOGGER);
+            log::set_max_level(log::LevelFilter::Trace);
+            RECORDS.with(|records| records.borrow_mut().clear());
+            f();
+            RECORDS.with(|records| records.take())
+        }
+    }
+
+    #[test]
+    fn test_try_log_returns_early() {
+        fn load() -> Result<u32, String> {
+            let value = try_log!("x".parse::<u32>(), "parsing id");
+            Ok(value)
+        }
+        assert!(load().unwrap_err().starts_with("parsing id: "));
+        assert_eq!(log_error!("7".parse::<u32>(), 0), 7);
+        assert_eq!(log_error!("x".parse::<u32>(), 0), 0);
+    }
+
+    #[test]
+    fn test_with_retry_logs_and_retries() {
+        let recording = crate::retry::recorder::Recording::start();
+        let mut attempts = 0;
+        let res: Result<u32, u32> = with_retry!(3, backoff: 100, max: 1000, {
+            attempts += 1;
+            Err(attempts)
+        });
+        assert_eq!(res, Err(3));
+        assert_eq!(recording.delays_ms(), vec![100, 200]);
+    }
+
+    #[test]
+    fn test_log_duration_returns_block_value() {
+        assert_eq!(log_duration!("sum", { 1 + 2 }), 3);
+        assert_eq!(log_duration!(record: "sum_ms", { 1 + 2 }), 3);
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn test_try_log_emits_log_record_with_fields() {
+        fn load() -> Result<u32, String> {
+            let value = try_log!(level: warn, Err::<u32, _>("missing"), "loading config");
+            Ok(value)
+        }
+        let records = capture::records(|| {
+            let _ = load();
+        });
+        assert_eq!(records.len(), 1);
+        let (level, target, message) = &records[0];
+        assert_eq!(*level, log::Level::Warn);
+        assert_eq!(target, module_path!());
+        assert!(message.starts_with("Error at "));
+        assert!(message.contains(" error=\"missing\""));
+        assert!(message.contains(" context=loading config"));
+        assert!(!message.contains("backtrace="));
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn test_zwarn_and_log_duration_emit_log_records() {
+        let records = capture::records(|| {
+            zwarn!(user_id = 42, "quota exceeded");
+            log_duration!("query", {});
+        });
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].0, log::Level::Warn);
+        assert_eq!(records[0].1, "zirv");
+        assert!(records[0].2.starts_with("quota exceeded"));
+        assert!(records[0].2.contains(" user_id=42"));
+        assert_eq!(records[1].0, log::Level::Info);
+        assert!(records[1].2.contains(" label=query"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "tracing")]
+    use crate::test_support;
+    use serde_json::json;
+    use std::env;
+    use std::error::Error;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    // Test try_log! with a successful result.
+    #[test]
+    fn test_try_log_ok() {
+        fn test_fn() -> Result<i32, String> {
+            let x = try_log!(Ok::<_, Box<dyn Error>>(10));
+            Ok(x)
+        }
+        assert_eq!(test_fn().unwrap(), 10);
+    }
+
+    // Test try_log! when an error occurs. It should return early.
+    #[test]
+    fn test_try_log_err() {
+        fn test_fn() -> Result<i32, String> {
+            // This will trigger the error branch in try_log!.
+            let _x = try_log!(Err("error".to_string()));
+            // This line should never be reached.
+            Ok(42)
+        }
+        let res = test_fn();
+        assert!(res.is_err());
+        assert_eq!(res.unwrap_err(), "error".to_string());
+    }
+
+    // Test try_log! with a context message on the error path.
+    #[test]
+    fn test_try_log_context_err() {
+        fn test_fn() -> Result<i32, String> {
+            let _x = try_log!(Err("row not found".to_string()), "loading user profile");
+            Ok(42)
+        }
+        let err = test_fn().unwrap_err();
+        assert!(err.contains("loading user profile"));
+        assert_eq!(err, "loading user profile: row not found".to_string());
+    }
+
+    // Test try_log! with a context message on the success path.
+    #[test]
+    fn test_try_log_context_ok() {
+        fn test_fn() -> Result<i32, String> {
+            let x = try_log!(Ok::<_, String>(10), "loading user profile");
+            Ok(x)
+        }
+        assert_eq!(test_fn().unwrap(), 10);
+    }
+
+    // Test that try_log! emits a tracing error event with structured fields.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_try_log_emits_tracing_event() {
+        fn test_fn() -> Result<i32, String> {
+            let _x = try_log!(Err::<i32, _>("row not found"), "loading user profile");
+            Ok(42)
+        }
+        let (res, capture) = test_support::capture(test_fn);
+        assert!(res.is_err());
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.level, tracing::Level::ERROR);
+        assert_eq!(event.field("error"), Some("\"row not found\""));
+        assert_eq!(event.field("context"), Some("loading user profile"));
+        assert_eq!(event.field("file"), Some(file!()));
+        assert!(event.field("line").is_some());
+    }
+
+    // Test try_log! emitting at a caller-selected level.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_try_log_level() {
+        fn warn_fn() -> Result<i32, String> {
+            let x = try_log!(level: warn, Err::<i32, _>("not found"));
+            Ok(x)
+        }
+        fn debug_fn() -> Result<i32, String> {
+            let x = try_log!(level: debug, Err::<i32, _>("not found"), "lookup");
+            Ok(x)
+        }
+        let (res, capture) = test_support::capture(warn_fn);
+        assert_eq!(res.unwrap_err(), "not found");
+        assert_eq!(capture.events()[0].level, tracing::Level::WARN);
+
+        let (res, capture) = test_support::capture(debug_fn);
+        assert_eq!(res.unwrap_err(), "lookup: not found");
+        assert_eq!(capture.events()[0].level, tracing::Level::DEBUG);
+    }
+
+    // Test try_log! forwarding trailing key/value pairs as tracing fields.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_try_log_fields() {
+        fn test_fn(id: u32) -> Result<i32, String> {
+            let name = "alice";
+            let x = try_log!(
+                Err::<i32, _>("row not found"),
+                op = "fetch_user",
+                user_id = id,
+                user.name = %name,
+                tags = ?["a", "b"]
+            );
+            Ok(x)
+        }
+        let (res, capture) = test_support::capture(|| test_fn(42));
+        assert_eq!(res.unwrap_err(), "row not found");
+        let event = &capture.events()[0];
+        assert_eq!(event.field("op"), Some("fetch_user"));
+        assert_eq!(event.field("user_id"), Some("42"));
+        assert_eq!(event.field("user.name"), Some("alice"));
+        assert_eq!(event.field("tags"), Some("[\"a\", \"b\"]"));
+        assert_eq!(event.level, tracing::Level::ERROR);
+    }
+
+    // Test try_log! with an error code, both as a literal and a computed String.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_try_log_code() {
+        fn literal_fn() -> Result<i32, String> {
+            let x = try_log!(Err::<i32, _>("connection timed out"), code = "DB_TIMEOUT");
+            Ok(x)
+        }
+        fn computed_fn(kind: &str) -> Result<i32, String> {
+            let x = try_log!(
+                level: warn,
+                Err::<i32, _>("no rows"),
+                code = format!("DB_{}", kind.to_uppercase())
+            );
+            Ok(x)
+        }
+        let (res, capture) = test_support::capture(literal_fn);
+        assert_eq!(res.unwrap_err(), "[DB_TIMEOUT] connection timed out");
+        assert_eq!(capture.events()[0].field("error_code"), Some("DB_TIMEOUT"));
+
+        let (res, capture) = test_support::capture(|| computed_fn("empty"));
+        assert_eq!(res.unwrap_err(), "[DB_EMPTY] no rows");
+        let event = &capture.events()[0];
+        assert_eq!(event.field("error_code"), Some("DB_EMPTY"));
+        assert_eq!(event.level, tracing::Level::WARN);
+    }
+
+    // Domain error used to test try_log! with a mapping closure.
+    #[derive(Debug)]
+    enum IoAppError {
+        Storage(std::io::Error),
+    }
+
+    // Test try_log! mapping the error into a domain error while logging the original.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_try_log_map_err() {
+        fn test_fn() -> Result<i32, IoAppError> {
+            let _x = try_log!(
+                Err::<i32, _>(std::io::Error::new(std::io::ErrorKind::NotFound, "missing")),
+                |e| IoAppError::Storage(e)
+            );
+            Ok(42)
+        }
+        let (res, capture) = test_support::capture(test_fn);
+        let IoAppError::Storage(err) = res.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].field("error").unwrap().contains("NotFound"));
+    }
+
+    // Test try_log! with a mapping closure on the success path.
+    #[test]
+    fn test_try_log_map_ok() {
+        fn test_fn() -> Result<i32, IoAppError> {
+            let x = try_log!(Ok::<i32, std::io::Error>(5), |e| IoAppError::Storage(e));
+            Ok(x)
+        }
+        assert_eq!(test_fn().unwrap(), 5);
+    }
+
+    // Test try_log! with an enum variant constructor and a stored closure as the mapper.
+    #[test]
+    fn test_try_log_map_err_fn() {
+        fn variant_fn() -> Result<i32, IoAppError> {
+            let err = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+            Ok(try_log!(Err::<i32, _>(err), map_err: IoAppError::Storage))
+        }
+        fn stored_fn() -> Result<i32, String> {
+            let prefix = String::from("storage");
+            let mapper = move |e: &str| format!("{}: {}", prefix, e);
+            Ok(try_log!(level: warn, Err::<i32, _>("offline"), map_err: mapper))
+        }
+        fn move_fn() -> Result<i32, String> {
+            let prefix = String::from("cache");
+            Ok(try_log!(Err::<i32, _>("offline"), move |e| format!(
+                "{}: {}",
+                prefix, e
+            )))
+        }
+        let IoAppError::Storage(err) = variant_fn().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(stored_fn().unwrap_err(), "storage: offline");
+        assert_eq!(move_fn().unwrap_err(), "cache: offline");
+    }
+
+    // Error types for try_log_into!, mimicking an sqlx-style error wrapped by an app error.
+    #[derive(Debug, PartialEq)]
+    struct DbError(&'static str);
+
+    #[derive(Debug, PartialEq)]
+    enum AppError {
+        Database(DbError),
+    }
+
+    impl From<DbError> for AppError {
+        fn from(err: DbError) -> Self {
+            AppError::Database(err)
+        }
+    }
+
+    // Test try_log_into! converting the error through From.
+    #[test]
+    fn test_try_log_into_converts_error() {
+        fn test_fn() -> Result<i32, AppError> {
+            let _x = try_log_into!(Err::<i32, _>(DbError("row not found")));
+            Ok(42)
+        }
+        assert_eq!(
+            test_fn().unwrap_err(),
+            AppError::Database(DbError("row not found"))
+        );
+    }
+
+    // Test try_log_into! when the error already is the target type.
+    #[test]
+    fn test_try_log_into_same_type() {
+        fn test_fn(fail: bool) -> Result<i32, AppError> {
+            let res = if fail {
+                Err(AppError::Database(DbError("boom")))
+            } else {
+                Ok(7)
+            };
+            let x = try_log_into!(res, "loading");
+            Ok(x)
+        }
+        assert_eq!(test_fn(false).unwrap(), 7);
+        assert_eq!(
+            test_fn(true).unwrap_err(),
+            AppError::Database(DbError("boom"))
+        );
+    }
+
+    // Test try_log_async! awaiting futures on both branches, several times in one fn.
+    #[tokio::test]
+    async fn test_try_log_async() {
+        async fn fetch(fail: bool) -> Result<i32, DbError> {
+            if fail { Err(DbError("timeout")) } else { Ok(1) }
+        }
+        async fn handler(fail_second: bool) -> Result<i32, AppError> {
+            let a = try_log_async!(fetch(false));
+            let b = try_log_async!(fetch(fail_second), "fetching second");
+            Ok(a + b)
+        }
+        assert_eq!(handler(false).await.unwrap(), 2);
+        assert_eq!(
+            handler(true).await.unwrap_err(),
+            AppError::Database(DbError("timeout"))
+        );
+    }
+
+    // Test try_log_anyhow! attaching context to the returned anyhow::Error.
+    #[cfg(feature = "anyhow")]
+    #[test]
+    fn test_try_log_anyhow_context() {
+        fn test_fn() -> anyhow::Result<i32> {
+            let x = try_log_anyhow!("abc".parse::<i32>(), "parsing count");
+            Ok(x)
+        }
+        let err = test_fn().unwrap_err();
+        let rendered = format!("{:#}", err);
+        assert!(rendered.contains("parsing count"));
+        assert!(rendered.contains("invalid digit"));
+    }
+
+    // Test try_log_anyhow! passing Ok values through and wrapping existing anyhow errors.
+    #[cfg(feature = "anyhow")]
+    #[test]
+    fn test_try_log_anyhow_ok_and_nested() {
+        fn ok_fn() -> anyhow::Result<i32> {
+            Ok(try_log_anyhow!("12".parse::<i32>(), "parsing count"))
+        }
+        fn nested_fn() -> anyhow::Result<i32> {
+            let x = try_log_anyhow!(Err::<i32, _>(anyhow::anyhow!("inner")));
+            Ok(x)
+        }
+        assert_eq!(ok_fn().unwrap(), 12);
+        assert_eq!(format!("{:#}", nested_fn().unwrap_err()), "inner");
+    }
+
+    // Test try_log_anyhow! logging the source chain of plain and anyhow errors.
+    #[cfg(feature = "anyhow")]
+    #[test]
+    fn test_try_log_anyhow_causes() {
+        fn plain_fn() -> anyhow::Result<i32> {
+            Ok(try_log_anyhow!(
+                Err::<i32, _>(OuterError(InnerError)),
+                "querying"
+            ))
+        }
+        fn anyhow_fn() -> anyhow::Result<i32> {
+            let err = anyhow::Error::from(InnerError).context("query failed");
+            Ok(try_log_anyhow!(Err::<i32, _>(err)))
+        }
+        let (_, capture) = test_support::capture(plain_fn);
+        assert_eq!(
+            capture.events()[0].field("causes"),
+            Some("0: connection reset")
+        );
+        let (_, capture) = test_support::capture(anyhow_fn);
+        assert_eq!(
+            capture.events()[0].field("causes"),
+            Some("0: connection reset")
+        );
+    }
+
+    // Test http_span! recording the request on the span for handlers and service requests.
+    #[cfg(feature = "actix")]
+    #[actix_web::test]
+    async fn test_http_span() {
+        use actix_web::{App, HttpRequest, HttpResponse, test, web};
+        use tracing::Instrument;
+
+        async fn get_user(req: HttpRequest) -> HttpResponse {
+            async {
+                tracing::info!("loading user");
+                HttpResponse::Ok().finish()
+            }
+            .instrument(http_span!(req))
+            .await
+        }
+
+        let (capture, _guard) = test_support::install();
+        let app =
+            test::init_service(App::new().route("/users/{id}", web::get().to(get_user))).await;
+        let req = test::TestRequest::get()
+            .uri("/users/7?verbose=1")
+            .insert_header(("x-request-id", "abc-123"))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::post().uri("/orders").to_srv_request();
+        drop(http_span!(req));
+
+        let spans = capture.spans();
+        assert_eq!(spans[0].name, "http_request");
+        assert_eq!(spans[0].field("http.method"), Some("GET"));
+        assert_eq!(spans[0].field("http.route"), Some("/users/{id}"));
+        assert_eq!(spans[0].field("http.target"), Some("/users/7?verbose=1"));
+        assert_eq!(spans[0].field("request_id"), Some("abc-123"));
+        assert_eq!(spans[1].field("http.method"), Some("POST"));
+        assert_eq!(spans[1].field("http.route"), None);
+        assert_eq!(spans[1].field("http.target"), Some("/orders"));
+        assert!(!spans[1].field("request_id").unwrap().is_empty());
+        assert_eq!(capture.events()[0].spans, vec!["http_request".to_string()]);
+    }
+
+    // Test try_log_http! returning an error response from an Actix handler.
+    #[cfg(feature = "actix")]
+    #[actix_web::test]
+    async fn test_try_log_http() {
+        use actix_web::http::StatusCode;
+        use actix_web::{App, HttpResponse, test, web};
+
+        async fn internal() -> HttpResponse {
+            let value = try_log_http!(Err::<u32, _>("database unavailable"));
+            HttpResponse::Ok().json(value)
+        }
+        async fn not_found() -> HttpResponse {
+            let value = try_log_http!(Err::<u32, _>("user not found"), StatusCode::NOT_FOUND);
+            HttpResponse::Ok().json(value)
+        }
+        async fn ok() -> HttpResponse {
+            let value = try_log_http!(Ok::<u32, &str>(7));
+            HttpResponse::Ok().json(value)
+        }
+
+        let app = test::init_service(
+            App::new()
+                .route("/internal", web::get().to(internal))
+                .route("/not-found", web::get().to(not_found))
+                .route("/ok", web::get().to(ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/internal").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body, json!({ "error": "database unavailable" }));
+
+        let req = test::TestRequest::get().uri("/not-found").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body, json!({ "error": "user not found" }));
+
+        let req = test::TestRequest::get().uri("/ok").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body, json!(7));
+    }
+
+    // Test try_log_none! inside a function returning Option.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_try_log_none() {
+        fn f(res: Result<i32, &str>) -> Option<i32> {
+            let x = try_log_none!(res);
+            Some(x + 1)
+        }
+        assert_eq!(f(Ok(1)), Some(2));
+        let (res, capture) = test_support::capture(|| f(Err("broken")));
+        assert_eq!(res, None);
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, tracing::Level::ERROR);
+        assert_eq!(events[0].field("error"), Some("\"broken\""));
+    }
+
+    // Test ok_or_return! bailing out with the given value in a sync fn.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_ok_or_return() {
+        fn list(input: Result<usize, &str>, reached: &AtomicUsize) -> Vec<i32> {
+            let count = ok_or_return!(input, Vec::new());
+            reached.fetch_add(1, Ordering::SeqCst);
+            vec![0; count]
+        }
+        let reached = AtomicUsize::new(0);
+        assert_eq!(list(Ok(2), &reached), vec![0, 0]);
+        assert_eq!(reached.load(Ordering::SeqCst), 1);
+        let (res, capture) = test_support::capture(|| list(Err("db down"), &reached));
+        assert!(res.is_empty());
+        assert_eq!(reached.load(Ordering::SeqCst), 1);
+        assert_eq!(capture.events()[0].level, tracing::Level::ERROR);
+    }
+
+    // Test ok_or_return! in an async fn.
+    #[tokio::test]
+    async fn test_ok_or_return_async() {
+        async fn load(input: Result<i32, &str>, reached: &AtomicUsize) -> i32 {
+            let value = ok_or_return!(input, -1);
+            tokio::task::yield_now().await;
+            reached.fetch_add(1, Ordering::SeqCst);
+            value
+        }
+        let reached = AtomicUsize::new(0);
+        assert_eq!(load(Ok(5), &reached).await, 5);
+        assert_eq!(load(Err("fail"), &reached).await, -1);
+        assert_eq!(reached.load(Ordering::SeqCst), 1);
+    }
+
+    // Test try_continue! skipping Err values while the loop runs to completion.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_try_continue() {
+        let rows: Vec<Result<i32, &str>> = vec![Ok(1), Err("bad"), Ok(2), Err("worse"), Ok(3)];
+        let mut processed = Vec::new();
+        let mut iterations = 0;
+        let ((), capture) = test_support::capture(|| {
+            for row in rows {
+                iterations += 1;
+                let value = try_continue!(row, "skipping row");
+                processed.push(value);
+            }
+        });
+        assert_eq!(processed, vec![1, 2, 3]);
+        assert_eq!(iterations, 5);
+        let events = capture.events();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e.level == tracing::Level::WARN));
+        assert_eq!(events[0].field("context"), Some("skipping row"));
+    }
+
+    // Test try_break! stopping at the first Err value.
+    #[test]
+    fn test_try_break() {
+        let rows: Vec<Result<i32, &str>> = vec![Ok(1), Ok(2), Err("bad"), Ok(3)];
+        let mut processed = Vec::new();
+        for row in rows {
+            processed.push(try_break!(row));
+        }
+        assert_eq!(processed, vec![1, 2]);
+    }
+
+    // Test try_log_opt! passing Some values through and returning early on None.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_try_log_opt_option() {
+        fn test_fn(input: Option<i32>) -> Option<i32> {
+            let x = try_log_opt!(input, "missing input");
+            Some(x * 2)
+        }
+        assert_eq!(test_fn(Some(21)), Some(42));
+        let (res, capture) = test_support::capture(|| test_fn(None));
+        assert_eq!(res, None);
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, tracing::Level::WARN);
+        assert_eq!(events[0].message(), "missing input");
+    }
+
+    // Test try_log_opt! treating Err as the None path.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_try_log_opt_result() {
+        fn test_fn(input: Result<i32, &str>) -> Option<i32> {
+            let x = try_log_opt!(input, "bad input");
+            Some(x + 1)
+        }
+        assert_eq!(test_fn(Ok(1)), Some(2));
+        let (res, capture) = test_support::capture(|| test_fn(Err("boom")));
+        assert_eq!(res, None);
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, tracing::Level::ERROR);
+        assert_eq!(events[0].field("error"), Some("\"boom\""));
+        assert_eq!(events[0].field("context"), Some("bad input"));
+    }
+
+    // Two-level nested error for testing source chain logging.
+    #[derive(Debug)]
+    struct InnerError;
+
+    impl std::fmt::Display for InnerError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "connection reset")
+        }
+    }
+
+    impl Error for InnerError {}
+
+    #[derive(Debug)]
+    struct OuterError(InnerError);
+
+    impl std::fmt::Display for OuterError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "query failed")
+        }
+    }
+
+    impl Error for OuterError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    // Test rendering the source chain of plain, boxed and non-`Error` values.
+    #[test]
+    fn test_error_chain_render() {
+        let err = OuterError(InnerError);
+        assert_eq!(__zirv_error_chain!(err), "0: connection reset");
+        let boxed: Box<dyn Error> = Box::new(OuterError(InnerError));
+        assert_eq!(__zirv_error_chain!(boxed), "0: connection reset");
+        assert_eq!(__zirv_error_chain!(InnerError), "");
+        assert_eq!(__zirv_error_chain!("plain"), "");
+    }
+
+    // Test that try_log! and log_error! log the error source chain.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_error_source_chain() {
+        fn test_fn() -> Result<i32, String> {
+            let x = try_log!(Err::<i32, _>(OuterError(InnerError)));
+            Ok(x)
+        }
+        let (res, capture) = test_support::capture(test_fn);
+        assert_eq!(res.unwrap_err(), "query failed");
+        let event = &capture.events()[0];
+        assert!(event.field("error").unwrap().contains("OuterError"));
+        assert_eq!(event.field("causes"), Some("0: connection reset"));
+
+        let (value, capture) =
+            test_support::capture(|| log_error!(Err::<i32, _>(OuterError(InnerError)), 0));
+        assert_eq!(value, 0);
+        let event = &capture.events()[0];
+        assert!(event.message().contains("OuterError"));
+        assert!(event.field("causes").unwrap().contains("connection reset"));
+
+        fn boxed_fn() -> Result<i32, String> {
+            let err: Box<dyn Error> = Box::new(OuterError(InnerError));
+            Ok(try_log!(Err::<i32, _>(err)))
+        }
+        let (res, capture) = test_support::capture(boxed_fn);
+        assert_eq!(res.unwrap_err(), "query failed");
+        assert_eq!(
+            capture.events()[0].field("causes"),
+            Some("0: connection reset")
+        );
+
+        let err: Box<dyn Error + Send + Sync> = Box::new(OuterError(InnerError));
+        let (_, capture) = test_support::capture(|| log_error!(Err::<i32, _>(err), 0));
+        assert_eq!(
+            capture.events()[0].field("causes"),
+            Some("0: connection reset")
+        );
+    }
+
+    // Test that a backtrace is logged as a separate field when enabled.
+    #[cfg(feature = "backtrace")]
+    #[test]
+    fn test_error_backtrace() {
+        unsafe {
+            env::set_var("RUST_BACKTRACE", "1");
+        }
+        fn test_fn() -> Result<i32, String> {
+            Ok(try_log!(Err::<i32, _>("fail")))
+        }
+        let (_, capture) = test_support::capture(test_fn);
+        let event = &capture.events()[0];
+        assert!(event.field("backtrace").is_some());
+        assert!(!event.message().contains("backtrace"));
+
+        let (_, capture) = test_support::capture(|| log_error!(Err::<i32, &str>("fail"), 0));
+        assert!(capture.events()[0].fields.contains_key("backtrace"));
+    }
+
+    // Test that no backtrace field is recorded without the feature.
+    #[cfg(all(feature = "tracing", not(feature = "backtrace")))]
+    #[test]
+    fn test_error_no_backtrace() {
+        let (_, capture) = test_support::capture(|| log_error!(Err::<i32, &str>("fail"), 0));
+        assert!(capture.events()[0].field("backtrace").is_none());
+    }
+
+    // Test that errors without a std::error::Error impl still work with an empty chain.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_error_source_chain_fallback() {
+        let (value, capture) = test_support::capture(|| log_error!(Err::<i32, _>("plain"), 1));
+        assert_eq!(value, 1);
+        assert_eq!(capture.events()[0].field("causes"), Some(""));
+    }
+
+    // Test that the error hook fires on error branches only.
+    #[test]
+    fn test_error_hook() {
+        use std::sync::Mutex;
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static LINES: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+        fn hook(file: &'static str, line: u32) {
+            if file == file!() {
+                CALLS.fetch_add(1, Ordering::SeqCst);
+                LINES.lock().unwrap().push(line);
+            }
+        }
+        // Other tests may have installed the hook already; it is always this one.
+        let _ = set_error_hook(hook);
+
+        fn try_fn(res: Result<i32, &str>) -> Result<i32, String> {
+            Ok(try_log!(res))
+        }
+        let ok_line = line!() + 1;
+        let _ = log_error!(Ok::<i32, &str>(1), 0);
+        let err_line = line!() + 1;
+        let _ = log_error!(Err::<i32, &str>("fail"), 0);
+        let unwrap_line = line!() + 1;
+        let _ = unwrap_or_log!(Err::<i32, &str>("fail"), 0);
+        let some_line = line!() + 1;
+        let _ = some_or_log!(None::<i32>, 0);
+        let _ = some_or_log!(Some(1), 0);
+        let async_line = line!() + 2;
+        block_on(async {
+            let _ = log_error_async!(async { Err::<i32, &str>("fail") }, async { 0 });
+            let _ = log_error_async!(async { Ok::<i32, &str>(1) }, async { 0 });
+        });
+        let _ = try_fn(Ok(1));
+        let _ = try_fn(Err("fail"));
+
+        let continue_line = line!() + 2;
+        for row in [Ok(1), Err("bad"), Ok(2)] {
+            let _: i32 = try_continue!(row);
+        }
+        let break_line = line!() + 2;
+        for row in [Ok(1), Err("bad"), Ok(2)] {
+            let _: i32 = try_break!(row, "stopping");
+        }
+
+        let opt_line = line!() + 2;
+        fn opt_fn(value: Option<i32>, res: Result<i32, &str>) -> Option<i32> {
+            Some(try_log_opt!(value, "missing") + try_log_opt!(res))
+        }
+        let _ = opt_fn(None, Ok(1));
+        let _ = opt_fn(Some(1), Err("fail"));
+
+        let lines = LINES.lock().unwrap();
+        assert!(CALLS.load(Ordering::SeqCst) >= 9);
+        assert!(!lines.contains(&ok_line));
+        assert!(lines.contains(&err_line));
+        assert!(lines.contains(&unwrap_line));
+        assert_eq!(lines.iter().filter(|line| **line == some_line).count(), 1);
+        assert_eq!(lines.iter().filter(|line| **line == async_line).count(), 1);
+        assert_eq!(lines.iter().filter(|line| **line == opt_line).count(), 2);
+        assert_eq!(
+            lines.iter().filter(|line| **line == continue_line).count(),
+            1
+        );
+        assert_eq!(lines.iter().filter(|line| **line == break_line).count(), 1);
+    }
+
+    // Test tap_err! and tap_ok! returning the Result unchanged.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tap_err_and_tap_ok() {
+        let ((ok, err), capture) = test_support::capture(|| {
+            let ok = tap_err!(Ok::<i32, String>(1));
+            let err = tap_err!(Err::<i32, String>("fail".to_string()), "loading", id = 7);
+            (ok, err)
+        });
+        assert_eq!(ok, Ok(1));
+        assert_eq!(err, Err("fail".to_string()));
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, tracing::Level::WARN);
+        assert_eq!(events[0].message(), "loading");
+        assert_eq!(events[0].field("id"), Some("7"));
+
+        let ((ok, err), capture) = test_support::capture(|| {
+            let ok = tap_ok!(Ok::<i32, String>(2), "loaded", op = "load");
+            let err = tap_ok!(Err::<i32, String>("fail".to_string()));
+            (ok, err)
+        });
+        assert_eq!(ok, Ok(2));
+        assert_eq!(err, Err("fail".to_string()));
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, tracing::Level::DEBUG);
+        assert_eq!(events[0].message(), "loaded");
+        assert_eq!(events[0].field("op"), Some("load"));
+    }
+
+    // Test unwrap_or_log! macro.
+    #[test]
+    fn test_unwrap_or_log() {
+        let ok_val: Result<&str, &str> = Ok("hello");
+        let err_val: Result<&str, &str> = Err("fail");
+        let v1 = unwrap_or_log!(ok_val, "default");
+        assert_eq!(v1, "hello");
+        let v2 = unwrap_or_log!(err_val, "default");
+        assert_eq!(v2, "default");
+    }
+
+    // Test that unwrap_or_log! emits a tracing warning with structured fields.
+    #[cfg(all(feature = "tracing", not(feature = "println-logging")))]
+    #[test]
+    fn test_unwrap_or_log_tracing_event() {
+        let (v, capture) = test_support::capture(|| unwrap_or_log!(Err::<i32, &str>("fail"), 5));
+        assert_eq!(v, 5);
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.level, tracing::Level::WARN);
+        assert_eq!(event.target, module_path!());
+        assert_eq!(event.field("error"), Some("\"fail\""));
+        assert_eq!(event.field("default"), Some("5"));
+        assert_eq!(event.field("file"), Some(file!()));
+        assert!(event.field("line").is_some());
+    }
+
+    // Test unwrap_or_log! with a context label, including a non-literal one.
+    #[cfg(all(feature = "tracing", not(feature = "println-logging")))]
+    #[test]
+    fn test_unwrap_or_log_context() {
+        let v = unwrap_or_log!("80".parse::<u16>(), 8080, "parsing PORT header");
+        assert_eq!(v, 80);
+        let header = "PORT";
+        let (v, capture) = test_support::capture(|| {
+            unwrap_or_log!(
+                "http".parse::<u16>(),
+                8080,
+                format!("parsing {} header", header)
+            )
+        });
+        assert_eq!(v, 8080);
+        let event = &capture.events()[0];
+        assert!(event.message().contains("parsing PORT header"));
+        assert_eq!(event.field("context"), Some("parsing PORT header"));
+    }
+
+    // Test that unwrap_or_log! evaluates its default once, and only on error.
+    #[test]
+    fn test_unwrap_or_log_default_evaluated_once() {
+        let calls = AtomicUsize::new(0);
+        let default = || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            0
+        };
+        let v = unwrap_or_log!(Ok::<i32, &str>(5), default());
+        assert_eq!(v, 5);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        let v = unwrap_or_log!(Err::<i32, &str>("fail"), default());
+        assert_eq!(v, 0);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    // Test unwrap_or_default_log! with several Default types.
+    #[test]
+    fn test_unwrap_or_default_log() {
+        #[derive(Debug, Default, PartialEq)]
+        struct Settings {
+            retries: u32,
+            name: String,
+        }
+
+        let n: i32 = unwrap_or_default_log!("12".parse::<i32>());
+        assert_eq!(n, 12);
+        let n: i32 = unwrap_or_default_log!("twelve".parse::<i32>());
+        assert_eq!(n, 0);
+
+        let s = unwrap_or_default_log!(Err::<String, &str>("fail"));
+        assert_eq!(s, String::new());
+
+        let settings = unwrap_or_default_log!(Err::<Settings, &str>("fail"));
+        assert_eq!(settings, Settings::default());
+    }
+
+    // Test that unwrap_or_default_log! emits a tracing warning.
+    #[cfg(all(feature = "tracing", not(feature = "println-logging")))]
+    #[test]
+    fn test_unwrap_or_default_log_tracing_event() {
+        let (n, capture) =
+            test_support::capture(|| unwrap_or_default_log!(Err::<u32, &str>("fail")));
+        assert_eq!(n, 0);
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, tracing::Level::WARN);
+        assert_eq!(events[0].field("file"), Some(file!()));
+    }
+
+    // Test some_or_log! on Some and None.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_some_or_log() {
+        let v = some_or_log!(Some(3), 0);
+        assert_eq!(v, 3);
+        let (v, capture) = test_support::capture(|| some_or_log!(None::<i32>, 7));
+        assert_eq!(v, 7);
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, tracing::Level::WARN);
+        assert!(events[0].message().starts_with("Value was None"));
+        assert!(events[0].message().ends_with("Using default: 7"));
+    }
+
+    // Test that unwrap_or_else_log! only calls the closure on the error path.
+    #[test]
+    fn test_unwrap_or_else_log() {
+        let calls = AtomicUsize::new(0);
+        let expensive_default = || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            "fallback".to_string()
+        };
+        let v = unwrap_or_else_log!(Ok::<String, &str>("value".to_string()), expensive_default);
+        assert_eq!(v, "value");
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        let v = unwrap_or_else_log!(Err::<String, &str>("fail"), expensive_default);
+        assert_eq!(v, "fallback");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    // Timings reported to the test timer sink, which is installed once for the whole process.
+    static TIMINGS: std::sync::Mutex<Vec<(String, Duration)>> = std::sync::Mutex::new(Vec::new());
+
+    fn recording_sink(label: &str, duration: Duration) {
+        TIMINGS.lock().unwrap().push((label.to_string(), duration));
+    }
+
+    fn recorded_timings(label: &str) -> Vec<Duration> {
+        TIMINGS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(l, _)| l == label)
+            .map(|(_, d)| *d)
+            .collect()
+    }
+
+    // Test that time_it! reports to the installed timer sink once per invocation.
+    #[cfg(not(feature = "timing-disabled"))]
+    #[test]
+    fn test_time_it_sink() {
+        let _ = set_timer_sink(recording_sink);
+        let label = "sink test".to_string();
+        let a = time_it!(label, {
+            std::thread::sleep(Duration::from_millis(10));
+            1
+        });
+        let b = time_it!("sink test", { 2 });
+        assert_eq!(a + b, 3);
+        let timings = recorded_timings("sink test");
+        assert_eq!(timings.len(), 2);
+        assert!(timings[0] >= Duration::from_millis(10));
+    }
+
+    // Test time_it! macro.
+    #[test]
+    fn test_time_it() {
+        let result = time_it!("sleep test", {
+            std::thread::sleep(Duration::from_millis(50));
+            5
+        });
+        assert_eq!(result, 5);
+    }
+
+    // Test time_it_with! returning the measured duration, with and without a label.
+    #[test]
+    fn test_time_it_with() {
+        let (result, elapsed) = time_it_with!("with test", {
+            std::thread::sleep(Duration::from_millis(20));
+            7
+        });
+        assert_eq!(result, 7);
+        assert!(elapsed >= Duration::from_millis(20));
+
+        let (result, elapsed) = time_it_with!({
+            std::thread::sleep(Duration::from_millis(10));
+            8
+        });
+        assert_eq!(result, 8);
+        assert!(elapsed >= Duration::from_millis(10));
+    }
+
+    // Test time_it_async! macro.
+    #[tokio::test]
+    async fn test_time_it_async() {
+        let start = std::time::Instant::now();
+        let result = time_it_async!("async sleep test", async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            5
+        });
+        assert_eq!(result, 5);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    // Test log_duration_async! only measuring the awaited future, on success and on error.
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_log_duration_async() {
+        let (capture, _guard) = test_support::install();
+        let fut = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            5
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let result = log_duration_async!("async duration", fut);
+        assert_eq!(result, 5);
+
+        let result: Result<u32, String> = log_duration_async!("async failure", async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Err("boom".to_string())
+        });
+        assert_eq!(result, Err("boom".to_string()));
+
+        let events = capture.events();
+        assert_eq!(events.len(), 2);
+        let ms: f64 = events[0].field("duration_ms").unwrap().parse().unwrap();
+        assert!((50.0..150.0).contains(&ms), "measured {ms}ms");
+        assert_eq!(events[1].field("label"), Some("async failure"));
+        let ms: f64 = events[1].field("duration_ms").unwrap().parse().unwrap();
+        assert!(ms >= 20.0);
+    }
+
+    // Test stopwatch! logging every lap and a total covering them.
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_stopwatch() {
+        let (capture, _guard) = test_support::install();
+        let mut sw = stopwatch!("import");
+        for step in ["parse", "insert", "index"] {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            sw.lap(step);
+        }
+        let total = sw.finish();
+        assert!(total >= Duration::from_millis(30));
+
+        let events = capture.events();
+        assert_eq!(events.len(), 4);
+        assert!(events.iter().all(|e| e.field("label") == Some("import")));
+        let laps: Vec<_> = events[..3]
+            .iter()
+            .map(|e| e.field("lap").unwrap())
+            .collect();
+        assert_eq!(laps, ["parse", "insert", "index"]);
+        for event in &events[..3] {
+            let lap_ms: f64 = event.field("lap_ms").unwrap().parse().unwrap();
+            assert!(lap_ms >= 10.0);
+        }
+        let total_ms: f64 = events[3].field("total_ms").unwrap().parse().unwrap();
+        assert!(total_ms >= 30.0);
+    }
+
+    // Test trace_guard! logging entry and exit, however the scope is left.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_trace_guard() {
+        fn rebuild(shard: u32, input: &str) -> Result<u32, std::num::ParseIntError> {
+            let _guard = trace_guard!("rebuild_index", shard = shard, input = %input);
+            if input.is_empty() {
+                return Ok(0);
+            }
+            let parsed: u32 = input.parse()?;
+            Ok(parsed)
+        }
+
+        for input in ["", "x", "7"] {
+            let (_, capture) = test_support::capture(|| rebuild(3, input));
+            let events = capture.events();
+            assert_eq!(events.len(), 2, "input {:?}", input);
+            assert!(events.iter().all(|e| e.level == tracing::Level::DEBUG));
+            assert_eq!(events[0].message(), "entering rebuild_index");
+            assert!(
+                events[1]
+                    .message()
+                    .starts_with("leaving rebuild_index after ")
+            );
+            assert!(events[1].message().ends_with("ms"));
+            let fields = format!("shard=3, input={}", input);
+            assert_eq!(events[0].field("fields"), Some(fields.as_str()));
+            assert_eq!(events[1].field("fields"), Some(fields.as_str()));
+            assert!(events[1].field("duration_ms").is_some());
+        }
+
+        let (_, capture) = test_support::capture(|| {
+            let _guard = trace_guard!("plain");
+        });
+        let events = capture.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].field("fields"), None);
+        assert_eq!(events[1].field("label"), Some("plain"));
+    }
+
+    // Test deadline! within and over budget.
+    #[test]
+    fn test_deadline() {
+        assert_eq!(deadline!(1000, { "fast" }), Ok("fast"));
+
+        let err = deadline!(5, {
+            std::thread::sleep(Duration::from_millis(20));
+            "slow"
+        })
+        .unwrap_err();
+        assert_eq!(err.result, "slow");
+        assert_eq!(err.budget, Duration::from_millis(5));
+        assert!(err.elapsed >= Duration::from_millis(20));
+        assert!(
+            err.to_string()
+                .starts_with("deadline of 5ms exceeded after ")
+        );
+        let _: &dyn Error = &err;
+    }
+
+    // Test timeout_async! with a plain future, completing and timing out.
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_timeout_async() {
+        let (capture, _guard) = test_support::install();
+        assert_eq!(timeout_async!(1000, async { 5 }), Ok(5));
+
+        let result = timeout_async!(10, async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            5
+        });
+        assert_eq!(
+            result,
+            Err(TimeoutError {
+                budget: Duration::from_millis(10)
+            })
+        );
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, tracing::Level::WARN);
+        assert_eq!(events[0].field("budget_ms"), Some("10"));
+    }
+
+    // Test timeout_async! mapping the timeout into a Result future's own error type.
+    #[tokio::test]
+    async fn test_timeout_async_result() {
+        async fn call(ms: u64) -> Result<u64, String> {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            if ms == 0 {
+                Err("refused".to_string())
+            } else {
+                Ok(ms)
+            }
+        }
+        let ok: Result<u64, String> =
+            timeout_async!(1000, call(1), |e: TimeoutError| e.to_string());
+        assert_eq!(ok, Ok(1));
+        let refused = timeout_async!(1000, call(0), |e: TimeoutError| e.to_string());
+        assert_eq!(refused, Err("refused".to_string()));
+        let timed_out = timeout_async!(10, call(5000), |e: TimeoutError| e.to_string());
+        assert_eq!(timed_out, Err("timed out after 10ms".to_string()));
+    }
+
+    // Test json_merge! macro.
+    #[test]
+    fn test_json_merge() {
+        let base = json!({"a": 1, "b": 2});
+        let other = json!({"b": 3, "c": 4});
+        let merged = json_merge!(base, other);
+        assert_eq!(merged["a"], 1);
+        assert_eq!(merged["b"], 3);
+        assert_eq!(merged["c"], 4);
+    }
+
+    // Test json_merge! layering four objects, the rightmost value winning per key.
+    #[test]
+    fn test_json_merge_layers() {
+        let defaults = json!({"port": 80, "host": "localhost", "log": {"level": "info", "json": false}, "mw": ["log"]});
+        let file = json!({"port": 8080, "log": {"json": true}, "mw": ["auth"]});
+        let env = json!({"host": "0.0.0.0", "log": {"level": "debug"}});
+        let request = json!({"port": 9000, "mw": ["trace"]});
+
+        let merged = json_merge!(defaults.clone(), file, env, request);
+        assert_eq!(
+            merged,
+            json!({"port": 9000, "host": "0.0.0.0", "log": {"level": "debug"}, "mw": ["trace"]})
+        );
+
+        let merged = json_merge!(deep, arrays: concat, defaults, &file, &env, &request);
+        assert_eq!(
+            merged,
+            json!({
+                "port": 9000,
+                "host": "0.0.0.0",
+                "log": {"level": "debug", "json": true},
+                "mw": ["log", "auth", "trace"]
+            })
+        );
+    }
+
+    // Test json_merge! with each array strategy, shallow and deep.
+    #[test]
+    fn test_json_merge_arrays() {
+        let base = json!({"mw": ["log", 1, {"x": 1}], "tags": ["a"], "n": {"list": [1, [2]]}});
+        let other = json!({"mw": ["auth", 1, {"x": 1}, [3]], "tags": "b", "n": {"list": [[2], 3]}});
+
+        let merged = json_merge!(base.clone(), other);
+        assert_eq!(merged["mw"], json!(["auth", 1, {"x": 1}, [3]]));
+        assert_eq!(merged["tags"], "b");
+        assert_eq!(merged["n"], json!({"list": [[2], 3]}));
+
+        let merged = json_merge!(arrays: replace, base.clone(), other);
+        assert_eq!(merged["mw"], json!(["auth", 1, {"x": 1}, [3]]));
+
+        let merged = json_merge!(arrays: concat, base.clone(), other);
+        assert_eq!(
+            merged["mw"],
+            json!(["log", 1, {"x": 1}, "auth", 1, {"x": 1}, [3]])
+        );
+        assert_eq!(merged["tags"], "b");
+        // Without `deep`, the nested object is replaced whole.
+        assert_eq!(merged["n"], json!({"list": [[2], 3]}));
+
+        let merged = json_merge!(arrays: union, base.clone(), other);
+        assert_eq!(merged["mw"], json!(["log", 1, {"x": 1}, "auth", [3]]));
+
+        let merged = json_merge!(deep, base.clone(), other);
+        assert_eq!(merged["n"], json!({"list": [[2], 3]}));
+
+        let merged = json_merge!(deep, arrays: concat, base.clone(), other);
+        assert_eq!(merged["n"], json!({"list": [1, [2], [2], 3]}));
+
+        let merged = json_merge!(deep, arrays: union, base, other);
+        assert_eq!(merged["n"], json!({"list": [1, [2], 3]}));
+        assert_eq!(merged["mw"], json!(["log", 1, {"x": 1}, "auth", [3]]));
+    }
+
+    // Test json_merge!(deep, ...) merging nested objects and keeping the key order.
+    #[test]
+    fn test_json_merge_deep() {
+        let base =
+            json!({"db": {"host": "localhost", "pool": {"min": 1, "max": 5}}, "debug": false});
+        let other = json!({"db": {"pool": {"max": 20}}, "debug": true});
+        let merged = json_merge!(deep, base, other);
+        assert_eq!(
+            merged,
+            json!({"db": {"host": "localhost", "pool": {"min": 1, "max": 20}}, "debug": true})
+        );
+        let keys: Vec<_> = merged["db"]["pool"].as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["min", "max"]);
+    }
+
+    // Test json_merge!(patch, ...) against the examples of RFC 7386, appendix A.
+    #[test]
+    fn test_json_merge_patch_rfc_examples() {
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (
+                json!({"a": "b"}),
+                json!({"b": "c"}),
+                json!({"a": "b", "b": "c"}),
+            ),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (
+                json!({"a": "b", "b": "c"}),
+                json!({"a": null}),
+                json!({"b": "c"}),
+            ),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (
+                json!({"a": [{"b": "c"}]}),
+                json!({"a": [1]}),
+                json!({"a": [1]}),
+            ),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (
+                json!({"e": null}),
+                json!({"a": 1}),
+                json!({"e": null, "a": 1}),
+            ),
+            (
+                json!([1, 2]),
+                json!({"a": "b", "c": null}),
+                json!({"a": "b"}),
+            ),
+            (
+                json!({}),
+                json!({"a": {"bb": {"ccc": null}}}),
+                json!({"a": {"bb": {}}}),
+            ),
+        ];
+        for (original, patch, expected) in cases {
+            assert_eq!(
+                json_merge!(patch, original.clone(), patch),
+                expected,
+                "patching {} with {}",
+                original,
+                patch
+            );
+        }
+    }
+
+    // Test json_merge!(patch, ...) applying several patches in order and keeping key order.
+    #[test]
+    fn test_json_merge_patch_layers() {
+        let user = json!({"name": "Ada", "email": "ada@example.com", "age": 36});
+        let patched = json_merge!(
+            patch,
+            user,
+            json!({"email": null, "age": 37}),
+            json!({"email": "ada@lovelace.dev"})
+        );
+        assert_eq!(
+            patched,
+            json!({"name": "Ada", "age": 37, "email": "ada@lovelace.dev"})
+        );
+        let keys: Vec<_> = patched.as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["name", "age", "email"]);
+
+        // Without `patch`, a null is inserted like any other value.
+        let merged = json_merge!(json!({"a": 1}), json!({"a": null}));
+        assert_eq!(merged, json!({"a": null}));
+    }
+
+    // Test json_diff! on nested changes, added and removed keys and identical values.
+    #[test]
+    fn test_json_diff() {
+        use json::DiffKind;
+
+        let expected =
+            json!({"user": {"name": "Ada", "age": 36, "a/b": 1}, "tags": ["x", "y"], "old": true});
+        assert!(json_diff!(expected, expected.clone()).is_empty());
+
+        let actual =
+            json!({"user": {"name": "Ada", "age": 37, "a/b": 2}, "tags": ["x"], "new": null});
+        let diffs = json_diff!(expected, actual);
+        let summary: Vec<_> = diffs.iter().map(|d| (d.path.as_str(), d.kind())).collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("/user/age", DiffKind::Changed),
+                ("/user/a~1b", DiffKind::Changed),
+                ("/tags/1", DiffKind::Removed),
+                ("/old", DiffKind::Removed),
+                ("/new", DiffKind::Added),
+            ]
+        );
+        assert_eq!(diffs[0].left, Some(json!(36)));
+        assert_eq!(diffs[0].right, Some(json!(37)));
+        assert_eq!(diffs[4].left, None);
+        assert_eq!(diffs[4].right, Some(json!(null)));
+        assert_eq!(
+            json::pretty(&diffs),
+            "~ /user/age: 36 -> 37\n~ /user/a~1b: 1 -> 2\n- /tags/1: \"y\"\n- /old: true\n+ /new: null"
+        );
+
+        let diffs = json_diff!(json!({"a": [1, {"b": 2}]}), json!({"a": {"b": 2}}));
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].path, "/a");
+        assert_eq!(diffs[0].kind(), DiffKind::Changed);
+
+        let diffs = json_diff!(json!(1), json!("1"));
+        assert_eq!(diffs[0].to_string(), "~ /: 1 -> \"1\"");
+    }
+
+    // Test json_get! on hits, missing paths and values of the wrong type.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_json_get() {
+        let value = json!({
+            "user": {"address": {"zip": "90210"}, "age": 36, "admin": true, "score": 1.5},
+            "a/b": {"~c": 7},
+            "items": [{"qty": 2}]
+        });
+
+        let (hits, capture) = test_support::capture(|| {
+            (
+                json_get!(value, "/user/address/zip", as_str, "00000"),
+                json_get!(value, "/user/age", as_i64, 0),
+                json_get!(value, "/user/admin", as_bool, false),
+                json_get!(value, "/user/score", as_f64, 0.0),
+                json_get!(value, "/a~1b/~0c", as_u64, 0),
+                json_get!(value, "/items/0/qty", as_i64, 0),
+                json_get!(value, "/items", as: Vec<std::collections::HashMap<String, u32>>, Vec::new()),
+            )
+        });
+        assert_eq!(hits.0, "90210");
+        assert_eq!(hits.1, 36);
+        assert!(hits.2);
+        assert_eq!(hits.3, 1.5);
+        assert_eq!(hits.4, 7);
+        assert_eq!(hits.5, 2);
+        assert_eq!(hits.6[0]["qty"], 2);
+        assert!(capture.events().is_empty());
+
+        let (missing, capture) = test_support::capture(|| {
+            (
+                json_get!(value, "/user/address/city", as_str, "unknown"),
+                json_get!(value, "/items/3/qty", as_i64, 1),
+            )
+        });
+        assert_eq!(missing, ("unknown", 1));
+        let events = capture.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].level, tracing::Level::DEBUG);
+        assert_eq!(events[0].field("pointer"), Some("/user/address/city"));
+        assert_eq!(
+            events[0].message(),
+            "No JSON value at /user/address/city, using the default"
+        );
+
+        let (wrong, capture) = test_support::capture(|| {
+            (
+                json_get!(value, "/user/age", as_str, "?"),
+                json_get!(value, "/user/address", as: String, String::from("none")),
+            )
+        });
+        assert_eq!(wrong, ("?", "none".to_string()));
+        let events = capture.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0].message(),
+            "JSON value at /user/age failed as_str, using the default"
+        );
+        assert!(
+            events[1]
+                .message()
+                .starts_with("JSON value at /user/address is not a String, using the default: ")
+        );
+        assert!(events[1].field("error").is_some());
+    }
+
+    // Test json_patch! with every operation, including escaped paths and array ends.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_json_patch_operations() {
+        let doc = json!({"a/b": {"~c": 1}, "list": [1, 2, 3], "user": {"name": "Ada"}});
+        let patch = json!([
+            {"op": "add", "path": "/user/age", "value": 36},
+            {"op": "add", "path": "/list/1", "value": "x"},
+            {"op": "add", "path": "/list/-", "value": 4},
+            {"op": "remove", "path": "/list/0"},
+            {"op": "replace", "path": "/a~1b/~0c", "value": 2},
+            {"op": "move", "from": "/user/name", "path": "/name"},
+            {"op": "copy", "from": "/list", "path": "/user/list"},
+            {"op": "test", "path": "/user/list/0", "value": "x"},
+            {"op": "test", "path": "/a~1b", "value": {"~c": 2}}
+        ]);
+        let (patched, capture) = test_support::capture(|| json_patch!(doc, patch));
+        assert_eq!(
+            patched,
+            Ok(json!({
+                "a/b": {"~c": 2},
+                "list": ["x", 2, 3, 4],
+                "user": {"age": 36, "list": ["x", 2, 3, 4]},
+                "name": "Ada"
+            }))
+        );
+        assert!(capture.events().is_empty());
+
+        assert_eq!(
+            json_patch!(
+                json!({"a": 1}),
+                json!([{"op": "replace", "path": "", "value": [1]}])
+            ),
+            Ok(json!([1]))
+        );
+    }
+
+    // Test json_patch! failing on bad paths and failed tests with the operation's index.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_json_patch_errors() {
+        let doc = json!({"user": {"name": "Ada"}, "list": [1]});
+        let fail = |patch: serde_json::Value| json_patch!(doc.clone(), patch).unwrap_err();
+
+        let err = fail(json!([
+            {"op": "add", "path": "/user/age", "value": 36},
+            {"op": "add", "path": "/missing/age", "value": 36}
+        ]));
+        assert_eq!(err.index, 1);
+        assert_eq!(err.reason, "no value at /missing");
+        assert_eq!(
+            fail(json!([{"op": "remove", "path": "/user/email"}])).reason,
+            "no value at /user/email"
+        );
+        assert_eq!(
+            fail(json!([{"op": "replace", "path": "/nope", "value": 1}])).reason,
+            "no value at /nope"
+        );
+        assert_eq!(
+            fail(json!([{"op": "add", "path": "/list/5", "value": 1}])).reason,
+            "invalid array index at /list/5"
+        );
+        assert_eq!(
+            fail(json!([{"op": "remove", "path": "/list/01"}])).reason,
+            "no value at /list/01"
+        );
+        assert_eq!(
+            fail(json!([{"op": "move", "from": "/user", "path": "/user/me"}])).reason,
+            "cannot move /user into itself"
+        );
+        assert_eq!(
+            fail(json!([{"op": "copy", "from": "/nope", "path": "/x"}])).reason,
+            "no value at /nope"
+        );
+        assert_eq!(
+            fail(json!([{"op": "frobnicate", "path": "/x"}])).reason,
+            "unknown operation `frobnicate`"
+        );
+        assert_eq!(
+            fail(json!([{"op": "add", "path": "/x"}])).reason,
+            "missing `value` member"
+        );
+        assert_eq!(
+            fail(json!([{"op": "add", "path": "x", "value": 1}])).reason,
+            "invalid pointer \"x\""
+        );
+        assert_eq!(
+            fail(json!({"op": "add"})).reason,
+            "the patch is not an array"
+        );
+
+        let (err, capture) = test_support::capture(|| {
+            fail(json!([
+                {"op": "test", "path": "/user/name", "value": "Ada"},
+                {"op": "test", "path": "/user/name", "value": "Grace"}
+            ]))
+        });
+        assert_eq!(err.index, 1);
+        assert_eq!(
+            err.to_string(),
+            "patch operation 1 failed: test failed at /user/name: \"Ada\" != \"Grace\""
+        );
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, tracing::Level::DEBUG);
+        assert_eq!(events[0].field("path"), Some("/user/name"));
+        assert_eq!(events[0].field("expected"), Some("\"Grace\""));
+        assert_eq!(events[0].field("actual"), Some("\"Ada\""));
+    }
+
+    // For debug_query!, create a dummy type with a .sql() method.
+    struct DummyQuery {
+        sql: String,
+    }
+    impl DummyQuery {
+        fn new(sql: &str) -> Self {
+            DummyQuery {
+                sql: sql.to_string(),
+            }
+        }
+        fn sql(&self) -> &str {
+            &self.sql
+        }
+    }
+    #[test]
+    fn test_debug_query() {
+        let query = DummyQuery::new("SELECT 1");
+        let _ = debug_query!(query);
+        // The macro prints the SQL; we simply ensure it does not panic.
+    }
+
+    // Test with_retry! macro.
+    #[test]
+    fn test_with_retry_success() {
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+        let res = with_retry!(3, 10, {
+            let current = ATTEMPTS.fetch_add(1, Ordering::SeqCst);
+            if current < 2 {
+                Err("fail")
+            } else {
+                Ok("success")
+            }
+        });
+        assert_eq!(res.unwrap(), "success");
+    }
+
+    #[test]
+    fn test_with_retry_failure() {
+        let res: Result<&str, &str> = with_retry!(2, 10, { Err("always fails") });
+        assert!(res.is_err());
+    }
+
+    // Test retry_async! macro.
+    #[tokio::test]
+    async fn test_retry_async_success() {
+        use std::sync::Arc;
+        use tokio::sync::Mutex;
+        let attempts = Arc::new(Mutex::new(0));
+        let res = retry_async!(3, 10, {
+            let attempts = attempts.clone();
+            async move {
+                let mut att = attempts.lock().await;
+                if *att < 2 {
+                    *att += 1;
+                    Err("fail")
+                } else {
+                    Ok("success")
+                }
+            }
+        });
+        assert_eq!(res.unwrap(), "success");
+    }
+
+    #[tokio::test]
+    async fn test_retry_async_failure() {
+        let res: Result<&str, &str> = retry_async!(2, 10, async { Err("fail") });
+        assert!(res.is_err());
+    }
+
+    // Test the exponential backoff sequence, its cap and overflow saturation.
+    #[test]
+    fn test_with_retry_backoff() {
+        let recording = retry::recorder::Recording::start();
+        let attempts = AtomicUsize::new(0);
+        let res: Result<(), usize> = with_retry!(8, backoff: 100, max: 5000, {
+            Err(attempts.fetch_add(1, Ordering::SeqCst))
+        });
+        assert_eq!(res, Err(7));
+        assert_eq!(
+            recording.delays_ms(),
+            vec![100, 200, 400, 800, 1600, 3200, 5000]
+        );
+        assert_eq!(
+            retry::exponential_delay(100, 5000, u32::MAX),
+            Duration::from_millis(5000)
+        );
+        assert_eq!(
+            retry::exponential_delay(u64::MAX / 2, u64::MAX, 40),
+            Duration::from_millis(u64::MAX)
+        );
+    }
+
+    // Test retry_async! with exponential backoff succeeding before the budget runs out.
+    #[tokio::test]
+    async fn test_retry_async_backoff() {
+        let recording = retry::recorder::Recording::start();
+        let attempts = AtomicUsize::new(0);
+        let res = retry_async!(5, backoff: 100, max: 250, async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                n if n < 3 => Err(n),
+                n => Ok(n),
+            }
+        });
+        assert_eq!(res, Ok(3));
+        assert_eq!(recording.delays_ms(), vec![100, 200, 250]);
+    }
+
+    // Test jittered delays staying within [delay / 2, delay].
+    #[tokio::test]
+    async fn test_retry_jitter() {
+        let recording = retry::recorder::Recording::start();
+        let res: Result<(), &str> = with_retry!(6, backoff: 100, max: 800, jitter, { Err("fail") });
+        assert!(res.is_err());
+        let delays = recording.delays_ms();
+        assert_eq!(delays.len(), 5);
+        for (delay, full) in delays.iter().zip([100, 200, 400, 800, 800]) {
+            assert!(
+                (full / 2..=full).contains(delay),
+                "{delay} outside [{}, {full}]",
+                full / 2
+            );
+        }
+        drop(recording);
+
+        let recording = retry::recorder::Recording::start();
+        let res: Result<(), &str> = retry_async!(50, 100, jitter, async { Err("fail") });
+        assert!(res.is_err());
+        let delays = recording.delays_ms();
+        assert!(delays.iter().all(|delay| (50..=100).contains(delay)));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    // Test with_retry!(..., if: ...) only retrying errors the predicate accepts.
+    #[test]
+    fn test_with_retry_if() {
+        fn is_transient(err: &&str) -> bool {
+            err.starts_with("transient")
+        }
+        let run = |outcomes: Vec<Result<u32, &'static str>>| {
+            let attempts = AtomicUsize::new(0);
+            let res = with_retry!(5, 1, outcomes[attempts.fetch_add(1, Ordering::SeqCst)], if: is_transient);
+            (res, attempts.into_inner())
+        };
+        let transient = vec![Err("transient 1"), Err("transient 2"), Ok(3)];
+        assert_eq!(run(transient), (Ok(3), 3));
+        let permanent = vec![Err("unique violation"), Ok(2)];
+        assert_eq!(run(permanent), (Err("unique violation"), 1));
+        let mixed = vec![Err("transient"), Err("unique violation"), Ok(3)];
+        assert_eq!(run(mixed), (Err("unique violation"), 2));
+    }
+
+    // Test retry_async!(..., if: ...) with a closure predicate.
+    #[tokio::test]
+    async fn test_retry_async_if() {
+        let attempts = AtomicUsize::new(0);
+        let res: Result<(), String> = retry_async!(5, 1, async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err("permanent".to_string())
+        }, if: |err| err != "permanent");
+        assert_eq!(res, Err("permanent".to_string()));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    // Test max_elapsed_ms giving up near the cap with attempts remaining.
+    #[test]
+    fn test_with_retry_max_elapsed() {
+        let attempts = AtomicUsize::new(0);
+        let start = std::time::Instant::now();
+        let res: Result<(), &str> = with_retry!(20, 10, {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(30));
+            Err("slow")
+        }, max_elapsed_ms: 100);
+        assert!(res.is_err());
+        let attempts = attempts.into_inner();
+        assert!((2..=3).contains(&attempts), "{attempts} attempts");
+        assert!(start.elapsed() < Duration::from_millis(150));
+    }
+
+    // Test decorrelated jitter keeping every delay within [base, min(cap, previous * 3)].
+    #[tokio::test]
+    async fn test_retry_decorrelated_jitter() {
+        let mut seed: u64 = 0x9E37_79B9_7F4A_7C15;
+        let mut previous = Duration::from_millis(100);
+        for _ in 0..1000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let delay = retry::decorrelated_delay(
+                Duration::from_millis(100),
+                Duration::from_millis(10_000),
+                previous,
+                seed,
+            );
+            assert!(delay >= Duration::from_millis(100), "{delay:?}");
+            assert!(
+                delay <= (previous * 3).min(Duration::from_millis(10_000)),
+                "{delay:?}"
+            );
+            previous = delay;
+        }
+
+        // The recorded delays are truncated to whole milliseconds.
+        let check = |delays: Vec<u64>| {
+            let mut previous = 10;
+            for delay in delays {
+                assert!(
+                    (10..=((previous + 1) * 3).min(200)).contains(&delay),
+                    "{delay}"
+                );
+                previous = delay;
+            }
+        };
+        let recording = retry::recorder::Recording::start();
+        let res: Result<(), &str> =
+            with_retry!(20, backoff: decorrelated(base: 10, cap: 200), Err("busy"), quiet: true);
+        assert!(res.is_err());
+        check(recording.delays_ms());
+        drop(recording);
+
+        let recording = retry::recorder::Recording::start();
+        let res: Result<(), &str> = retry_async!(
+            20,
+            backoff: decorrelated(base: 10, cap: 200),
+            async { Err("busy") },
+            quiet: true
+        );
+        assert!(res.is_err());
+        assert_eq!(recording.delays_ms().len(), 19);
+        check(recording.delays_ms());
+    }
+
+    // Test delay_fn computing each delay from the failed attempt's number.
+    #[tokio::test]
+    async fn test_retry_delay_fn() {
+        let recording = retry::recorder::Recording::start();
+        let res: Result<(), &str> =
+            with_retry!(5, delay_fn: |attempt| 100 * attempt as u64, Err("busy"), quiet: true);
+        assert!(res.is_err());
+        assert_eq!(recording.delays_ms(), vec![100, 200, 300, 400]);
+        drop(recording);
+
+        let recording = retry::recorder::Recording::start();
+        let mut calls = 0;
+        let res: Result<(), &str> = retry_async!(5, delay_fn: |attempt| {
+            calls += 1;
+            100 * attempt as u64
+        }, async { Err("busy") }, quiet: true);
+        assert!(res.is_err());
+        assert_eq!(calls, 4);
+        assert_eq!(recording.delays_ms(), vec![100, 200, 300, 400]);
+        drop(recording);
+
+        let start = std::time::Instant::now();
+        let res: Result<(), &str> = retry_async!(
+            5,
+            delay_fn: |attempt| 100 * attempt as u64,
+            async { Err("busy") },
+            max_elapsed_ms: 250,
+            quiet: true
+        );
+        assert!(res.is_err());
+        assert!(start.elapsed() < Duration::from_millis(250));
+    }
+
+    // Test retry_until! giving up at the deadline rather than sleeping past it.
+    #[tokio::test]
+    async fn test_retry_until() {
+        let attempts = AtomicUsize::new(0);
+        let start = std::time::Instant::now();
+        let deadline = start + Duration::from_millis(250);
+        let res: Result<(), &str> = retry_until!(deadline, 100, {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err("refused")
+        }, quiet: true);
+        assert_eq!(res, Err("refused"));
+        assert!((2..=3).contains(&attempts.into_inner()));
+        assert!(start.elapsed() < Duration::from_millis(250));
+
+        let attempts = AtomicUsize::new(0);
+        let start = std::time::Instant::now();
+        let deadline = start + Duration::from_millis(250);
+        let res: Result<usize, &str> = retry_until_async!(deadline, 100, || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Err("refused"),
+                n => Ok(n),
+            }
+        });
+        assert_eq!(res, Ok(1));
+
+        let res: Result<(), &str> =
+            retry_until_async!(std::time::Instant::now(), 100, async { Err("late") });
+        assert_eq!(res, Err("late"));
+    }
+
+    // Test all_errors collecting every attempt's error in order, without requiring Clone.
+    #[test]
+    fn test_with_retry_all_errors() {
+        #[derive(Debug, PartialEq)]
+        struct Failure(usize);
+
+        let attempts = AtomicUsize::new(0);
+        let res: Result<(), RetryErrors<Failure>> = with_retry!(all_errors, 3, 1, {
+            Err(Failure(attempts.fetch_add(1, Ordering::SeqCst)))
+        });
+        let errors = res.unwrap_err();
+        assert_eq!(errors.0, vec![Failure(0), Failure(1), Failure(2)]);
+        assert_eq!(errors.last(), Some(&Failure(2)));
+
+        let attempts = AtomicUsize::new(0);
+        let res: Result<(), RetryErrors<String>> = with_retry!(all_errors, delays: [1, 1, 1], {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Err("dns failure".to_string()),
+                _ => Err("permanent".to_string()),
+            }
+        }, if: |err| err != "permanent");
+        let errors = res.unwrap_err();
+        assert_eq!(errors.0.len(), attempts.into_inner());
+        assert_eq!(
+            errors.to_string(),
+            "all 2 attempts failed: 1: dns failure; 2: permanent"
+        );
+
+        let res: Result<u32, RetryErrors<&str>> = with_retry!(all_errors, 3, 1, Ok(7));
+        assert_eq!(res, Ok(7));
+    }
+
+    // Test forever mode not giving up before the 50th attempt succeeds.
+    #[tokio::test]
+    async fn test_retry_forever() {
+        let recording = retry::recorder::Recording::start();
+        let attempts = AtomicUsize::new(0);
+        let res: Result<usize, &str> = with_retry!(forever, backoff: 10, max: 1000, {
+            match attempts.fetch_add(1, Ordering::SeqCst) + 1 {
+                n if n < 50 => Err("connection refused"),
+                n => Ok(n),
+            }
+        }, quiet: true);
+        assert_eq!(res, Ok(50));
+        let delays = recording.delays_ms();
+        assert_eq!(delays.len(), 49);
+        assert_eq!(&delays[..8], &[10, 20, 40, 80, 160, 320, 640, 1000]);
+        assert_eq!(delays[48], 1000);
+        drop(recording);
+
+        let recording = retry::recorder::Recording::start();
+        let attempts = AtomicUsize::new(0);
+        let res: Result<usize, &str> = retry_async!(forever, 5, async {
+            match attempts.fetch_add(1, Ordering::SeqCst) + 1 {
+                n if n < 50 => Err("connection refused"),
+                n => Ok(n),
+            }
+        }, quiet: true);
+        assert_eq!(res, Ok(50));
+        assert_eq!(recording.delays_ms(), vec![5; 49]);
+    }
+
+    // Test the counted variants reporting the attempt that succeeded.
+    #[tokio::test]
+    async fn test_retry_counted() {
+        let res: Result<(&str, u32), &str> = with_retry_counted!(3, 1, Ok("first"));
+        assert_eq!(res, Ok(("first", 1)));
+
+        let attempts = AtomicUsize::new(0);
+        let res = with_retry_counted!(3, 1, {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                n if n < 2 => Err(n),
+                n => Ok(n),
+            }
+        }, quiet: true);
+        assert_eq!(res, Ok((2, 3)));
+
+        let attempts = AtomicUsize::new(0);
+        let res = retry_async_counted!(3, 1, || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                n if n < 2 => Err(n),
+                n => Ok(n),
+            }
+        });
+        assert_eq!(res, Ok((2, 3)));
+
+        let res: Result<((), u32), &str> =
+            retry_async_counted!(3, 1, async { Err("down") }, quiet: true);
+        assert_eq!(res, Err("down"));
+    }
+
+    // Test with_retry_catching! retrying attempts that panic, with &str and String payloads.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_with_retry_catching() {
+        let attempts = AtomicUsize::new(0);
+        let res: Result<usize, RetryError<&str>> = with_retry_catching!(3, 1, {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => panic!("malformed input"),
+                1 => panic!("malformed input at {}", 7),
+                n => Ok(n),
+            }
+        }, quiet: true);
+        assert_eq!(res, Ok(2));
+
+        let (res, capture) = test_support::capture(|| {
+            let res: Result<(), RetryError<&str>> =
+                with_retry_catching!(2, 1, panic!("malformed input"));
+            res
+        });
+        assert_eq!(
+            res,
+            Err(RetryError::Panicked("malformed input".to_string()))
+        );
+        assert_eq!(
+            capture
+                .events()
+                .iter()
+                .filter(|event| event.message().starts_with("Attempt panicked"))
+                .count(),
+            2
+        );
+
+        let res: Result<(), RetryError<&str>> =
+            with_retry_catching!(2, 1, Err("refused"), quiet: true);
+        assert_eq!(res, Err(RetryError::Exhausted("refused")));
+    }
+
+    // Test max_elapsed_ms not sleeping past the cap in retry_async!.
+    #[tokio::test]
+    async fn test_retry_async_max_elapsed() {
+        let start = std::time::Instant::now();
+        let res: Result<(), &str> =
+            retry_async!(5, 500, async { Err("down") }, max_elapsed_ms: 200);
+        assert!(res.is_err());
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    // Test on_retry being called for every failed attempt but the last.
+    #[tokio::test]
+    async fn test_retry_on_retry() {
+        let mut calls = Vec::new();
+        let res: Result<(), String> = with_retry!(4, 1, Err("refused".to_string()), on_retry: |attempt, err: &String| {
+            calls.push((attempt, err.clone()));
+        });
+        assert!(res.is_err());
+        assert_eq!(calls.len(), 3);
+        assert_eq!(
+            calls
+                .iter()
+                .map(|(attempt, _)| *attempt)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+
+        let mut calls = Vec::new();
+        let attempts = AtomicUsize::new(0);
+        let res = retry_async!(5, 1, async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                n if n < 2 => Err(n),
+                n => Ok(n),
+            }
+        }, on_retry: |attempt, err| calls.push((attempt, *err)));
+        assert_eq!(res, Ok(2));
+        assert_eq!(calls, vec![(1, 0), (2, 1)]);
+    }
+
+    // Test retry_async! with a closure building a fresh future from a captured String.
+    #[tokio::test]
+    async fn test_retry_async_closure() {
+        async fn send(body: String, attempt: usize) -> Result<String, usize> {
+            if attempt < 2 { Err(attempt) } else { Ok(body) }
+        }
+        let body = String::from("payload");
+        let attempts = AtomicUsize::new(0);
+        let res = retry_async!(3, 1, move || {
+            send(body.clone(), attempts.fetch_add(1, Ordering::SeqCst))
+        });
+        assert_eq!(res, Ok("payload".to_string()));
+
+        let attempts = AtomicUsize::new(0);
+        let res: Result<(), usize> = retry_async!(3, 1, || async {
+            Err(attempts.fetch_add(1, Ordering::SeqCst))
+        }, if: |n| *n < 5);
+        assert_eq!(res, Err(2));
+    }
+
+    // Test cancelling retry_async! while it sleeps between attempts.
+    #[cfg(feature = "cancellation")]
+    #[tokio::test]
+    async fn test_retry_async_cancel() {
+        let token = tokio_util::sync::CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            canceller.cancel();
+        });
+        let start = std::time::Instant::now();
+        let res: Result<(), RetryError<&str>> =
+            retry_async!(5, 1000, async { Err("down") }, cancel: token);
+        assert_eq!(res, Err(RetryError::Cancelled));
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        let token = tokio_util::sync::CancellationToken::new();
+        let res: Result<(), RetryError<&str>> =
+            retry_async!(2, 1, async { Err("down") }, cancel: token);
+        assert_eq!(res, Err(RetryError::Exhausted("down")));
+
+        let token = tokio_util::sync::CancellationToken::new();
+        let res: Result<(), RetryError<&str>> = retry_async!(
+            2, 1, std::future::pending(), cancel: token, attempt_timeout_ms: 10
+        );
+        assert_eq!(res, Err(RetryError::TimedOut(Duration::from_millis(10))));
+    }
+
+    // Test attempt_timeout_ms retrying a hung first attempt and reporting a hung last attempt.
+    #[tokio::test]
+    async fn test_retry_async_attempt_timeout() {
+        let recording = retry::recorder::Recording::start();
+        let attempts = AtomicUsize::new(0);
+        let res: Result<usize, RetryError<&str>> = retry_async!(3, 10, || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => std::future::pending().await,
+                n => Ok(n),
+            }
+        }, attempt_timeout_ms: 20);
+        assert_eq!(res, Ok(1));
+        assert_eq!(recording.delays_ms(), vec![10]);
+
+        let attempts = AtomicUsize::new(0);
+        let res: Result<(), RetryError<&str>> = retry_async!(2, 10, || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Err("refused"),
+                _ => std::future::pending().await,
+            }
+        }, attempt_timeout_ms: 20);
+        assert_eq!(res, Err(RetryError::TimedOut(Duration::from_millis(20))));
+
+        let res: Result<(), RetryError<&str>> = retry_async!(2, backoff: 10, max: 100, async { Err("refused") }, attempt_timeout_ms: 20);
+        assert_eq!(res, Err(RetryError::Exhausted("refused")));
+    }
+
+    // Runs a future to completion on the current thread, without any async runtime.
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        struct ThreadWaker(std::thread::Thread);
+
+        impl std::task::Wake for ThreadWaker {
+            fn wake(self: std::sync::Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = std::sync::Arc::new(ThreadWaker(std::thread::current())).into();
+        let mut cx = std::task::Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                std::task::Poll::Ready(output) => return output,
+                std::task::Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    // Test retry_async! sleeping with sleep_with outside of a tokio runtime.
+    #[test]
+    fn test_retry_async_sleep_with() {
+        let mut slept = Vec::new();
+        let attempts = AtomicUsize::new(0);
+        let res = block_on(async {
+            retry_async!(4, backoff: 10, max: 100, async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    n if n < 2 => Err(n),
+                    n => Ok(n),
+                }
+            }, sleep_with: |delay| {
+                slept.push(delay);
+                std::future::ready(())
+            })
+        });
+        assert_eq!(res, Ok(2));
+        assert_eq!(
+            slept,
+            vec![Duration::from_millis(10), Duration::from_millis(20)]
+        );
+    }
+
+    // Test hedge_async! taking the fast hedged attempt over the slow first one.
+    #[tokio::test]
+    async fn test_hedge_async() {
+        let attempts = AtomicUsize::new(0);
+        let start = std::time::Instant::now();
+        let res: Result<&str, &str> = hedge_async!(after_ms: 50, max_parallel: 2, || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => {
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                    Ok("slow")
+                }
+                _ => {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    Ok("fast")
+                }
+            }
+        });
+        let elapsed = start.elapsed();
+        assert_eq!(res, Ok("fast"));
+        assert_eq!(attempts.into_inner(), 2);
+        assert!(elapsed >= Duration::from_millis(70), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(200), "{elapsed:?}");
+
+        let attempts = AtomicUsize::new(0);
+        let res: Result<(), usize> = hedge_async!(after_ms: 1000, max_parallel: 3, || async {
+            Err(attempts.fetch_add(1, Ordering::SeqCst))
+        });
+        assert_eq!(res, Err(2));
+    }
+
+    // Test a circuit breaker opening after the threshold, failing fast, and recovering.
+    #[test]
+    fn test_circuit_breaker() {
+        use crate::{CircuitBreaker, CircuitError, CircuitState};
+
+        let breaker = std::sync::Arc::new(CircuitBreaker::new(3, Duration::from_millis(50)));
+        let calls = AtomicUsize::new(0);
+        let failing = || -> Result<(), &str> {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err("down")
+        };
+        assert_eq!(circuit_breaker!(breaker, Ok::<_, &str>(1)), Ok(1));
+        for _ in 0..3 {
+            assert_eq!(
+                circuit_breaker!(breaker, failing()),
+                Err(CircuitError::Failed("down"))
+            );
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(
+            circuit_breaker!(breaker, failing()),
+            Err(CircuitError::Open)
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // A failed probe reopens the breaker, a successful one closes it.
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert_eq!(
+            circuit_breaker!(breaker, failing()),
+            Err(CircuitError::Failed("down"))
+        );
+        assert_eq!(breaker.state(), CircuitState::Open);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(circuit_breaker!(breaker, Ok::<_, &str>(2)), Ok(2));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        // Successes reset the count of consecutive failures.
+        for _ in 0..2 {
+            assert!(circuit_breaker!(breaker, failing()).is_err());
+        }
+        assert_eq!(circuit_breaker!(breaker, Ok::<_, &str>(3)), Ok(3));
+        assert!(circuit_breaker!(breaker, failing()).is_err());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    // Test a late success from a call admitted before the breaker opened leaving it open.
+    #[test]
+    fn test_circuit_breaker_stale_success() {
+        use crate::{CircuitBreaker, CircuitError, CircuitState};
+
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+        let slow_call = breaker.acquire().unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                circuit_breaker!(breaker, Err::<(), _>("down")),
+                Err(CircuitError::Failed("down"))
+            );
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+        slow_call.success();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(
+            circuit_breaker!(breaker, Ok::<_, &str>(1)),
+            Err(CircuitError::Open)
+        );
+    }
+
+    // Test only one half-open probe running at a time, and a cancelled probe reopening.
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_circuit_breaker_async() {
+        use crate::{CircuitBreaker, CircuitError, CircuitState};
+
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(20)).named("replica");
+        let (res, capture) =
+            test_support::capture(|| circuit_breaker!(breaker, Err::<(), _>("down")));
+        assert_eq!(res, Err(CircuitError::Failed("down")));
+        assert!(capture.events()[0].message().contains("replica opened"));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let probe = async {
+            circuit_breaker_async!(breaker, async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok::<_, &str>("probe")
+            })
+        };
+        let concurrent = async {
+            tokio::task::yield_now().await;
+            circuit_breaker_async!(breaker, async { Ok::<_, &str>("concurrent") })
+        };
+        let (probe, concurrent) = tokio::join!(probe, concurrent);
+        assert_eq!(probe, Ok("probe"));
+        assert_eq!(concurrent, Err(CircuitError::Open));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        assert!(circuit_breaker_async!(breaker, async { Err::<(), _>("down") }).is_err());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let cancelled = tokio::time::timeout(Duration::from_millis(5), async {
+            circuit_breaker_async!(breaker, std::future::pending::<Result<(), &str>>())
+        })
+        .await;
+        assert!(cancelled.is_err());
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    // Serves the canned HTTP responses in order, one per connection, and returns the base URL.
+    #[cfg(feature = "reqwest")]
+    async fn serve_http(responses: Vec<&'static str>) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    response
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    // Test retry_http! waiting for Retry-After on 429 and 503 until the request succeeds.
+    #[cfg(feature = "reqwest")]
+    #[tokio::test]
+    async fn test_retry_http() {
+        let url = serve_http(vec![
+            "429 Too Many Requests\r\nretry-after: 2",
+            "503 Service Unavailable",
+            "200 OK",
+        ])
+        .await;
+        let client = reqwest::Client::new();
+        let recording = retry::recorder::Recording::start();
+        let response = retry_http!(5, || client.get(&url).send()).unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(recording.delays_ms(), vec![2000, 200]);
+    }
+
+    // Test retry_http! returning other 4xx responses and the last retryable one as is.
+    #[cfg(feature = "reqwest")]
+    #[tokio::test]
+    async fn test_retry_http_permanent() {
+        let url = serve_http(vec!["404 Not Found"]).await;
+        let client = reqwest::Client::new();
+        let recording = retry::recorder::Recording::start();
+        let response = retry_http!(5, || client.get(&url).send()).unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        assert!(recording.delays_ms().is_empty());
+
+        let url = serve_http(vec!["429 Too Many Requests"; 2]).await;
+        let response = retry_http!(2, backoff: 10, max: 100, || client.get(&url).send()).unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(recording.delays_ms(), vec![10]);
+    }
+
+    // Test retry_http! honoring long Retry-After delays in full, and giving up on those past
+    // max_retry_after_ms instead of retrying early.
+    #[cfg(feature = "reqwest")]
+    #[tokio::test]
+    async fn test_retry_http_long_retry_after() {
+        let url = serve_http(vec![
+            "429 Too Many Requests\r\nretry-after: 86400",
+            "200 OK",
+        ])
+        .await;
+        let client = reqwest::Client::new();
+        let recording = retry::recorder::Recording::start();
+        let response = retry_http!(5, backoff: 10, max: 100, || client.get(&url).send()).unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(recording.delays_ms(), vec![86_400_000]);
+
+        let url = serve_http(vec![
+            "429 Too Many Requests\r\nretry-after: 30",
+            "503 Service Unavailable\r\nretry-after: Fri, 31 Dec 9999 23:59:59 GMT",
+            "200 OK",
+        ])
+        .await;
+        let response =
+            retry_http!(5, max_retry_after_ms: 60_000, || client.get(&url).send()).unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(recording.delays_ms(), vec![86_400_000, 30_000]);
+    }
+
+    // Test parsing Retry-After as seconds and as an HTTP-date.
+    #[cfg(feature = "reqwest")]
+    #[test]
+    fn test_retry_after() {
+        let now = std::time::UNIX_EPOCH + Duration::from_secs(784_111_767);
+        let retry_after = |value| retry::retry_after(value, now);
+        assert_eq!(retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            retry_after("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            retry_after("Sun, 06 Nov 1994 08:00:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after("soon"), None);
+    }
+
+    // A database error reporting the given SQLSTATE.
+    #[cfg(feature = "sqlx")]
+    #[derive(Debug)]
+    struct SqlState(&'static str);
+
+    #[cfg(feature = "sqlx")]
+    impl std::fmt::Display for SqlState {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "SQLSTATE {}", self.0)
+        }
+    }
+
+    #[cfg(feature = "sqlx")]
+    impl std::error::Error for SqlState {}
+
+    #[cfg(feature = "sqlx")]
+    impl sqlx::error::DatabaseError for SqlState {
+        fn message(&self) -> &str {
+            self.0
+        }
+
+        fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+            Some(self.0.into())
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> sqlx::error::ErrorKind {
+            sqlx::error::ErrorKind::Other
+        }
+    }
+
+    // Test which sqlx errors is_transient_db_error considers retryable.
+    #[cfg(feature = "sqlx")]
+    #[test]
+    fn test_is_transient_db_error() {
+        use is_transient_db_error;
+        use std::io::{Error, ErrorKind};
+
+        assert!(is_transient_db_error(&sqlx::Error::PoolTimedOut));
+        assert!(is_transient_db_error(
+            &Error::from(ErrorKind::ConnectionReset).into()
+        ));
+        assert!(is_transient_db_error(&sqlx::Error::Database(Box::new(
+            SqlState("40001")
+        ))));
+        assert!(is_transient_db_error(&sqlx::Error::Database(Box::new(
+            SqlState("40P01")
+        ))));
+        assert!(!is_transient_db_error(&sqlx::Error::Database(Box::new(
+            SqlState("23505")
+        ))));
+        assert!(!is_transient_db_error(
+            &Error::from(ErrorKind::PermissionDenied).into()
+        ));
+        assert!(!is_transient_db_error(&sqlx::Error::PoolClosed));
+        assert!(!is_transient_db_error(&sqlx::Error::RowNotFound));
+    }
+
+    // Test retry_db! retrying a deadlock with backoff and returning a unique violation at once.
+    #[cfg(feature = "sqlx")]
+    #[tokio::test]
+    async fn test_retry_db() {
+        let recording = retry::recorder::Recording::start();
+        let attempts = AtomicUsize::new(0);
+        let res = retry_db!(4, 10, || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                n if n < 2 => Err(sqlx::Error::Database(Box::new(SqlState("40P01")))),
+                n => Ok(n),
+            }
+        });
+        assert_eq!(res.unwrap(), 2);
+        assert_eq!(recording.delays_ms(), vec![10, 20]);
+
+        let attempts = AtomicUsize::new(0);
+        let res: Result<(), sqlx::Error> = retry_db!(4, 10, async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(sqlx::Error::Database(Box::new(SqlState("23505"))))
+        });
+        assert!(res.is_err());
+        assert_eq!(attempts.into_inner(), 1);
+    }
+
+    // Test a backoff crate policy driving the delays until its max_elapsed_time runs out.
+    #[cfg(feature = "backoff")]
+    #[tokio::test]
+    async fn test_retry_async_backoff_policy() {
+        let policy = backoff::ExponentialBackoff {
+            initial_interval: Duration::from_millis(10),
+            randomization_factor: 0.0,
+            multiplier: 2.0,
+            max_elapsed_time: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let attempts = AtomicUsize::new(0);
+        let start = std::time::Instant::now();
+        let res: Result<(), usize> = retry_async!(policy: policy.clone(), async {
+            Err(attempts.fetch_add(1, Ordering::SeqCst))
+        });
+        assert!(res.is_err());
+        assert!(attempts.load(Ordering::SeqCst) >= 3);
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        let attempts = AtomicUsize::new(0);
+        let res = retry_async!(policy: policy, || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                n if n < 2 => Err(n),
+                n => Ok(n),
+            }
+        }, quiet: true);
+        assert_eq!(res, Ok(2));
+    }
+
+    // Retry loops reported to the test retry observer, which is installed once for the whole process.
+    static RETRY_LOOPS: std::sync::Mutex<Vec<(String, u32, bool)>> =
+        std::sync::Mutex::new(Vec::new());
+
+    fn recording_observer(label: &str, attempts: u32, succeeded: bool) {
+        RETRY_LOOPS
+            .lock()
+            .unwrap()
+            .push((label.to_string(), attempts, succeeded));
+    }
+
+    // Test the retry observer being told the attempts and outcome of labelled retry loops.
+    #[tokio::test]
+    async fn test_retry_observer() {
+        let _ = set_retry_observer(recording_observer);
+        let attempts = AtomicUsize::new(0);
+        let res = with_retry!(3, 1, {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Err("refused"),
+                n => Ok(n),
+            }
+        }, label: "fetch_invoice", quiet: true);
+        assert_eq!(res, Ok(1));
+        let res: Result<(), &str> =
+            retry_async!(3, 1, async { Err("refused") }, label: "send_invoice", quiet: true);
+        assert!(res.is_err());
+        let res: Result<(), &str> = retry_async!(3, 1, async { Err("permanent") }, if: |_| false);
+        assert!(res.is_err());
+
+        let loops = RETRY_LOOPS.lock().unwrap().clone();
+        let labelled: Vec<_> = loops
+            .iter()
+            .filter(|(label, _, _)| label.ends_with("_invoice"))
+            .cloned()
+            .collect();
+        assert_eq!(
+            labelled,
+            vec![
+                ("fetch_invoice".to_string(), 2, true),
+                ("send_invoice".to_string(), 3, false),
+            ]
+        );
+        assert!(loops.iter().any(|(label, attempts, succeeded)| {
+            label.starts_with(concat!(file!(), ":")) && *attempts == 1 && !succeeded
+        }));
+    }
+
+    // Test one RetryPolicy constant shared by a sync and an async call site.
+    #[tokio::test]
+    async fn test_retry_policy() {
+        const POLICY: RetryPolicy = crate::RetryPolicy::new()
+            .attempts(4)
+            .exponential(10)
+            .factor(3)
+            .max_delay(50);
+
+        let recording = retry::recorder::Recording::start();
+        let res: Result<(), &str> = with_retry!(POLICY, Err("sync"));
+        assert_eq!(res, Err("sync"));
+        assert_eq!(recording.delays_ms(), vec![10, 30, 50]);
+        drop(recording);
+
+        let recording = retry::recorder::Recording::start();
+        let attempts = AtomicUsize::new(0);
+        let res: Result<(), usize> = retry_async!(POLICY, || async {
+            Err(attempts.fetch_add(1, Ordering::SeqCst))
+        }, if: |n| *n < 1);
+        assert_eq!(res, Err(1));
+        assert_eq!(recording.delays_ms(), vec![10]);
+        assert_eq!(crate::RetryPolicy::default().max_attempts(), 3);
+    }
+
+    // Test the warn event per retried attempt and the error event on exhaustion.
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_retry_logging() {
+        let (res, capture) = test_support::capture(|| with_retry!(3, 1, Err::<(), _>("refused")));
+        assert!(res.is_err());
+        let events = capture.events();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].level, tracing::Level::WARN);
+        assert_eq!(events[0].field("attempt"), Some("1"));
+        assert_eq!(events[0].field("max_attempts"), Some("3"));
+        assert_eq!(events[0].field("delay_ms"), Some("1"));
+        assert_eq!(events[0].field("error"), Some("\"refused\""));
+        assert_eq!(events[1].field("attempt"), Some("2"));
+        assert_eq!(events[2].level, tracing::Level::ERROR);
+        assert_eq!(events[2].field("attempts"), Some("3"));
+
+        let (capture, _guard) = test_support::install();
+        struct Opaque;
+        let res: Result<(), Opaque> = retry_async!(2, 1, async { Err(Opaque) });
+        assert!(res.is_err());
+        let res: Result<(), &str> = retry_async!(3, 1, async { Err("down") }, quiet: true);
+        assert!(res.is_err());
+        let events = capture.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].field("error"), Some("<error>"));
+    }
+
+    // Test an explicit delay schedule deciding the delays and number of attempts.
+    #[tokio::test]
+    async fn test_retry_delays() {
+        let recording = retry::recorder::Recording::start();
+        let attempts = AtomicUsize::new(0);
+        let res: Result<(), usize> = with_retry!(delays: [0, 100, 500, 5000], {
+            Err(attempts.fetch_add(1, Ordering::SeqCst))
+        });
+        assert_eq!(res, Err(4));
+        assert_eq!(recording.delays_ms(), vec![0, 100, 500, 5000]);
+        drop(recording);
+
+        let recording = retry::recorder::Recording::start();
+        let schedule = [10, 20];
+        let res: Result<(), &str> = retry_async!(delays: schedule.iter().map(|ms| ms * 2), async {
+            Err("down")
+        }, quiet: true);
+        assert!(res.is_err());
+        assert_eq!(recording.delays_ms(), vec![20, 40]);
+    }
+
+    // Test span_wrap! macro.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_span_wrap() {
+        let value = span_wrap!("test_span", { 123 });
+        assert_eq!(value, 123);
+    }
+
+    // Test span_wrap_async! parenting events inside the future across awaits.
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_span_wrap_async() {
+        let (capture, _guard) = test_support::install();
+        let value = span_wrap_async!("outer_async", async {
+            tracing::info!("before");
+            tokio::task::yield_now().await;
+            tracing::info!("after");
+            span_wrap_async!(level: debug, "inner_async", async {
+                tracing::info!("nested");
+                42
+            })
+        });
+        tracing::info!("outside");
+        assert_eq!(value, 42);
+
+        let spans: Vec<_> = capture
+            .events()
+            .iter()
+            .map(|event| (event.message().to_string(), event.spans.clone()))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                ("before".to_string(), vec!["outer_async".to_string()]),
+                ("after".to_string(), vec!["outer_async".to_string()]),
+                (
+                    "nested".to_string(),
+                    vec!["outer_async".to_string(), "inner_async".to_string()]
+                ),
+                ("outside".to_string(), vec![]),
+            ]
+        );
+        assert_eq!(capture.spans()[1].level, tracing::Level::DEBUG);
+    }
+
+    // Test call_with_trace_async! keeping events inside the awaited function in the span.
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_call_with_trace_async() {
+        async fn fetch(id: u32) -> u32 {
+            tokio::task::yield_now().await;
+            tracing::info!(id, "fetched");
+            id * 2
+        }
+
+        struct Client;
+
+        impl Client {
+            async fn fetch(&self, id: u32) -> u32 {
+                fetch(id).await
+            }
+        }
+
+        let (capture, _guard) = test_support::install();
+        assert_eq!(call_with_trace_async!("fetch_fn", fetch, 1), 2);
+        let client = Client;
+        assert_eq!(
+            call_with_trace_async!(level: debug, "fetch_method", || client.fetch(2)),
+            4
+        );
+
+        let events = capture.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].spans, vec!["fetch_fn".to_string()]);
+        assert_eq!(events[1].spans, vec!["fetch_method".to_string()]);
+        assert_eq!(capture.spans()[1].level, tracing::Level::DEBUG);
+    }
+
+    // Test trace_result! logging each branch and passing the Result through.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_trace_result() {
+        fn create_invoice(amount: u32) -> Result<u32, String> {
+            if amount == 0 {
+                Err("empty invoice".to_string())
+            } else {
+                Ok(amount)
+            }
+        }
+
+        let (results, capture) = test_support::capture(|| {
+            (
+                trace_result!("create_invoice", create_invoice(5)),
+                trace_result!("create_invoice", create_invoice(0)),
+                trace_result!("create_invoice", create_invoice(7), log_ok: true),
+            )
+        });
+        assert_eq!(results.0, Ok(5));
+        assert_eq!(results.1, Err("empty invoice".to_string()));
+        assert_eq!(results.2, Ok(7));
+
+        let events = capture.events();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].level, tracing::Level::INFO);
+        assert_eq!(events[0].message(), "succeeded");
+        assert_eq!(events[0].field("op"), Some("create_invoice"));
+        assert_eq!(events[0].field("value"), None);
+        assert_eq!(events[1].level, tracing::Level::ERROR);
+        assert_eq!(events[1].message(), "failed");
+        assert_eq!(events[1].field("op"), Some("create_invoice"));
+        assert_eq!(events[1].field("error"), Some("\"empty invoice\""));
+        assert_eq!(events[2].field("value"), Some("7"));
+    }
+
+    // Test spawn_traced! keeping spawned tasks in the span they were spawned from.
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_spawn_traced() {
+        let (capture, _guard) = test_support::install();
+        let handle = span_wrap!("request", {
+            spawn_traced!(async {
+                tokio::task::yield_now().await;
+                tracing::info!("in task");
+                1
+            })
+        });
+        assert_eq!(handle.await.unwrap(), 1);
+        spawn_traced!(span: tracing::info_span!("explicit"), async {
+            tracing::info!("in explicit task");
+        })
+        .await
+        .unwrap();
+        tokio::spawn(async {
+            tracing::info!("untraced");
+        })
+        .await
+        .unwrap();
+
+        let events = capture.events();
+        assert_eq!(events[0].spans, vec!["request".to_string()]);
+        assert_eq!(events[1].spans, vec!["explicit".to_string()]);
+        assert!(events[2].spans.is_empty());
+    }
+
+    // Test zinfo!, zwarn! and zerror! using the zirv target and forwarding fields and arguments.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_zirv_events() {
+        let ((), capture) = test_support::capture(|| {
+            let tenant = "acme";
+            zinfo!("started");
+            zwarn!(tenant, attempts = 3, "retrying {} in {}ms", "job", 50);
+            zerror!(error = ?"refused", "failed");
+        });
+
+        let events = capture.events();
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|event| event.target == "zirv"));
+        assert!(
+            events
+                .iter()
+                .all(|event| event.field("file") == Some(file!()))
+        );
+        assert!(events.iter().all(|event| event.field("line").is_some()));
+        assert_eq!(events[0].level, tracing::Level::INFO);
+        assert_eq!(events[0].message(), "started");
+        assert_eq!(events[1].level, tracing::Level::WARN);
+        assert_eq!(events[1].message(), "retrying job in 50ms");
+        assert_eq!(events[1].field("tenant"), Some("acme"));
+        assert_eq!(events[1].field("attempts"), Some("3"));
+        assert_eq!(events[2].level, tracing::Level::ERROR);
+        assert_eq!(events[2].field("error"), Some("\"refused\""));
+    }
+
+    // Test log_context! fields reaching events emitted inside nested contexts.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_log_context() {
+        fn process(batch: u32) -> Result<u32, String> {
+            Err(format!("batch {} failed", batch))
+        }
+
+        let tenant_id = String::from("acme");
+        let (value, capture) = test_support::capture(|| {
+            log_context!(tenant_id = %tenant_id, {
+                log_context!(batch = 7, job = ?"import", {
+                    log_error!(process(7), 0)
+                })
+            })
+        });
+        assert_eq!(value, 0);
+
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].spans, vec!["log_context", "log_context"]);
+        assert_eq!(
+            events[0].context.get("tenant_id").map(String::as_str),
+            Some("acme")
+        );
+        assert_eq!(
+            events[0].context.get("batch").map(String::as_str),
+            Some("7")
+        );
+        assert_eq!(
+            events[0].context.get("job").map(String::as_str),
+            Some("\"import\"")
+        );
+    }
+
+    // Test log_context! instrumenting async blocks.
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_log_context_async() {
+        let (capture, _guard) = test_support::install();
+        let value = log_context!(tenant_id = "acme", async {
+            tokio::task::yield_now().await;
+            log_context!(batch = 1, async move {
+                tracing::info!("processed");
+                2
+            })
+        });
+        assert_eq!(value, 2);
+
+        let events = capture.events();
+        assert_eq!(
+            events[0].context.get("tenant_id").map(String::as_str),
+            Some("acme")
+        );
+        assert_eq!(
+            events[0].context.get("batch").map(String::as_str),
+            Some("1")
+        );
+    }
+
+    // Test request_id! generating unique ids and recording them on the current span.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_request_id() {
+        let ids: std::collections::HashSet<String> = (0..100).map(|_| request_id!()).collect();
+        assert_eq!(ids.len(), 100);
+
+        let ((generated, propagated), capture) = test_support::capture(|| {
+            let generated = span_wrap_request(|| request_id!());
+            let header = "abc-123";
+            let propagated = span_wrap_request(|| request_id!(header));
+            (generated, propagated)
+        });
+        assert_eq!(propagated, "abc-123");
+        let spans = capture.spans();
+        assert_eq!(spans[0].field("request_id"), Some(generated.as_str()));
+        assert_eq!(spans[1].field("request_id"), Some("abc-123"));
+
+        fn span_wrap_request(f: impl FnOnce() -> String) -> String {
+            let span = tracing::info_span!("request", request_id = tracing::field::Empty);
+            let _enter = span.enter();
+            f()
+        }
+    }
+
+    // Test record_field! recording values and reporting fields it can't record.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_record_field() {
+        let ((), capture) = test_support::capture(|| {
+            let span = tracing::info_span!(
+                "query",
+                rows_returned = tracing::field::Empty,
+                table = tracing::field::Empty
+            );
+            let _enter = span.enter();
+            let rows = [1, 2, 3];
+            record_field!("rows_returned", rows.len());
+            record_field!("table" => "users", "cache_hit" => true);
+        });
+        let spans = capture.spans();
+        assert_eq!(spans[0].field("rows_returned"), Some("3"));
+        assert_eq!(spans[0].field("table"), Some("users"));
+        assert_eq!(spans[0].field("cache_hit"), None);
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, tracing::Level::DEBUG);
+        assert_eq!(events[0].field("field"), Some("cache_hit"));
+        assert_eq!(events[0].field("span"), Some("query"));
+
+        let ((), capture) = test_support::capture(|| record_field!("rows_returned", 3));
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].message(),
+            "Not recording field rows_returned: there is no current span"
+        );
+    }
+
+    // Test call_with_trace!(result, ...) recording the outcome on the span.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_call_with_trace_result() {
+        fn parse(input: &str) -> Result<i32, std::num::ParseIntError> {
+            input.parse()
+        }
+
+        let (results, capture) = test_support::capture(|| {
+            (
+                call_with_trace!(result, "parse_ok", parse, "42"),
+                call_with_trace!(result, level: warn, "parse_err", parse, "x"),
+            )
+        });
+        assert_eq!(results.0, Ok(42));
+        assert!(results.1.is_err());
+
+        let spans = capture.spans();
+        assert_eq!(spans[0].field("error"), Some("false"));
+        assert_eq!(spans[0].field("error.message"), None);
+        assert_eq!(spans[1].level, tracing::Level::WARN);
+        assert_eq!(spans[1].field("error"), Some("true"));
+        assert_eq!(
+            spans[1].field("error.message"),
+            Some("ParseIntError { kind: InvalidDigit }")
+        );
+
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, tracing::Level::ERROR);
+        assert_eq!(events[0].spans, vec!["parse_err".to_string()]);
+        assert_eq!(
+            events[0].field("error"),
+            Some("ParseIntError { kind: InvalidDigit }")
+        );
+    }
+
+    // Test call_with_trace! recording the arguments listed with field names on the span.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_call_with_trace_args() {
+        struct Image(u32);
+
+        fn resize(image: Image, path: &str, width: u32) -> Result<u32, String> {
+            if width == 0 {
+                Err(format!("cannot resize {} to zero", path))
+            } else {
+                Ok(image.0 * width)
+            }
+        }
+
+        let calls = std::cell::Cell::new(0);
+        let next_width = || {
+            calls.set(calls.get() + 1);
+            64
+        };
+        let path = "cat.png";
+        let (results, capture) = test_support::capture(|| {
+            (
+                call_with_trace!("resize", resize, args: [Image(2), path = %path, width = next_width()]),
+                call_with_trace!(result, level: debug, "resize_err", resize, args: [Image(2), path = ?path, 0]),
+            )
+        });
+        assert_eq!(results.0, Ok(128));
+        assert!(results.1.is_err());
+        assert_eq!(calls.get(), 1);
+
+        let spans = capture.spans();
+        assert_eq!(spans[0].field("path"), Some("cat.png"));
+        assert_eq!(spans[0].field("width"), Some("64"));
+        assert_eq!(spans[0].field("error"), None);
+        assert_eq!(spans[1].level, tracing::Level::DEBUG);
+        assert_eq!(spans[1].field("path"), Some("\"cat.png\""));
+        assert_eq!(spans[1].field("width"), None);
+        assert_eq!(spans[1].field("error"), Some("true"));
+    }
+
+    // Test call_with_trace_async! recording listed arguments on the span.
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_call_with_trace_async_args() {
+        async fn fetch(id: u32, token: String) -> Result<u32, String> {
+            tokio::task::yield_now().await;
+            tracing::info!("fetching");
+            if token.is_empty() {
+                Err("no token".to_string())
+            } else {
+                Ok(id)
+            }
+        }
+
+        let (capture, _guard) = test_support::install();
+        let token = String::from("secret");
+        assert_eq!(
+            call_with_trace_async!("fetch", fetch, args: [id = 7, token]),
+            Ok(7)
+        );
+        assert!(
+            call_with_trace_async!(result, "fetch_err", fetch, args: [id = 8, String::new()])
+                .is_err()
+        );
+
+        let spans = capture.spans();
+        assert_eq!(spans[0].field("id"), Some("7"));
+        assert_eq!(spans[0].field("token"), None);
+        assert_eq!(spans[1].field("id"), Some("8"));
+        assert_eq!(spans[1].field("error"), Some("true"));
+        assert_eq!(capture.events()[0].spans, vec!["fetch".to_string()]);
+    }
+
+    // Test call_with_trace_async!(result, ...) recording the outcome on the span.
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_call_with_trace_async_result() {
+        async fn fetch(id: u32) -> Result<u32, String> {
+            tokio::task::yield_now().await;
+            if id == 0 {
+                Err("not found".to_string())
+            } else {
+                Ok(id)
+            }
+        }
+
+        let (capture, _guard) = test_support::install();
+        assert_eq!(call_with_trace_async!(result, "fetch_ok", fetch, 1), Ok(1));
+        assert_eq!(
+            call_with_trace_async!(result, "fetch_err", || fetch(0)),
+            Err("not found".to_string())
+        );
+
+        let spans = capture.spans();
+        assert_eq!(spans[0].field("error"), Some("false"));
+        assert_eq!(spans[1].field("error"), Some("true"));
+        assert_eq!(spans[1].field("error.message"), Some("\"not found\""));
+
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].spans, vec!["fetch_err".to_string()]);
+    }
+
+    // Test span_wrap!(dynamic: ...) carrying the runtime name in the otel.name field.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_span_wrap_dynamic() {
+        let tenant = "acme";
+        let (value, capture) = test_support::capture(|| {
+            span_wrap!(dynamic: format!("tenant-{}", tenant), {
+                tracing::info!("inside");
+            });
+            span_wrap!(level: debug, dynamic: tenant, { 7 })
+        });
+        assert_eq!(value, 7);
+
+        let spans = capture.spans();
+        assert_eq!(spans[0].name, "dynamic");
+        assert_eq!(spans[0].level, tracing::Level::INFO);
+        assert_eq!(spans[0].field("otel.name"), Some("tenant-acme"));
+        assert_eq!(spans[1].level, tracing::Level::DEBUG);
+        assert_eq!(spans[1].field("otel.name"), Some("acme"));
+        assert_eq!(capture.events()[0].spans, vec!["dynamic".to_string()]);
+    }
+
+    // Test span_wrap! recording the block's duration on the span and returning it on request.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_span_wrap_duration() {
+        let ((value, (tuple_value, elapsed)), capture) = test_support::capture(|| {
+            let value = span_wrap!("sleepy", {
+                std::thread::sleep(Duration::from_millis(20));
+                1
+            });
+            let timed = span_wrap!(with_duration, dynamic: "job-7", {
+                std::thread::sleep(Duration::from_millis(5));
+                2
+            });
+            (value, timed)
+        });
+        assert_eq!((value, tuple_value), (1, 2));
+        assert!(elapsed >= Duration::from_millis(5));
+
+        let spans = capture.spans();
+        let sleepy: u64 = spans[0].field("duration_ms").unwrap().parse().unwrap();
+        assert!((20..1000).contains(&sleepy));
+        assert_eq!(
+            spans[1].field("duration_ms"),
+            Some(elapsed.as_millis().to_string().as_str())
+        );
+        assert_eq!(spans[1].field("otel.name"), Some("job-7"));
+    }
+
+    // Test span_wrap!(sample: ...) creating spans for the sampled fraction of calls only.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_span_wrap_sample() {
+        fn count_spans(rate: f64) -> (u32, usize) {
+            let (sum, capture) = test_support::capture(|| {
+                (0..200)
+                    .map(|i| span_wrap!(sample: rate, level: trace, "hot_path", { i % 2 }))
+                    .sum::<u32>()
+            });
+            (sum, capture.spans().len())
+        }
+
+        assert_eq!(count_spans(0.0), (100, 0));
+        assert_eq!(count_spans(1.0), (100, 200));
+        let (sum, sampled) = count_spans(0.5);
+        assert_eq!(sum, 100);
+        assert!((20..180).contains(&sampled));
+
+        let rate = "0.25".parse::<f64>().unwrap();
+        assert_eq!(span_wrap!(sample: rate, "configured", { 3 }), 3);
+
+        // Items in the block are the same whether or not the span was sampled.
+        fn tick(rate: f64) -> usize {
+            span_wrap!(sample: rate, "counted", {
+                static CALLS: AtomicUsize = AtomicUsize::new(0);
+                CALLS.fetch_add(1, Ordering::SeqCst) + 1
+            })
+        }
+        assert_eq!(tick(0.0), 1);
+        assert_eq!(tick(1.0), 2);
+        assert_eq!(tick(0.0), 3);
+
+        let (_, capture) =
+            test_support::capture(|| span_wrap!(sample: 1.0, "timed", { tracing::info!("in") }));
+        assert!(capture.spans()[0].field("duration_ms").is_some());
+        assert_eq!(capture.events()[0].spans, ["timed"]);
+    }
+
+    // Test span_wrap!(catch, ...) logging a panic inside the span before resuming it.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_span_wrap_catch() {
+        let id = 7;
+        let (result, capture) = test_support::capture(|| {
+            std::panic::catch_unwind(|| {
+                span_wrap!(catch, level: debug, "request", {
+                    tracing::info!("handling");
+                    if id == 7 {
+                        panic!("boom {}", id);
+                    }
+                    id
+                })
+            })
+        });
+        let payload = result.unwrap_err();
+        assert_eq!(__private::panic_message(&*payload), "boom 7");
+
+        let events = capture.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].level, tracing::Level::ERROR);
+        assert_eq!(events[1].field("panic"), Some("boom 7"));
+        assert_eq!(events[1].spans, vec!["request".to_string()]);
+        assert_eq!(span_wrap!(catch, "no_panic", { 1 }), 1);
+    }
+
+    // Test span_wrap!(catch, ...) letting the panic propagate.
+    #[cfg(feature = "tracing")]
+    #[test]
+    #[should_panic(expected = "boom")]
+    fn test_span_wrap_catch_resumes() {
+        let (_capture, _guard) = test_support::install();
+        let fail = true;
+        span_wrap!(catch, "request", {
+            assert!(!fail, "boom");
+        });
+    }
+
+    // Test span_wrap_async!(catch, ...) logging a panic of the future inside the span.
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_span_wrap_async_catch() {
+        let (capture, _guard) = test_support::install();
+        let handle = tokio::spawn(async {
+            span_wrap_async!(catch, "job", async {
+                tokio::task::yield_now().await;
+                panic!("job failed");
+            })
+        });
+        assert!(handle.await.unwrap_err().is_panic());
+        assert_eq!(
+            span_wrap_async!(catch, level: debug, "ok_job", async { 2 }),
+            2
+        );
+
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].field("panic"), Some("job failed"));
+        assert_eq!(events[0].spans, vec!["job".to_string()]);
+    }
+
+    // Test call_with_trace!(catch, ...) and its async form logging a panic inside the span.
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_call_with_trace_catch() {
+        fn parse(input: &str) -> Result<i32, std::num::ParseIntError> {
+            if input.is_empty() {
+                panic!("empty input");
+            }
+            input.parse()
+        }
+        async fn load(id: u32) -> u32 {
+            tokio::task::yield_now().await;
+            assert_ne!(id, 0, "no id");
+            id
+        }
+        let (capture, _guard) = test_support::install();
+        let caught = std::panic::catch_unwind(|| call_with_trace!(catch, "parsing", parse, ""));
+        assert!(caught.is_err());
+        assert_eq!(
+            call_with_trace!(catch, result, level: debug, "parsing_ok", parse, "4"),
+            Ok(4)
+        );
+        let handle = tokio::spawn(async { call_with_trace_async!(catch, "loading", load, 0) });
+        assert!(handle.await.unwrap_err().is_panic());
+        assert_eq!(call_with_trace_async!(catch, "loading_ok", load, 5), 5);
 
-    // Test try_log! with a successful result.
+        let panics: Vec<_> = capture
+            .events()
+            .into_iter()
+            .filter(|e| e.field("panic").is_some())
+            .collect();
+        assert_eq!(panics.len(), 2);
+        assert_eq!(panics[0].level, tracing::Level::ERROR);
+        assert_eq!(panics[0].field("panic"), Some("empty input"));
+        assert_eq!(panics[0].spans, vec!["parsing".to_string()]);
+        assert!(
+            panics[1]
+                .field("panic")
+                .unwrap()
+                .starts_with("assertion `left != right` failed: no id")
+        );
+        assert_eq!(panics[1].spans, vec!["loading".to_string()]);
+    }
+
+    // Test call_with_trace!(catch, ...) letting the panic propagate.
+    #[cfg(feature = "tracing")]
     #[test]
-    fn test_try_log_ok() {
-        fn test_fn() -> Result<i32, String> {
-            let x = try_log!(Ok::<_, Box<dyn Error>>(10));
-            Ok(x)
+    #[should_panic(expected = "empty input")]
+    fn test_call_with_trace_catch_resumes() {
+        fn parse(input: &str) -> i32 {
+            assert!(!input.is_empty(), "empty input");
+            input.parse().unwrap()
         }
-        assert_eq!(test_fn().unwrap(), 10);
+        let (_capture, _guard) = test_support::install();
+        call_with_trace!(catch, "parsing", parse, "");
     }
 
-    // Test try_log! when an error occurs. It should return early.
+    // Test span_wrap! and call_with_trace! creating spans at the requested level.
+    #[cfg(feature = "tracing")]
     #[test]
-    fn test_try_log_err() {
-        fn test_fn() -> Result<i32, String> {
-            // This will trigger the error branch in try_log!.
-            let _x = try_log!(Err("error".to_string()));
-            // This line should never be reached.
-            Ok(42)
+    fn test_span_levels() {
+        fn add(a: i32, b: i32) -> i32 {
+            a + b
         }
-        let res = test_fn();
-        assert!(res.is_err());
-        assert_eq!(res.unwrap_err(), "error".to_string());
+        let (_, capture) = test_support::capture(|| {
+            span_wrap!("default_span", {});
+            span_wrap!(level: trace, "trace_span", {});
+            span_wrap!(level: error, "error_span", {});
+            assert_eq!(call_with_trace!("default_call", add, 1, 2), 3);
+            assert_eq!(call_with_trace!(level: debug, "debug_call", add, 1, 2), 3);
+        });
+        let levels: Vec<_> = capture
+            .spans()
+            .iter()
+            .map(|span| (span.name.clone(), span.level))
+            .collect();
+        assert_eq!(
+            levels,
+            vec![
+                ("default_span".to_string(), tracing::Level::INFO),
+                ("trace_span".to_string(), tracing::Level::TRACE),
+                ("error_span".to_string(), tracing::Level::ERROR),
+                ("default_call".to_string(), tracing::Level::INFO),
+                ("debug_call".to_string(), tracing::Level::DEBUG),
+            ]
+        );
     }
 
-    // Test unwrap_or_log! macro.
+    // Test log_duration! macro.
     #[test]
-    fn test_unwrap_or_log() {
-        let ok_val: Result<&str, &str> = Ok("hello");
-        let err_val: Result<&str, &str> = Err("fail");
-        let v1 = unwrap_or_log!(ok_val, "default");
-        assert_eq!(v1, "hello");
-        let v2 = unwrap_or_log!(err_val, "default");
-        assert_eq!(v2, "default");
+    fn test_log_duration() {
+        let value = log_duration!("duration test", { 456 });
+        assert_eq!(value, 456);
     }
 
-    // Test time_it! macro.
+    // Test log_duration! emitting structured label and duration_ms fields.
+    #[cfg(all(feature = "tracing", not(feature = "timing-disabled")))]
     #[test]
-    fn test_time_it() {
-        let result = time_it!("sleep test", {
-            std::thread::sleep(Duration::from_millis(50));
-            5
+    fn test_log_duration_fields() {
+        let (value, capture) = test_support::capture(|| {
+            log_duration!("db query", {
+                std::thread::sleep(Duration::from_millis(5));
+                1
+            })
         });
-        assert_eq!(result, 5);
+        assert_eq!(value, 1);
+        let event = &capture.events()[0];
+        assert_eq!(event.field("label"), Some("db query"));
+        let duration_ms: f64 = event.field("duration_ms").unwrap().parse().unwrap();
+        assert!(duration_ms >= 5.0);
     }
 
-    // Test json_merge! macro.
+    // Test log_duration! only emitting for blocks slower than the threshold.
+    #[cfg(all(feature = "tracing", not(feature = "timing-disabled")))]
     #[test]
-    fn test_json_merge() {
-        let base = json!({"a": 1, "b": 2});
-        let other = json!({"b": 3, "c": 4});
-        let merged = json_merge!(base, other);
-        assert_eq!(merged["a"], 1);
-        assert_eq!(merged["b"], 3);
-        assert_eq!(merged["c"], 4);
+    fn test_log_duration_threshold() {
+        let threshold = 20;
+        let ((fast, slow), capture) = test_support::capture(|| {
+            let fast = log_duration!("fast", min_ms = threshold, { 1 });
+            let slow = log_duration!("slow", min_ms = threshold, {
+                std::thread::sleep(Duration::from_millis(40));
+                2
+            });
+            (fast, slow)
+        });
+        assert_eq!((fast, slow), (1, 2));
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].message().starts_with("slow took"));
     }
 
-    // For debug_query!, create a dummy type with a .sql() method.
-    struct DummyQuery {
-        sql: String,
+    // Test log_duration! escalating to warn level for slow blocks.
+    #[cfg(all(feature = "tracing", not(feature = "timing-disabled")))]
+    #[test]
+    fn test_log_duration_warn_after() {
+        let label = String::from("db query");
+        let (_, capture) = test_support::capture(|| {
+            log_duration!(label.as_str(), warn_after_ms = 20, { 1 });
+            log_duration!(label.as_str(), warn_after_ms = 10 + 10, {
+                std::thread::sleep(Duration::from_millis(40));
+            });
+        });
+        let levels: Vec<_> = capture.events().iter().map(|e| e.level).collect();
+        assert_eq!(levels, vec![tracing::Level::INFO, tracing::Level::WARN]);
     }
-    impl DummyQuery {
-        fn new(sql: &str) -> Self {
-            DummyQuery {
-                sql: sql.to_string(),
+
+    // Test time_it!(nested, ...) composing labels across two levels of nesting.
+    #[cfg(not(feature = "timing-disabled"))]
+    #[test]
+    fn test_time_it_nested() {
+        let _ = set_timer_sink(recording_sink);
+        let result = time_it!(nested, "nested handler", percent = true, {
+            let rows = time_it!(nested, "db", { time_it!(nested, "serialize", { 2 }) * 20 });
+            rows + 2
+        });
+        assert_eq!(result, 42);
+        assert_eq!(recorded_timings("nested handler").len(), 1);
+        assert_eq!(recorded_timings("nested handler > db").len(), 1);
+        assert_eq!(recorded_timings("nested handler > db > serialize").len(), 1);
+        // Flat time_it! calls are unaffected by the nesting stack.
+        time_it!("nested flat", { time_it!("nested inner", {}) });
+        assert_eq!(recorded_timings("nested inner").len(), 1);
+    }
+
+    // Test time_it!(nested, percent = true, ...) logging each direct child's share.
+    #[cfg(all(feature = "tracing", not(feature = "timing-disabled")))]
+    #[test]
+    fn test_time_it_nested_percent() {
+        let (_, capture) = test_support::capture(|| {
+            time_it!(nested, "percent parent", percent = true, {
+                time_it!(nested, "first", {
+                    std::thread::sleep(Duration::from_millis(10))
+                });
+                time_it!(nested, "second", { time_it!(nested, "grandchild", {}) });
+            })
+        });
+        let events = capture.events();
+        assert_eq!(events.len(), 2);
+        let children: Vec<_> = events.iter().map(|e| e.field("child").unwrap()).collect();
+        assert_eq!(
+            children,
+            vec!["percent parent > first", "percent parent > second"]
+        );
+        let percents: Vec<f64> = events
+            .iter()
+            .map(|e| e.field("percent").unwrap().parse().unwrap())
+            .collect();
+        assert!(percents.iter().all(|p| (0.0..=100.0).contains(p)));
+        assert!(percents[0] > percents[1]);
+        assert!(percents.iter().sum::<f64>() <= 100.0);
+        assert_eq!(events[0].field("label"), Some("percent parent"));
+        assert!(
+            events[0]
+                .message()
+                .starts_with("percent parent > first took ")
+        );
+    }
+
+    // Test the rendered unit suffix of each duration format.
+    #[test]
+    fn test_duration_formats() {
+        use timing::DurationFormat;
+        let duration = Duration::from_millis(1003);
+        assert_eq!(DurationFormat::Millis.format(duration), "1003.000ms");
+        assert_eq!(DurationFormat::Micros.format(duration), "1003000us");
+        assert_eq!(DurationFormat::Secs.format(duration), "1.003s");
+        assert_eq!(DurationFormat::Human.format(duration), "1.003s");
+        assert_eq!(time_it!("fmt test", fmt = ms, { 4 }), 4);
+    }
+
+    // Test log_duration! rendering the message with the requested format.
+    #[cfg(all(feature = "tracing", not(feature = "timing-disabled")))]
+    #[test]
+    fn test_log_duration_fmt() {
+        let (_, capture) = test_support::capture(|| {
+            log_duration!("fmt ms", fmt = ms, { 1 });
+            log_duration!("fmt us", fmt = us, { 1 });
+            log_duration!("fmt s", fmt = s, { 1 });
+            log_duration!("fmt human", fmt = human, { 1 });
+        });
+        let messages: Vec<_> = capture
+            .events()
+            .iter()
+            .map(|e| e.message().to_string())
+            .collect();
+        assert!(messages[0].starts_with("fmt ms took ") && messages[0].ends_with("ms"));
+        assert!(messages[0].contains('.'));
+        assert!(messages[1].ends_with("us"));
+        assert!(
+            messages[1]
+                .trim_end_matches("us")
+                .rsplit(' ')
+                .next()
+                .unwrap()
+                .parse::<u128>()
+                .is_ok()
+        );
+        assert!(messages[2].ends_with('s') && !messages[2].ends_with("ms"));
+        assert!(messages[3].starts_with("fmt human took "));
+    }
+
+    // Test log_duration!(record: ...) recording on the current span, or logging without one.
+    #[cfg(all(feature = "tracing", not(feature = "timing-disabled")))]
+    #[test]
+    fn test_log_duration_record() {
+        let (_, capture) = test_support::capture(|| {
+            let span = tracing::info_span!("db", db_ms = tracing::field::Empty);
+            let _enter = span.enter();
+            log_duration!(record: "db_ms", {
+                std::thread::sleep(Duration::from_millis(5));
+            });
+        });
+        assert!(capture.events().is_empty());
+        let db_ms: u64 = capture.spans()[0].field("db_ms").unwrap().parse().unwrap();
+        assert!(db_ms >= 5);
+
+        let (result, capture) = test_support::capture(|| log_duration!(record: "db_ms", { 3 }));
+        assert_eq!(result, 3);
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].field("label"), Some("db_ms"));
+    }
+
+    // Test time_it! and log_duration! compiling down to their block with timing-disabled.
+    #[cfg(feature = "timing-disabled")]
+    #[test]
+    fn test_timing_disabled() {
+        let _ = set_timer_sink(recording_sink);
+        let (result, capture) = test_support::capture(|| {
+            time_it!("disabled", { 1 }) + log_duration!("disabled", warn_after_ms = 1, { 2 })
+        });
+        assert_eq!(result, 3);
+        assert!(recorded_timings("disabled").is_empty());
+        assert!(capture.events().is_empty());
+    }
+
+    // Test time_it!(sampled: ...) only emitting a percentile summary every n calls.
+    #[cfg(all(feature = "tracing", not(feature = "timing-disabled")))]
+    #[test]
+    fn test_time_it_sampled() {
+        let (_, capture) = test_support::capture(|| {
+            for i in 0..2500u64 {
+                time_it!(sampled: 1000, "sampled test", {
+                    if i % 100 == 0 {
+                        std::thread::sleep(Duration::from_micros(200));
+                    }
+                });
             }
-        }
-        fn sql(&self) -> &str {
-            &self.sql
+        });
+        let events = capture.events();
+        assert_eq!(events.len(), 2);
+        for event in events {
+            assert_eq!(event.field("samples"), Some("1000"));
+            let p50: f64 = event.field("p50_ms").unwrap().parse().unwrap();
+            let p95: f64 = event.field("p95_ms").unwrap().parse().unwrap();
+            let p99: f64 = event.field("p99_ms").unwrap().parse().unwrap();
+            assert!(p50 <= p95 && p95 <= p99);
         }
     }
+
+    // Test time_it!(announce, ...) emitting correlated start and end events.
+    #[cfg(all(feature = "tracing", not(feature = "timing-disabled")))]
     #[test]
-    fn test_debug_query() {
-        let query = DummyQuery::new("SELECT 1");
-        let _ = debug_query!(query);
-        // The macro prints the SQL; we simply ensure it does not panic.
+    fn test_time_it_announce() {
+        let (result, capture) = test_support::capture(|| {
+            time_it!(announce, "export", { 1 }) + time_it!(announce, "export", { 2 })
+        });
+        assert_eq!(result, 3);
+        let events = capture.events();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0].message(), "starting export");
+        assert!(events[1].message().starts_with("finished export in "));
+        assert!(events[1].field("duration_ms").is_some());
+        assert_eq!(
+            events[0].field("invocation_id"),
+            events[1].field("invocation_id")
+        );
+        assert_eq!(
+            events[2].field("invocation_id"),
+            events[3].field("invocation_id")
+        );
+        assert_ne!(
+            events[0].field("invocation_id"),
+            events[2].field("invocation_id")
+        );
     }
 
-    // Test with_retry! macro.
+    // Test time_it! with a threshold still returning the result.
     #[test]
-    fn test_with_retry_success() {
-        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
-        let res = with_retry!(3, 10, {
-            let current = ATTEMPTS.fetch_add(1, Ordering::SeqCst);
-            if current < 2 {
-                Err("fail")
-            } else {
-                Ok("success")
+    fn test_time_it_threshold() {
+        let result = time_it!("fast", min_ms = 1000, { 3 });
+        assert_eq!(result, 3);
+    }
+
+    // Test the timing-stats registry aggregating repeated measurements of one label.
+    #[cfg(all(feature = "timing-stats", not(feature = "timing-disabled")))]
+    #[test]
+    fn test_timing_stats() {
+        for i in 0..10 {
+            time_it!("stats test", {
+                std::thread::sleep(Duration::from_millis(i % 3));
+            });
+        }
+        let stat = timing_stats()
+            .into_iter()
+            .find(|s| s.label == "stats test")
+            .unwrap();
+        assert_eq!(stat.count, 10);
+        assert!(stat.min <= stat.mean());
+        assert!(stat.mean() <= stat.max);
+        assert!(stat.max >= Duration::from_millis(2));
+    }
+
+    // Test the timing-stats registry counting runs under a min_ms threshold as well.
+    #[cfg(all(feature = "timing-stats", not(feature = "timing-disabled")))]
+    #[test]
+    fn test_timing_stats_threshold() {
+        for slow in [false, true, false, false] {
+            let delay = Duration::from_millis(if slow { 20 } else { 0 });
+            time_it!("stats threshold time_it", min_ms = 10, {
+                std::thread::sleep(delay)
+            });
+            log_duration!("stats threshold log_duration", min_ms = 10, {
+                std::thread::sleep(delay)
+            });
+        }
+        #[allow(unused_mut)]
+        let mut labels = vec!["stats threshold time_it", "stats threshold log_duration"];
+        #[cfg(feature = "derive")]
+        {
+            #[timed(threshold_ms = 10)]
+            fn stats_threshold_timed(ms: u64) {
+                std::thread::sleep(Duration::from_millis(ms));
             }
-        });
-        assert_eq!(res.unwrap(), "success");
+            for ms in [0, 20, 0, 0] {
+                stats_threshold_timed(ms);
+            }
+            labels.push("stats_threshold_timed");
+        }
+        for label in labels {
+            let stat = timing_stats()
+                .into_iter()
+                .find(|s| s.label == label)
+                .unwrap();
+            assert_eq!(stat.count, 4);
+            assert!(stat.min < Duration::from_millis(10));
+            assert!(stat.max >= Duration::from_millis(20));
+        }
     }
 
+    // Test the timing-stats registry being fed by log_duration! from several threads.
+    #[cfg(all(feature = "timing-stats", not(feature = "timing-disabled")))]
     #[test]
-    fn test_with_retry_failure() {
-        let res: Result<&str, &str> = with_retry!(2, 10, { Err("always fails") });
-        assert!(res.is_err());
+    fn test_timing_stats_threads() {
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    for _ in 0..5 {
+                        log_duration!(String::from("stats threads"), { 1 });
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let stat = timing_stats()
+            .into_iter()
+            .find(|s| s.label == "stats threads")
+            .unwrap();
+        assert_eq!(stat.count, 20);
     }
 
-    // Test retry_async! macro.
+    // Test #[timed] on a sync fn, including early returns.
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_timed_sync() {
+        #[timed]
+        fn timed_sync(early: bool) -> u32 {
+            if early {
+                return 1;
+            }
+            42
+        }
+        let (results, capture) = test_support::capture(|| (timed_sync(true), timed_sync(false)));
+        assert_eq!(results, (1, 42));
+        let events = capture.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].field("label"), Some("timed_sync"));
+        assert_eq!(events[0].level, tracing::Level::INFO);
+    }
+
+    // Test #[timed] on an async fn with a level and threshold.
+    #[cfg(feature = "derive")]
     #[tokio::test]
-    async fn test_retry_async_success() {
-        use std::sync::Arc;
-        use tokio::sync::Mutex;
-        let attempts = Arc::new(Mutex::new(0));
-        let res = retry_async!(3, 10, {
-            let attempts = attempts.clone();
-            async move {
-                let mut att = attempts.lock().await;
-                if *att < 2 {
-                    *att += 1;
-                    Err("fail")
-                } else {
-                    Ok("success")
-                }
+    async fn test_timed_async() {
+        #[timed(level = "warn", threshold_ms = 10)]
+        async fn timed_async(ms: u64) -> u64 {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            ms
+        }
+        let (capture, _guard) = test_support::install();
+        assert_eq!(timed_async(0).await, 0);
+        assert_eq!(timed_async(30).await, 30);
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, tracing::Level::WARN);
+        assert_eq!(events[0].field("label"), Some("timed_async"));
+    }
+
+    // Test #[timed] on a generic fn returning impl Trait.
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_timed_generic() {
+        #[timed(level = "debug")]
+        fn timed_generic<T: Clone>(value: T, n: usize) -> impl Iterator<Item = T> {
+            std::iter::repeat_n(value, n)
+        }
+        let (items, capture) = test_support::capture(|| timed_generic("x", 3).collect::<Vec<_>>());
+        assert_eq!(items, vec!["x"; 3]);
+        assert_eq!(capture.events()[0].field("label"), Some("timed_generic"));
+        assert_eq!(capture.events()[0].level, tracing::Level::DEBUG);
+    }
+
+    // Test #[traced] on a sync fn recording its arguments, minus skipped ones.
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_traced_sync() {
+        #[traced(skip(password))]
+        fn login(user: &str, password: &str, attempts: u32) -> bool {
+            tracing::info!("checking");
+            !password.is_empty() && attempts < 3
+        }
+        let (ok, capture) = test_support::capture(|| login("ada", "secret", 1));
+        assert!(ok);
+        let span = &capture.spans()[0];
+        assert_eq!(span.name, "login");
+        assert_eq!(span.level, tracing::Level::INFO);
+        assert_eq!(span.field("user"), Some("\"ada\""));
+        assert_eq!(span.field("attempts"), Some("1"));
+        assert_eq!(span.field("password"), None);
+        assert_eq!(span.field("error"), None);
+        assert_eq!(capture.events()[0].spans, vec!["login".to_string()]);
+    }
+
+    // Test #[traced] on a fn returning a Result, including early returns and `?`.
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_traced_result() {
+        #[traced(level = "debug")]
+        fn parse(input: &str) -> Result<i32, std::num::ParseIntError> {
+            if input.is_empty() {
+                return Ok(0);
             }
-        });
-        assert_eq!(res.unwrap(), "success");
+            let value: i32 = input.parse()?;
+            Ok(value * 2)
+        }
+        let (results, capture) =
+            test_support::capture(|| (parse(""), parse("21"), parse("x").is_err()));
+        assert_eq!(results, (Ok(0), Ok(42), true));
+        let spans = capture.spans();
+        assert_eq!(spans[0].level, tracing::Level::DEBUG);
+        assert_eq!(spans[0].field("error"), Some("false"));
+        assert_eq!(spans[1].field("error"), Some("false"));
+        assert_eq!(spans[2].field("input"), Some("\"x\""));
+        assert_eq!(spans[2].field("error"), Some("true"));
+        assert_eq!(
+            spans[2].field("error.message"),
+            Some("ParseIntError { kind: InvalidDigit }")
+        );
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, tracing::Level::ERROR);
+        assert_eq!(events[0].spans, vec!["parse".to_string()]);
     }
 
+    // Test #[traced] instrumenting async fns and methods.
+    #[cfg(feature = "derive")]
     #[tokio::test]
-    async fn test_retry_async_failure() {
-        let res: Result<&str, &str> = retry_async!(2, 10, async { Err("fail") });
-        assert!(res.is_err());
+    async fn test_traced_async() {
+        struct Client {
+            base: u32,
+        }
+
+        impl Client {
+            #[traced]
+            async fn fetch(&self, id: u32) -> u32 {
+                tokio::task::yield_now().await;
+                tracing::info!("fetched");
+                self.base + id
+            }
+
+            #[traced(skip(token))]
+            async fn delete(&self, id: u32, token: String) -> Result<(), String> {
+                tokio::task::yield_now().await;
+                if token.is_empty() {
+                    return Err(format!("no token to delete {}", id));
+                }
+                Ok(())
+            }
+        }
+
+        let (capture, _guard) = test_support::install();
+        let client = Client { base: 10 };
+        assert_eq!(client.fetch(5).await, 15);
+        assert!(client.delete(5, String::new()).await.is_err());
+
+        let spans = capture.spans();
+        assert_eq!(spans[0].name, "fetch");
+        assert_eq!(spans[0].field("id"), Some("5"));
+        assert_eq!(spans[1].name, "delete");
+        assert_eq!(spans[1].field("token"), None);
+        assert_eq!(spans[1].field("error"), Some("true"));
+        let events = capture.events();
+        assert_eq!(events[0].spans, vec!["fetch".to_string()]);
+        assert_eq!(events[1].spans, vec!["delete".to_string()]);
     }
 
-    // Test span_wrap! macro.
+    // Test #[traced] on a generic fn.
+    #[cfg(feature = "derive")]
     #[test]
-    fn test_span_wrap() {
-        let value = span_wrap!("test_span", { 123 });
-        assert_eq!(value, 123);
+    fn test_traced_generic() {
+        #[traced(level = "trace")]
+        fn first<T: Clone + std::fmt::Debug>(items: &[T]) -> Option<T> {
+            items.first().cloned()
+        }
+        let (first, capture) = test_support::capture(|| first(&[3, 4]));
+        assert_eq!(first, Some(3));
+        assert_eq!(capture.spans()[0].level, tracing::Level::TRACE);
+        assert_eq!(capture.spans()[0].field("items"), Some("[3, 4]"));
     }
 
-    // Test log_duration! macro.
+    // Test time_it! and log_duration! recording histograms with the metrics feature.
+    #[cfg(all(feature = "metrics", not(feature = "timing-disabled")))]
     #[test]
-    fn test_log_duration() {
-        let value = log_duration!("duration test", { 456 });
-        assert_eq!(value, 456);
+    fn test_timing_metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            time_it!("metrics_time_it", { 1 });
+            log_duration!("metrics_log_duration", labels = ["table" => "users", "shard" => 3], {
+                2
+            });
+            // Under the threshold, so not logged, but still sampled.
+            time_it!("metrics_min_ms", min_ms = 60_000, { 3 });
+        });
+        let snapshot = snapshotter.snapshot().into_vec();
+        assert_eq!(snapshot.len(), 3);
+        for (key, _, _, value) in snapshot {
+            let DebugValue::Histogram(samples) = value else {
+                panic!("expected a histogram for {:?}", key);
+            };
+            assert_eq!(samples.len(), 1);
+            if key.key().name() == "metrics_log_duration" {
+                let labels: Vec<_> = key.key().labels().map(|l| (l.key(), l.value())).collect();
+                assert_eq!(labels, vec![("table", "users"), ("shard", "3")]);
+            } else {
+                assert!(["metrics_time_it", "metrics_min_ms"].contains(&key.key().name()));
+            }
+        }
     }
 
     // Test call_with_trace! macro.
+    #[cfg(feature = "tracing")]
     #[test]
     fn test_call_with_trace() {
         fn add(a: i32, b: i32) -> i32 {
@@ -559,6 +8688,102 @@ mod tests {
         assert_eq!(v2, "default");
     }
 
+    // Test log_warn! macro.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_log_warn() {
+        let ok_val: Result<&str, &str> = Ok("ok");
+        let err_val: Result<&str, &str> = Err("error");
+        let v1 = log_warn!(ok_val, "default");
+        assert_eq!(v1, "ok");
+        let (v2, capture) = test_support::capture(|| log_warn!(err_val, "default"));
+        assert_eq!(v2, "default");
+        let event = &capture.events()[0];
+        assert_eq!(event.level, tracing::Level::WARN);
+        assert_eq!(event.message(), "Error: \"error\"");
+    }
+
+    // Test log_debug! macro.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_log_debug() {
+        let ok_val: Result<&str, &str> = Ok("ok");
+        let err_val: Result<&str, &str> = Err("error");
+        let v1 = log_debug!(ok_val, "default");
+        assert_eq!(v1, "ok");
+        let (v2, capture) = test_support::capture(|| log_debug!(err_val, "default"));
+        assert_eq!(v2, "default");
+        let event = &capture.events()[0];
+        assert_eq!(event.level, tracing::Level::DEBUG);
+        assert_eq!(event.message(), "Error: \"error\"");
+    }
+
+    // Test log_error! emitting at a caller-selected level.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_log_error_level() {
+        let (v, capture) = test_support::capture(|| {
+            let a = log_error!(level: warn, Err::<i32, &str>("stale cache"), 1);
+            let b = log_error!(level: debug, Err::<i32, &str>("cache miss"), 2);
+            let c = log_error!(level: info, Ok::<i32, &str>(3), 0);
+            a + b + c
+        });
+        assert_eq!(v, 6);
+        let levels: Vec<_> = capture.events().iter().map(|e| e.level).collect();
+        assert_eq!(levels, vec![tracing::Level::WARN, tracing::Level::DEBUG]);
+    }
+
+    // Test log_error_with! running the callback after logging and before the default.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_log_error_with() {
+        use std::cell::RefCell;
+        let (capture, _guard) = test_support::install();
+        let calls = RefCell::new(Vec::new());
+
+        let value = log_error_with!(Ok::<i32, &str>(1), 0, |err: &str| {
+            calls.borrow_mut().push(err.to_string())
+        });
+        assert_eq!(value, 1);
+        assert!(calls.borrow().is_empty());
+
+        let value = log_error_with!(
+            Err::<i32, &str>("fail"),
+            {
+                calls.borrow_mut().push("default".to_string());
+                0
+            },
+            |err: &str| {
+                assert_eq!(capture.events().len(), 1);
+                calls.borrow_mut().push(err.to_string());
+            }
+        );
+        assert_eq!(value, 0);
+        assert_eq!(
+            *calls.borrow(),
+            vec!["fail".to_string(), "default".to_string()]
+        );
+    }
+
+    // Test log_error_async! only polling the fallback when the primary fails.
+    #[tokio::test]
+    async fn test_log_error_async() {
+        use std::sync::atomic::AtomicBool;
+        let fallback_polled = AtomicBool::new(false);
+        let fallback = || async {
+            fallback_polled.store(true, Ordering::SeqCst);
+            "cached"
+        };
+
+        let value = log_error_async!(async { Ok::<_, &str>("fresh") }, fallback());
+        assert_eq!(value, "fresh");
+        assert!(!fallback_polled.load(Ordering::SeqCst));
+
+        let value = log_error_async!(async { Err::<&str, _>("timeout") }, fallback());
+        assert_eq!(value, "cached");
+        assert!(fallback_polled.load(Ordering::SeqCst));
+    }
+
     // Test parse_env! macro.
     #[test]
     fn test_parse_env() {
@@ -584,4 +8809,210 @@ mod tests {
         // Call the macro to ensure it doesn't panic.
         pretty_debug!(obj);
     }
+
+    // Test pretty_debug!(..., redact: [...]) at several depths, in arrays and ignoring case.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_pretty_debug_redact() {
+        let payload = json!({
+            "user": "ada",
+            "Password": "hunter2",
+            "session": {"token": "abc", "expires": 3600, "scopes": ["read"]},
+            "headers": [
+                {"name": "accept", "Authorization": "Bearer xyz"},
+                {"nested": {"TOKEN": {"any": "value"}}}
+            ],
+            "tokens_issued": 2
+        });
+        let ((), capture) = test_support::capture(|| {
+            pretty_debug!(level: info, payload, redact: ["password", "token", "authorization"]);
+        });
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        let logged: serde_json::Value =
+            serde_json::from_str(events[0].field("json").unwrap()).unwrap();
+        assert_eq!(
+            logged,
+            json!({
+                "user": "ada",
+                "Password": "***",
+                "session": {"token": "***", "expires": 3600, "scopes": ["read"]},
+                "headers": [
+                    {"name": "accept", "Authorization": "***"},
+                    {"nested": {"TOKEN": "***"}}
+                ],
+                "tokens_issued": 2
+            })
+        );
+        assert!(!events[0].field("json").unwrap().contains("hunter2"));
+        // The object itself is left untouched.
+        assert_eq!(payload["Password"], "hunter2");
+    }
+
+    // Test each pretty_debug! limit kicking in on its own, and the defaults of `limits`.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_pretty_debug_limits() {
+        fn logged(f: impl FnOnce()) -> String {
+            let ((), capture) = test_support::capture(f);
+            capture.events()[0].field("json").unwrap().to_string()
+        }
+        let body = json!({"name": "x".repeat(10), "items": [1, 2, 3, 4, 5]});
+
+        let json = logged(|| pretty_debug!(body, max_string: 4));
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value,
+            json!({"name": "xxxx…(+6 chars)", "items": [1, 2, 3, 4, 5]})
+        );
+
+        let json = logged(|| pretty_debug!(body, max_array: 2));
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value,
+            json!({"name": "x".repeat(10), "items": [1, 2, "…(+3 items)"]})
+        );
+
+        let json = logged(|| pretty_debug!(body, max_bytes: 40));
+        assert!(json.len() <= 40, "{} bytes", json.len());
+        assert!(json.starts_with("{\n  \"name\": \"xxxxxxxxxx\""));
+        assert!(json.ends_with(" bytes)"));
+        let full = format!("{:#}", body);
+        let kept = json.rfind('…').unwrap();
+        assert_eq!(&json[kept..], format!("…(+{} bytes)", full.len() - kept));
+
+        // Limits apply at any depth, and leave short values alone.
+        let nested = json!([{"tags": ["é".repeat(300)], "ids": (0..30).collect::<Vec<_>>()}, "ok"]);
+        let json = logged(|| pretty_debug!(nested, limits, redact: ["ids"]));
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value,
+            json!([{"tags": [format!("{}…(+44 chars)", "é".repeat(256))], "ids": "***"}, "ok"])
+        );
+
+        let big = json!((0..30).map(|i| "y".repeat(i * 100)).collect::<Vec<_>>());
+        let json = logged(|| pretty_debug!(big, limits));
+        assert!(json.len() <= 16_384);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value.as_array().unwrap().len(), 21);
+        assert_eq!(value[20], "…(+10 items)");
+        assert_eq!(value[5], "y".repeat(256) + "…(+244 chars)");
+    }
+
+    // Test pretty_debug! logging a serialization failure instead of panicking.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_pretty_debug_serialization_failure() {
+        #[derive(Debug)]
+        struct Failing;
+        impl serde::Serialize for Failing {
+            fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+                Err(serde::ser::Error::custom("not today"))
+            }
+        }
+
+        let ((), capture) = test_support::capture(|| {
+            pretty_debug!(Failing);
+            pretty_debug!(stdout, Failing);
+            pretty_debug!(level: info, debug_fallback, Failing);
+        });
+        let events = capture.events();
+        assert_eq!(events.len(), 4);
+        for event in &events[..3] {
+            assert_eq!(event.level, tracing::Level::WARN);
+            assert_eq!(event.field("error"), Some("not today"));
+            assert_eq!(
+                event.message(),
+                "Could not serialize Failing for pretty_debug!: not today"
+            );
+        }
+        assert_eq!(events[3].level, tracing::Level::INFO);
+        assert_eq!(events[3].field("json"), Some("Failing"));
+
+        let by_point = std::collections::HashMap::from([((0, 0), "origin")]);
+        let ((), capture) = test_support::capture(|| pretty_debug!(debug_fallback, by_point));
+        let events = capture.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].level, tracing::Level::WARN);
+        assert_eq!(events[1].level, tracing::Level::DEBUG);
+        assert_eq!(
+            events[1].field("json"),
+            Some(format!("{:#?}", by_point).as_str())
+        );
+    }
+
+    // Test pretty_debug! logging the whole JSON as one event, at debug or a chosen level.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_pretty_debug_event() {
+        let obj = json!({"x": 1, "nested": {"y": [1, 2]}});
+        let ((), capture) = test_support::capture(|| {
+            pretty_debug!(obj);
+            pretty_debug!(level: info, obj);
+            // Printed to stdout instead of logged, so it adds no event.
+            pretty_debug!(stdout, obj);
+        });
+        let events = capture.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].level, tracing::Level::DEBUG);
+        assert_eq!(events[1].level, tracing::Level::INFO);
+        let expected = serde_json::to_string_pretty(&obj).unwrap();
+        assert!(expected.contains('\n'));
+        for event in &events {
+            assert_eq!(event.field("json"), Some(expected.as_str()));
+            assert_eq!(event.message(), "obj");
+        }
+    }
+
+    // The span macros expanded where a local `tracing` module shadows the crate, as in a
+    // crate that doesn't depend on `tracing` itself.
+    #[cfg(feature = "tracing")]
+    mod without_tracing_dependency {
+        #[allow(dead_code)]
+        mod tracing {}
+
+        fn add(a: i32, b: i32) -> i32 {
+            a + b
+        }
+
+        fn parse(s: &str) -> Result<i32, std::num::ParseIntError> {
+            s.parse()
+        }
+
+        #[cfg(feature = "actix")]
+        #[allow(dead_code)]
+        fn request_span(req: &actix_web::HttpRequest) -> crate::__private::tracing::Span {
+            http_span!(req)
+        }
+
+        #[cfg(feature = "derive")]
+        #[zirv_macros::traced]
+        async fn traced_fetch(id: u32) -> Result<u32, String> {
+            Ok(id)
+        }
+
+        // Test that every span macro compiles and runs without `tracing` in scope.
+        #[tokio::test]
+        async fn test_span_macros_without_tracing() {
+            assert_eq!(span_wrap!("plain", { 1 }), 1);
+            assert_eq!(span_wrap!(catch, level: debug, "caught", { 2 }), 2);
+            assert_eq!(span_wrap!(with_duration, "timed", { 3 }).0, 3);
+            assert_eq!(span_wrap!(sample: 0.5, "sampled", { 4 }), 4);
+            assert_eq!(span_wrap_async!("async", async { 5 }), 5);
+            assert_eq!(spawn_traced!(async { 6 }).await.unwrap(), 6);
+            assert_eq!(log_context!(tenant = "acme", { 7 }), 7);
+            assert_eq!(log_context!(tenant = "acme", async { 8 }), 8);
+            assert!(!request_id!().is_empty());
+            record_field!("rows", 9);
+            assert_eq!(call_with_trace!("adding", add, 4, 6), 10);
+            assert_eq!(call_with_trace!(result, "parsing", parse, "11"), Ok(11));
+            assert_eq!(call_with_trace_async!("adding", || async { 12 }), 12);
+            assert_eq!(
+                call_with_trace_async!(result, "parsing", || async { parse("13") }),
+                Ok(13)
+            );
+            #[cfg(feature = "derive")]
+            assert_eq!(traced_fetch(14).await, Ok(14));
+        }
+    }
 }
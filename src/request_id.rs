@@ -0,0 +1,34 @@
+//! Correlation ids for `request_id!`.
+
+/// Generates a unique request id: a UUID v4 with the `uuid` feature, otherwise the current
+/// time in milliseconds, the process id and a process-wide counter in hex, such as
+/// `192a3f4e1b2-3e8-1`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// assert_ne!(new_request_id(), new_request_id());
+/// ```
+pub fn new_request_id() -> String {
+    #[cfg(feature = "uuid")]
+    {
+        uuid::Uuid::new_v4().to_string()
+    }
+    #[cfg(not(feature = "uuid"))]
+    {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis());
+        format!(
+            "{:x}-{:x}-{:x}",
+            millis,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+}
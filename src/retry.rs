@@ -0,0 +1,509 @@
+//! Support code for the retry macros.
+
+use std::fmt;
+use std::time::Duration;
+
+/// The error of a retry loop that can end for reasons other than the operation failing,
+/// such as `retry_async!(..., cancel: token)` or `retry_async!(..., attempt_timeout_ms: 500)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RetryError<E> {
+    /// Every attempt failed (or the error was not retryable); holds the last error.
+    Exhausted(E),
+    /// The retry loop was cancelled before an attempt succeeded.
+    Cancelled,
+    /// The last attempt ran longer than `attempt_timeout_ms`; holds that budget.
+    TimedOut(Duration),
+    /// The last attempt of `with_retry_catching!` panicked; holds the panic message.
+    Panicked(String),
+}
+
+impl<E: fmt::Display> fmt::Display for RetryError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetryError::Exhausted(err) => write!(f, "retries exhausted: {}", err),
+            RetryError::Cancelled => write!(f, "retries cancelled"),
+            RetryError::TimedOut(budget) => write!(f, "last attempt timed out after {:?}", budget),
+            RetryError::Panicked(message) => write!(f, "last attempt panicked: {}", message),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for RetryError<E> {}
+
+/// The errors of every attempt of `with_retry!(all_errors, ...)`, oldest first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryErrors<E>(pub Vec<E>);
+
+impl<E> RetryErrors<E> {
+    /// The error of the last attempt.
+    pub fn last(&self) -> Option<&E> {
+        self.0.last()
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for RetryErrors<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "all {} attempts failed", self.0.len())?;
+        for (attempt, err) in self.0.iter().enumerate() {
+            write!(
+                f,
+                "{} {}: {}",
+                if attempt == 0 { ":" } else { ";" },
+                attempt + 1,
+                err
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for RetryErrors<E> {}
+
+/// Reusable retry parameters for `with_retry!(policy, expr)` and `retry_async!(policy, expr)`.
+///
+/// The builder methods are `const`, so policies can be declared as constants and shared by
+/// several call sites. Which errors to retry is decided per call site with the `if:` option.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// const DB_RETRY: RetryPolicy = RetryPolicy::new().attempts(5).exponential(100).jitter(true);
+///
+/// fn query() -> Result<u32, &'static str> { Ok(42) }
+/// assert_eq!(with_retry!(DB_RETRY, query()), Ok(42));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    attempts: u32,
+    base_ms: u64,
+    factor: u32,
+    max_delay_ms: u64,
+    jitter: bool,
+}
+
+impl RetryPolicy {
+    /// Three attempts, 100 milliseconds apart.
+    pub const fn new() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            base_ms: 100,
+            factor: 1,
+            max_delay_ms: u64::MAX,
+            jitter: false,
+        }
+    }
+
+    /// The total number of attempts, including the first one.
+    pub const fn attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts;
+        self
+    }
+
+    /// Waits the same number of milliseconds between all attempts.
+    pub const fn fixed(mut self, delay_ms: u64) -> Self {
+        self.base_ms = delay_ms;
+        self.factor = 1;
+        self
+    }
+
+    /// Waits `base_ms` milliseconds after the first attempt, doubling the delay every time.
+    pub const fn exponential(mut self, base_ms: u64) -> Self {
+        self.base_ms = base_ms;
+        self.factor = 2;
+        self
+    }
+
+    /// Multiplies the delay by `factor` after every attempt instead of doubling it.
+    pub const fn factor(mut self, factor: u32) -> Self {
+        self.factor = factor;
+        self
+    }
+
+    /// Caps every delay at `max_delay_ms` milliseconds.
+    pub const fn max_delay(mut self, max_delay_ms: u64) -> Self {
+        self.max_delay_ms = max_delay_ms;
+        self
+    }
+
+    /// Randomizes every delay like the `jitter` flag of the retry macros.
+    pub const fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub const fn max_attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// The delay after failed `attempt` (1-based).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = u64::from(self.factor).saturating_pow(attempt.saturating_sub(1));
+        let delay =
+            Duration::from_millis(self.base_ms.saturating_mul(factor).min(self.max_delay_ms));
+        if self.jitter { jitter(delay) } else { delay }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The delay after failed `attempt` (1-based) with exponential backoff: `base_ms` doubled
+/// for every attempt after the first, saturating at `max_ms`.
+#[doc(hidden)]
+pub fn exponential_delay(base_ms: u64, max_ms: u64, attempt: u32) -> Duration {
+    let factor = 1u64
+        .checked_shl(attempt.saturating_sub(1))
+        .unwrap_or(u64::MAX);
+    Duration::from_millis(base_ms.saturating_mul(factor).min(max_ms))
+}
+
+/// Randomizes a delay uniformly in `[delay / 2, delay]` ("equal jitter"), so clients failing
+/// at the same time don't retry in lockstep.
+#[doc(hidden)]
+pub fn jitter(delay: Duration) -> Duration {
+    let half = delay / 2;
+    let spread = (delay - half).as_nanos() as u64;
+    half + Duration::from_nanos(random_u64() % spread.saturating_add(1))
+}
+
+/// The next delay with "decorrelated jitter": random between `base` and three times the
+/// `previous` delay, capped at `cap`.
+#[doc(hidden)]
+pub fn decorrelated_jitter(base: Duration, cap: Duration, previous: Duration) -> Duration {
+    decorrelated_delay(base, cap, previous, random_u64())
+}
+
+/// [`decorrelated_jitter`] with the random number passed in.
+#[doc(hidden)]
+pub fn decorrelated_delay(
+    base: Duration,
+    cap: Duration,
+    previous: Duration,
+    random: u64,
+) -> Duration {
+    let upper = previous.saturating_mul(3).max(base);
+    let spread = (upper - base).as_nanos() as u64;
+    (base + Duration::from_nanos(random % spread.saturating_add(1))).min(cap)
+}
+
+/// A xorshift64* generator per thread, seeded from the clock and the thread. Not suitable
+/// for anything but spreading out retries and sampling spans.
+pub(crate) fn random_u64() -> u64 {
+    use std::cell::Cell;
+    use std::hash::{BuildHasher, RandomState};
+
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(std::time::Instant::now()) | 1);
+    }
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    })
+}
+
+/// The delay before the next attempt as computed by a retry macro's delay closure, where
+/// `None` means giving up.
+#[doc(hidden)]
+pub trait NextDelay {
+    fn next_delay(self) -> Option<Duration>;
+}
+
+impl NextDelay for Duration {
+    fn next_delay(self) -> Option<Duration> {
+        Some(self)
+    }
+}
+
+impl NextDelay for Option<Duration> {
+    fn next_delay(self) -> Option<Duration> {
+        self
+    }
+}
+
+/// Resets a `policy:` backoff before the first attempt.
+#[cfg(feature = "backoff")]
+#[doc(hidden)]
+pub fn backoff<B: backoff::backoff::Backoff>(mut backoff: B) -> B {
+    backoff.reset();
+    backoff
+}
+
+/// The delay before the next attempt of a `policy:` backoff.
+#[cfg(feature = "backoff")]
+#[doc(hidden)]
+pub fn next_backoff<B: backoff::backoff::Backoff>(backoff: &mut B) -> Option<Duration> {
+    backoff.next_backoff()
+}
+
+/// Pins down the signature of an `if:` predicate so closures accept any borrow of the error.
+#[doc(hidden)]
+pub fn predicate<E, F: FnMut(&E) -> bool>(predicate: F) -> F {
+    predicate
+}
+
+/// Pins down the signature of a `delay_fn:` closure like [`predicate`] does.
+#[doc(hidden)]
+pub fn delay_fn<F: FnMut(u32) -> u64>(delay_fn: F) -> F {
+    delay_fn
+}
+
+/// Pins down the signature of a `sleep_with:` function like [`predicate`] does.
+#[doc(hidden)]
+pub fn sleeper<F, Fut>(sleep: F) -> F
+where
+    F: FnMut(Duration) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    sleep
+}
+
+/// Pins down the signature of an `on_retry:` callback like [`predicate`] does.
+#[doc(hidden)]
+pub fn on_retry<E, F: FnMut(u32, &E)>(on_retry: F) -> F {
+    on_retry
+}
+
+/// Waits until the token of a `cancel:` option is cancelled.
+#[cfg(feature = "cancellation")]
+#[doc(hidden)]
+pub fn cancelled(
+    token: &tokio_util::sync::CancellationToken,
+) -> tokio_util::sync::WaitForCancellationFuture<'_> {
+    token.cancelled()
+}
+
+/// A failed `retry_http!` attempt worth retrying.
+#[cfg(feature = "reqwest")]
+#[doc(hidden)]
+pub struct HttpRetry {
+    /// The delay the server asked for with `Retry-After`, if any.
+    pub retry_after: Option<Duration>,
+    /// The status line or network error, for logging.
+    pub reason: String,
+}
+
+/// Classifies the outcome of a `retry_http!` attempt: `429 Too Many Requests`,
+/// `503 Service Unavailable` and network errors are retried, anything else is returned as is.
+#[cfg(feature = "reqwest")]
+#[doc(hidden)]
+pub fn http_retry(result: &Result<reqwest::Response, reqwest::Error>) -> Option<HttpRetry> {
+    use reqwest::StatusCode;
+
+    match result {
+        Ok(response) => match response.status() {
+            status @ (StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE) => {
+                Some(HttpRetry {
+                    retry_after: response
+                        .headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| retry_after(value, std::time::SystemTime::now())),
+                    reason: status.to_string(),
+                })
+            }
+            _ => None,
+        },
+        Err(err) if err.is_connect() || err.is_timeout() || err.is_request() => Some(HttpRetry {
+            retry_after: None,
+            reason: err.to_string(),
+        }),
+        Err(_) => None,
+    }
+}
+
+/// Parses a `Retry-After` value, either a number of seconds or an HTTP-date (a date in the
+/// past means no delay).
+#[cfg(feature = "reqwest")]
+#[doc(hidden)]
+pub fn retry_after(value: &str, now: std::time::SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    match value.parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()
+            .map(|date| date.duration_since(now).unwrap_or(Duration::ZERO)),
+    }
+}
+
+/// Whether a `sqlx` error is worth retrying: dropped or refused connections, a pool timeout,
+/// or a PostgreSQL `serialization_failure` (`40001`) or `deadlock_detected` (`40P01`).
+/// Used as the `if:` predicate of `retry_db!`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use zirv_macros::*;
+/// assert!(is_transient_db_error(&sqlx::Error::PoolTimedOut));
+/// assert!(!is_transient_db_error(&sqlx::Error::RowNotFound));
+/// ```
+#[cfg(feature = "sqlx")]
+pub fn is_transient_db_error(err: &sqlx::Error) -> bool {
+    use std::io::ErrorKind;
+
+    match err {
+        sqlx::Error::PoolTimedOut => true,
+        sqlx::Error::Io(err) => matches!(
+            err.kind(),
+            ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::BrokenPipe
+                | ErrorKind::TimedOut
+                | ErrorKind::UnexpectedEof
+        ),
+        sqlx::Error::Database(err) => matches!(err.code().as_deref(), Some("40001" | "40P01")),
+        _ => false,
+    }
+}
+
+/// Runs the attempts of `hedge_async!`: starts another attempt whenever none has finished
+/// `after` the last one started (or right away when all running attempts failed), up to
+/// `max_parallel` attempts, and returns the first success or else the last error. Attempts
+/// still running when it returns are dropped.
+#[doc(hidden)]
+pub async fn hedge<F, Fut, T, E>(
+    after: Duration,
+    max_parallel: usize,
+    mut make_attempt: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, E>>,
+{
+    use std::task::Poll;
+
+    let max_parallel = max_parallel.max(1);
+    let mut running = vec![Box::pin(make_attempt())];
+    let mut started = 1;
+    let mut last_err = None;
+    let mut timer = Box::pin(tokio::time::sleep(after));
+    std::future::poll_fn(|cx| {
+        loop {
+            let mut i = 0;
+            while i < running.len() {
+                match running[i].as_mut().poll(cx) {
+                    Poll::Ready(Ok(val)) => return Poll::Ready(Ok(val)),
+                    Poll::Ready(Err(err)) => {
+                        drop(running.swap_remove(i));
+                        last_err = Some(err);
+                    }
+                    Poll::Pending => i += 1,
+                }
+            }
+            if started < max_parallel && (running.is_empty() || timer.as_mut().poll(cx).is_ready())
+            {
+                crate::__zirv_log!(
+                    debug,
+                    attempt = started + 1,
+                    "Starting hedged attempt {}/{}",
+                    started + 1,
+                    max_parallel
+                );
+                running.push(Box::pin(make_attempt()));
+                started += 1;
+                timer.as_mut().reset(tokio::time::Instant::now() + after);
+                continue;
+            }
+            // Nothing is running only once every attempt was started and failed.
+            if running.is_empty()
+                && let Some(err) = last_err.take()
+            {
+                return Poll::Ready(Err(err));
+            }
+            return Poll::Pending;
+        }
+    })
+    .await
+}
+
+/// Runs an attempt of `with_retry_catching!`, turning a panic into `RetryError::Panicked`
+/// (logged at error level) and an error into `RetryError::Exhausted`.
+#[doc(hidden)]
+pub fn catch_panic<T, E>(attempt: impl FnOnce() -> Result<T, E>) -> Result<T, RetryError<E>> {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(attempt)) {
+        Ok(result) => result.map_err(RetryError::Exhausted),
+        Err(payload) => {
+            let message = crate::__private::panic_message(&*payload);
+            crate::__zirv_log!(error, panic = %message, "Attempt panicked: {}", message);
+            Err(RetryError::Panicked(message))
+        }
+    }
+}
+
+/// Sleeps the current thread between two attempts of `with_retry!`.
+#[doc(hidden)]
+pub fn sleep(delay: Duration) {
+    #[cfg(test)]
+    if recorder::record(delay) {
+        return;
+    }
+    std::thread::sleep(delay);
+}
+
+/// Sleeps between two attempts of `retry_async!`.
+#[doc(hidden)]
+pub async fn sleep_async(delay: Duration) {
+    #[cfg(test)]
+    if recorder::record(delay) {
+        return;
+    }
+    tokio::time::sleep(delay).await;
+}
+
+/// Lets tests record the delays the retry macros sleep for on the current thread
+/// instead of actually sleeping.
+#[cfg(test)]
+pub(crate) mod recorder {
+    use std::cell::RefCell;
+    use std::time::Duration;
+
+    thread_local! {
+        static DELAYS: RefCell<Option<Vec<Duration>>> = const { RefCell::new(None) };
+    }
+
+    pub(super) fn record(delay: Duration) -> bool {
+        DELAYS.with(|delays| match delays.borrow_mut().as_mut() {
+            Some(delays) => {
+                delays.push(delay);
+                true
+            }
+            None => false,
+        })
+    }
+
+    /// Records delays until dropped.
+    pub(crate) struct Recording;
+
+    impl Recording {
+        pub(crate) fn start() -> Self {
+            DELAYS.with(|delays| *delays.borrow_mut() = Some(Vec::new()));
+            Recording
+        }
+
+        /// The recorded delays in milliseconds.
+        pub(crate) fn delays_ms(&self) -> Vec<u64> {
+            DELAYS.with(|delays| {
+                delays
+                    .borrow()
+                    .iter()
+                    .flatten()
+                    .map(|delay| delay.as_millis() as u64)
+                    .collect()
+            })
+        }
+    }
+
+    impl Drop for Recording {
+        fn drop(&mut self) {
+            DELAYS.with(|delays| *delays.borrow_mut() = None);
+        }
+    }
+}
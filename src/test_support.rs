@@ -0,0 +1,150 @@
+//! Test helpers for asserting on the tracing events and spans emitted by the macros.
+
+// Not every test module uses every helper.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::DefaultGuard;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// An event recorded by [`Capture`].
+#[derive(Debug, Clone)]
+pub struct CapturedEvent {
+    pub level: Level,
+    pub target: String,
+    pub fields: HashMap<String, String>,
+    /// Names of the spans the event was emitted in, outermost first.
+    pub spans: Vec<String>,
+    /// Fields of the spans the event was emitted in, inner spans overriding outer ones.
+    pub context: HashMap<String, String>,
+}
+
+impl CapturedEvent {
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+
+    pub fn message(&self) -> &str {
+        self.field("message").unwrap_or_default()
+    }
+}
+
+/// A span recorded by [`Capture`], including fields recorded after creation.
+#[derive(Debug, Clone)]
+pub struct CapturedSpan {
+    pub name: String,
+    pub level: Level,
+    pub fields: HashMap<String, String>,
+}
+
+impl CapturedSpan {
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+}
+
+/// A layer storing every event and span it sees so tests can inspect them.
+#[derive(Clone, Default)]
+pub struct Capture {
+    events: Arc<Mutex<Vec<CapturedEvent>>>,
+    spans: Arc<Mutex<Vec<CapturedSpan>>>,
+}
+
+impl Capture {
+    pub fn events(&self) -> Vec<CapturedEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    pub fn spans(&self) -> Vec<CapturedSpan> {
+        self.spans.lock().unwrap().clone()
+    }
+}
+
+/// Installs a capturing subscriber for the current thread until the guard is dropped.
+pub fn install() -> (Capture, DefaultGuard) {
+    let capture = Capture::default();
+    let subscriber = tracing_subscriber::registry().with(capture.clone());
+    let guard = tracing::subscriber::set_default(subscriber);
+    (capture, guard)
+}
+
+/// Runs `f` with a capturing subscriber and returns its result alongside the capture.
+pub fn capture<R>(f: impl FnOnce() -> R) -> (R, Capture) {
+    let (capture, _guard) = install();
+    let result = f();
+    (result, capture)
+}
+
+struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+/// Index of a captured span, stored in the registry's span extensions.
+struct SpanIndex(usize);
+
+impl<S> Layer<S> for Capture
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = HashMap::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+        let mut spans = self.spans.lock().unwrap();
+        spans.push(CapturedSpan {
+            name: attrs.metadata().name().to_string(),
+            level: *attrs.metadata().level(),
+            fields,
+        });
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanIndex(spans.len() - 1));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id)
+            && let Some(SpanIndex(index)) = span.extensions().get::<SpanIndex>()
+        {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut FieldVisitor(&mut spans[*index].fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = HashMap::new();
+        event.record(&mut FieldVisitor(&mut fields));
+        let mut spans = Vec::new();
+        let mut context = HashMap::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            let captured = self.spans.lock().unwrap();
+            for span in scope.from_root() {
+                spans.push(span.name().to_string());
+                if let Some(SpanIndex(index)) = span.extensions().get::<SpanIndex>() {
+                    context.extend(captured[*index].fields.clone());
+                }
+            }
+        }
+        self.events.lock().unwrap().push(CapturedEvent {
+            level: *event.metadata().level(),
+            target: event.metadata().target().to_string(),
+            fields,
+            spans,
+            context,
+        });
+    }
+}
@@ -0,0 +1,530 @@
+//! Timing support for the duration macros.
+//!
+//! With the `timing-stats` feature, every measurement made by `time_it!` and `log_duration!`
+//! is also recorded in a process-wide registry of cumulative statistics per label, which can
+//! be read with [`timing_stats`] and cleared with [`reset_timing_stats`].
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "timing-stats")]
+pub use stats::{TimingStat, reset_timing_stats, timing_stats};
+
+/// How the timing macros render a duration in their output, chosen with `fmt = ...`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DurationFormat {
+    /// `Duration`'s `Debug` output, e.g. `51.2ms` or `1.0034s`.
+    #[default]
+    Human,
+    /// Fixed-point milliseconds, e.g. `51.200ms`.
+    Millis,
+    /// Whole microseconds, e.g. `51200us`.
+    Micros,
+    /// Fixed-point seconds, e.g. `0.051s`.
+    Secs,
+}
+
+impl DurationFormat {
+    /// Renders `duration` in this format.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::time::Duration;
+    /// # use zirv_macros::timing::DurationFormat;
+    /// let duration = Duration::from_micros(51_200);
+    /// assert_eq!(DurationFormat::Millis.format(duration), "51.200ms");
+    /// assert_eq!(DurationFormat::Micros.format(duration), "51200us");
+    /// assert_eq!(DurationFormat::Secs.format(duration), "0.051s");
+    /// assert_eq!(DurationFormat::Human.format(duration), "51.2ms");
+    /// ```
+    pub fn format(self, duration: Duration) -> String {
+        match self {
+            DurationFormat::Human => format!("{:?}", duration),
+            DurationFormat::Millis => format!("{:.3}ms", duration.as_secs_f64() * 1000.0),
+            DurationFormat::Micros => format!("{}us", duration.as_micros()),
+            DurationFormat::Secs => format!("{:.3}s", duration.as_secs_f64()),
+        }
+    }
+}
+
+/// Records a measurement in the timing registry (`timing-stats` feature) and as a `metrics`
+/// histogram in seconds, named after the label (`metrics` feature). A no-op without either.
+#[doc(hidden)]
+#[inline]
+pub fn record_measurement(
+    label: fmt::Arguments<'_>,
+    duration: Duration,
+    labels: &[(&'static str, String)],
+) {
+    #[cfg(any(feature = "timing-stats", feature = "metrics"))]
+    {
+        let label = match label.as_str() {
+            Some(label) => std::borrow::Cow::Borrowed(label),
+            None => std::borrow::Cow::Owned(label.to_string()),
+        };
+        #[cfg(feature = "timing-stats")]
+        stats::record(&label, duration);
+        #[cfg(feature = "metrics")]
+        {
+            let labels: Vec<metrics::Label> = labels
+                .iter()
+                .map(|(key, value)| metrics::Label::new(*key, value.clone()))
+                .collect();
+            metrics::histogram!(label.into_owned(), labels).record(duration.as_secs_f64());
+        }
+    }
+    #[cfg(not(any(feature = "timing-stats", feature = "metrics")))]
+    let _ = (label, duration);
+    #[cfg(not(feature = "metrics"))]
+    let _ = labels;
+}
+
+/// Returned by `deadline!` when a block ran longer than its budget. The block's result is
+/// kept so the caller can still decide to use it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadlineExceeded<T> {
+    pub elapsed: Duration,
+    pub budget: Duration,
+    pub result: T,
+}
+
+impl<T> fmt::Display for DeadlineExceeded<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "deadline of {:?} exceeded after {:?}",
+            self.budget, self.elapsed
+        )
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for DeadlineExceeded<T> {}
+
+/// Returned by `timeout_async!` when a future did not complete within its budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutError {
+    pub budget: Duration,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out after {:?}", self.budget)
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+/// Latency percentiles over the most recent samples of a `time_it!(sampled: ...)` label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Percentiles {
+    /// Number of samples the percentiles were computed over.
+    pub samples: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// A ring buffer of the last `capacity` durations of one label.
+struct Sampler {
+    durations: Vec<Duration>,
+    next: usize,
+    calls: u64,
+}
+
+impl Sampler {
+    fn push(&mut self, duration: Duration, capacity: usize) {
+        if self.durations.len() < capacity {
+            self.durations.push(duration);
+        } else {
+            self.durations[self.next] = duration;
+        }
+        self.next = (self.next + 1) % capacity;
+        self.calls += 1;
+    }
+
+    fn percentiles(&self) -> Percentiles {
+        let mut sorted = self.durations.clone();
+        sorted.sort_unstable();
+        // Nearest-rank percentile over the buffered samples.
+        let rank = |p: usize| sorted[(sorted.len() * p).div_ceil(100).max(1) - 1];
+        Percentiles {
+            samples: sorted.len(),
+            p50: rank(50),
+            p95: rank(95),
+            p99: rank(99),
+        }
+    }
+}
+
+/// Adds a sample for `label`, keeping the last `every` durations, and returns their
+/// percentiles on every `every`th call.
+#[doc(hidden)]
+pub fn sample(label: &str, every: usize, duration: Duration) -> Option<Percentiles> {
+    type Samplers = RwLock<HashMap<String, Arc<Mutex<Sampler>>>>;
+    static SAMPLERS: OnceLock<Samplers> = OnceLock::new();
+
+    let every = every.max(1);
+    let samplers = SAMPLERS.get_or_init(Default::default);
+    let sampler = samplers.read().unwrap().get(label).cloned();
+    let sampler = match sampler {
+        Some(sampler) => sampler,
+        None => samplers
+            .write()
+            .unwrap()
+            .entry(label.to_string())
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(Sampler {
+                    durations: Vec::with_capacity(every),
+                    next: 0,
+                    calls: 0,
+                }))
+            })
+            .clone(),
+    };
+    let mut sampler = sampler.lock().unwrap();
+    sampler.push(duration, every);
+    (sampler.calls % every as u64 == 0).then(|| sampler.percentiles())
+}
+
+/// Returns a process-wide unique id correlating the start and end events of
+/// `time_it!(announce, ...)`.
+#[doc(hidden)]
+pub fn next_invocation_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A block opened by `time_it!(nested, ...)`, with the durations of its finished children.
+struct Frame {
+    label: String,
+    children: Vec<(String, Duration)>,
+}
+
+thread_local! {
+    static NESTED: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Times one `time_it!(nested, ...)` block. The label is composed with the labels of the
+/// enclosing nested blocks on the current thread, e.g. `"handler > db"`.
+#[doc(hidden)]
+pub struct NestedTimer {
+    start: Instant,
+    percent: bool,
+    finished: bool,
+}
+
+impl NestedTimer {
+    pub fn start(label: &str, percent: bool) -> Self {
+        NESTED.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            let label = match stack.last() {
+                Some(parent) => format!("{} > {}", parent.label, label),
+                None => label.to_string(),
+            };
+            stack.push(Frame {
+                label,
+                children: Vec::new(),
+            });
+        });
+        NestedTimer {
+            start: Instant::now(),
+            percent,
+            finished: false,
+        }
+    }
+
+    /// Reports the block's duration under its composed label and, with `percent`, the share
+    /// of it each direct child consumed.
+    pub fn finish(mut self) {
+        let duration = self.start.elapsed();
+        self.finished = true;
+        let Some(frame) = NESTED.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            let frame = stack.pop()?;
+            if let Some(parent) = stack.last_mut() {
+                parent.children.push((frame.label.clone(), duration));
+            }
+            Some(frame)
+        }) else {
+            return;
+        };
+        crate::hooks::report_timing(&frame.label, duration);
+        if self.percent {
+            for (child, child_duration) in &frame.children {
+                let percent = percent_of(*child_duration, duration);
+                crate::__zirv_log!(
+                    info,
+                    label = %frame.label,
+                    child = %child,
+                    percent,
+                    "{} took {:.1}% of {}",
+                    child,
+                    percent,
+                    frame.label
+                );
+            }
+        }
+    }
+}
+
+impl Drop for NestedTimer {
+    // Keeps the stack balanced when the block returns early or panics.
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = NESTED.try_with(|stack| stack.borrow_mut().pop());
+        }
+    }
+}
+
+fn percent_of(part: Duration, whole: Duration) -> f64 {
+    if whole.is_zero() {
+        return 0.0;
+    }
+    part.as_secs_f64() / whole.as_secs_f64() * 100.0
+}
+
+/// Measures a multi-step operation as a series of named laps, created with `stopwatch!`.
+///
+/// [`finish`](Stopwatch::finish), or dropping the stopwatch, logs one info event per lap,
+/// with the `label`, the `lap` name and its `lap_ms` duration, followed by one with the
+/// `label` and the `total_ms`.
+#[derive(Debug)]
+pub struct Stopwatch {
+    label: String,
+    start: Instant,
+    last: Instant,
+    laps: Vec<(String, Duration)>,
+    finished: bool,
+}
+
+impl Stopwatch {
+    pub fn start(label: impl Into<String>) -> Self {
+        let now = Instant::now();
+        Stopwatch {
+            label: label.into(),
+            start: now,
+            last: now,
+            laps: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Ends the current lap and returns its duration.
+    pub fn lap(&mut self, name: impl Into<String>) -> Duration {
+        let now = Instant::now();
+        let duration = now - self.last;
+        self.last = now;
+        self.laps.push((name.into(), duration));
+        duration
+    }
+
+    /// Logs the laps and returns the total duration since the stopwatch started.
+    pub fn finish(mut self) -> Duration {
+        self.report()
+    }
+
+    fn report(&mut self) -> Duration {
+        self.finished = true;
+        let total = self.start.elapsed();
+        for (lap, duration) in &self.laps {
+            crate::__zirv_log!(
+                info,
+                label = %self.label,
+                lap = %lap,
+                lap_ms = duration.as_secs_f64() * 1000.0,
+                "{} lap {} took {:?}",
+                self.label,
+                lap,
+                duration
+            );
+        }
+        record_measurement(format_args!("{}", self.label), total, &[]);
+        crate::__zirv_log!(
+            info,
+            label = %self.label,
+            total_ms = total.as_secs_f64() * 1000.0,
+            "{} took {:?}",
+            self.label,
+            total
+        );
+        total
+    }
+}
+
+impl Drop for Stopwatch {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.report();
+        }
+    }
+}
+
+/// Logs entering a scope and leaving it with the time spent, created with `trace_guard!`.
+///
+/// Creating the guard logs a debug event, and dropping it logs another one with a
+/// `duration_ms` field, however the scope is left. Fields given at creation are logged on
+/// both events as a `fields` field (`key=value, ...`).
+#[derive(Debug)]
+#[must_use = "the guard logs leaving the scope when it is dropped"]
+pub struct TraceGuard {
+    label: String,
+    fields: Option<String>,
+    start: Instant,
+}
+
+impl TraceGuard {
+    pub fn enter(label: impl Into<String>, fields: Vec<(&'static str, String)>) -> Self {
+        let label = label.into();
+        let fields = (!fields.is_empty()).then(|| {
+            fields
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>()
+                .join(", ")
+        });
+        crate::__zirv_log!(
+            debug,
+            label = %label,
+            fields = fields.as_deref(),
+            "entering {}",
+            label
+        );
+        TraceGuard {
+            label,
+            fields,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        let duration_ms = elapsed.as_secs_f64() * 1000.0;
+        crate::__zirv_log!(
+            debug,
+            label = %self.label,
+            fields = self.fields.as_deref(),
+            duration_ms,
+            "leaving {} after {:.1}ms",
+            self.label,
+            duration_ms
+        );
+    }
+}
+
+#[cfg(feature = "timing-stats")]
+mod stats {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, OnceLock, RwLock};
+    use std::time::Duration;
+
+    /// A snapshot of the cumulative statistics recorded for one label.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct TimingStat {
+        pub label: String,
+        pub count: u64,
+        pub total: Duration,
+        pub min: Duration,
+        pub max: Duration,
+    }
+
+    impl TimingStat {
+        /// The mean duration, or zero if nothing was recorded.
+        pub fn mean(&self) -> Duration {
+            if self.count == 0 {
+                return Duration::ZERO;
+            }
+            Duration::from_nanos((self.total.as_nanos() / u128::from(self.count)) as u64)
+        }
+    }
+
+    /// Per-label counters, updated with atomics so recording only takes the read lock.
+    struct Counters {
+        count: AtomicU64,
+        total_ns: AtomicU64,
+        min_ns: AtomicU64,
+        max_ns: AtomicU64,
+    }
+
+    impl Counters {
+        fn new() -> Self {
+            Counters {
+                count: AtomicU64::new(0),
+                total_ns: AtomicU64::new(0),
+                min_ns: AtomicU64::new(u64::MAX),
+                max_ns: AtomicU64::new(0),
+            }
+        }
+
+        fn record(&self, duration: Duration) {
+            let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+            self.count.fetch_add(1, Ordering::Relaxed);
+            self.total_ns.fetch_add(nanos, Ordering::Relaxed);
+            self.min_ns.fetch_min(nanos, Ordering::Relaxed);
+            self.max_ns.fetch_max(nanos, Ordering::Relaxed);
+        }
+    }
+
+    type Registry = RwLock<HashMap<String, Arc<Counters>>>;
+
+    fn registry() -> &'static Registry {
+        static REGISTRY: OnceLock<Registry> = OnceLock::new();
+        REGISTRY.get_or_init(Default::default)
+    }
+
+    pub(super) fn record(label: &str, duration: Duration) {
+        let counters = registry().read().unwrap().get(label).cloned();
+        let counters = match counters {
+            Some(counters) => counters,
+            None => registry()
+                .write()
+                .unwrap()
+                .entry(label.to_string())
+                .or_insert_with(|| Arc::new(Counters::new()))
+                .clone(),
+        };
+        counters.record(duration);
+    }
+
+    /// Returns a snapshot of the statistics recorded so far, sorted by label.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use zirv_macros::*;
+    /// for _ in 0..3 {
+    ///     time_it_with!("parse", { 1 + 1 });
+    /// }
+    /// let stat = timing_stats().into_iter().find(|s| s.label == "parse").unwrap();
+    /// assert_eq!(stat.count, 3);
+    /// assert!(stat.min <= stat.mean() && stat.mean() <= stat.max);
+    /// ```
+    pub fn timing_stats() -> Vec<TimingStat> {
+        let mut stats: Vec<_> = registry()
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(label, counters)| TimingStat {
+                label: label.clone(),
+                count: counters.count.load(Ordering::Relaxed),
+                total: Duration::from_nanos(counters.total_ns.load(Ordering::Relaxed)),
+                min: Duration::from_nanos(counters.min_ns.load(Ordering::Relaxed)),
+                max: Duration::from_nanos(counters.max_ns.load(Ordering::Relaxed)),
+            })
+            .collect();
+        stats.sort_by(|a, b| a.label.cmp(&b.label));
+        stats
+    }
+
+    /// Clears all recorded statistics.
+    pub fn reset_timing_stats() {
+        registry().write().unwrap().clear();
+    }
+}
@@ -0,0 +1,246 @@
+//! Attribute macros companion to `zirv-macros`, re-exported from it behind the `derive` feature.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::{FnArg, Ident, ItemFn, LitInt, LitStr, Pat, ReturnType, Type, parse_macro_input};
+
+const LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+/// Logs the duration of every call to the annotated function, like wrapping its body in
+/// `log_duration!` with the function name as the label. Works on async fns (timing starts
+/// when the future is first polled), generic fns and fns returning `impl Trait`, and the
+/// duration is logged on every exit, including early `return`s and `?`.
+///
+/// `level = "..."` picks the event level (`info` by default) and `threshold_ms = ...` only
+/// logs calls that took longer than the threshold (faster ones are still recorded in the
+/// timing statistics and metrics).
+///
+/// # Examples
+///
+/// ```rust
+/// use zirv_macros::timed;
+///
+/// #[timed]
+/// fn load() -> u32 {
+///     42
+/// }
+///
+/// #[timed(level = "warn", threshold_ms = 100)]
+/// fn parse<T: std::str::FromStr>(input: &str) -> Option<T> {
+///     input.parse().ok()
+/// }
+///
+/// assert_eq!(load(), 42);
+/// assert_eq!(parse::<u8>("7"), Some(7));
+/// ```
+#[proc_macro_attribute]
+pub fn timed(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut level = Ident::new("info", Span::call_site());
+    let mut threshold_ms: Option<LitInt> = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("level") {
+            let value: LitStr = meta.value()?.parse()?;
+            if !LEVELS.contains(&value.value().as_str()) {
+                return Err(syn::Error::new(
+                    value.span(),
+                    "unknown log level, expected one of: error, warn, info, debug, trace",
+                ));
+            }
+            level = format_ident!("{}", value.value(), span = value.span());
+            Ok(())
+        } else if meta.path.is_ident("threshold_ms") {
+            threshold_ms = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("unsupported timed argument, expected `level` or `threshold_ms`"))
+        }
+    });
+    parse_macro_input!(args with parser);
+
+    let mut function = parse_macro_input!(item as ItemFn);
+    let label = function.sig.ident.to_string();
+    let report = match threshold_ms {
+        // Fast calls still count towards the statistics and metrics, they just aren't logged.
+        Some(threshold_ms) => quote! {
+            ::zirv_macros::timing::record_measurement(format_args!("{}", #label), elapsed, &[]);
+            if elapsed > ::std::time::Duration::from_millis(#threshold_ms) {
+                ::zirv_macros::__zirv_duration_event!(@unrecorded #level, #label, elapsed);
+            }
+        },
+        None => quote! {
+            ::zirv_macros::__zirv_duration_event!(#level, #label, elapsed);
+        },
+    };
+    let body = &function.block;
+    // The duration is logged when the guard is dropped, so early returns are measured too.
+    function.block = syn::parse_quote! {{
+        struct __ZirvTimed(::std::time::Instant);
+        impl ::core::ops::Drop for __ZirvTimed {
+            fn drop(&mut self) {
+                let elapsed = self.0.elapsed();
+                #report
+            }
+        }
+        let __zirv_timed = __ZirvTimed(::std::time::Instant::now());
+        #body
+    }};
+    quote!(#function).into()
+}
+
+/// Runs every call to the annotated function inside a tracing span named after the function,
+/// with each argument recorded as a Debug field. `self` and arguments bound by a pattern
+/// are not recorded, and `skip(...)` leaves out others, e.g. secrets or values that don't
+/// implement `Debug`. Async fns are instrumented with the span, so it is only entered while
+/// the future is polled.
+///
+/// When the return type is a `Result`, the outcome is recorded on the span like
+/// `call_with_trace!(result, ...)` does: `error = false` on `Ok`, or `error = true` and the
+/// error's Debug output as `error.message` on `Err`, along with an error event in the span.
+///
+/// `level = "..."` picks the span level (`info` by default).
+///
+/// # Examples
+///
+/// ```rust
+/// use zirv_macros::traced;
+///
+/// #[traced(skip(password))]
+/// fn login(user: &str, password: &str) -> Result<u32, String> {
+///     if password.is_empty() { Err(format!("no password for {}", user)) } else { Ok(7) }
+/// }
+///
+/// #[traced(level = "debug")]
+/// async fn load(id: u32) -> u32 {
+///     id * 2
+/// }
+///
+/// #[traced]
+/// async fn fetch(id: u32) -> Result<u32, String> {
+///     if id == 0 { Err("no id".to_string()) } else { Ok(id) }
+/// }
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// assert_eq!(login("ada", "secret"), Ok(7));
+/// assert!(login("ada", "").is_err());
+/// assert_eq!(load(21).await, 42);
+/// assert!(fetch(0).await.is_err());
+/// # }
+/// ```
+#[proc_macro_attribute]
+pub fn traced(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut level = Ident::new("info", Span::call_site());
+    let mut skip: Vec<Ident> = Vec::new();
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("level") {
+            let value: LitStr = meta.value()?.parse()?;
+            if !LEVELS.contains(&value.value().as_str()) {
+                return Err(syn::Error::new(
+                    value.span(),
+                    "unknown log level, expected one of: error, warn, info, debug, trace",
+                ));
+            }
+            level = format_ident!("{}", value.value(), span = value.span());
+            Ok(())
+        } else if meta.path.is_ident("skip") {
+            meta.parse_nested_meta(|arg| {
+                skip.push(arg.path.require_ident()?.clone());
+                Ok(())
+            })
+        } else {
+            Err(meta.error("unsupported traced argument, expected `level` or `skip`"))
+        }
+    });
+    parse_macro_input!(args with parser);
+
+    let mut function = parse_macro_input!(item as ItemFn);
+    let mut recorded = Vec::new();
+    let mut named = Vec::new();
+    for input in &function.sig.inputs {
+        if let FnArg::Typed(arg) = input
+            && let Pat::Ident(pat) = &*arg.pat
+        {
+            named.push(pat.ident.clone());
+            if !skip.contains(&pat.ident) {
+                recorded.push(pat.ident.clone());
+            }
+        }
+    }
+    if let Some(unknown) = skip.iter().find(|ident| !named.contains(ident)) {
+        return syn::Error::new(unknown.span(), "no argument with this name to skip")
+            .to_compile_error()
+            .into();
+    }
+
+    let name = LitStr::new(&function.sig.ident.to_string(), function.sig.ident.span());
+    let returns_result = match &function.sig.output {
+        ReturnType::Type(_, ty) => match &**ty {
+            Type::Path(path) => path
+                .path
+                .segments
+                .last()
+                .is_some_and(|segment| segment.ident == "Result"),
+            _ => false,
+        },
+        ReturnType::Default => false,
+    };
+    let error_fields = if returns_result {
+        quote! {
+            , error = ::zirv_macros::__private::tracing::field::Empty,
+            error.message = ::zirv_macros::__private::tracing::field::Empty
+        }
+    } else {
+        quote!()
+    };
+    let span = quote! {
+        let __zirv_span = ::zirv_macros::__private::tracing::span!(
+            ::zirv_macros::__zirv_level!(#level),
+            #name
+            #(, #recorded = ?#recorded)*
+            #error_fields
+        );
+    };
+
+    let body = &function.block;
+    let output = match &function.sig.output {
+        ReturnType::Type(_, ty) => quote!(#ty),
+        ReturnType::Default => quote!(()),
+    };
+    function.block = match (function.sig.asyncness.is_some(), returns_result) {
+        (false, false) => syn::parse_quote! {{
+            #span
+            let __zirv_enter = __zirv_span.enter();
+            #body
+        }},
+        (false, true) => syn::parse_quote! {{
+            #span
+            let __zirv_enter = __zirv_span.enter();
+            // The body runs in a closure so its `return`s and `?`s still yield the result.
+            #[allow(clippy::redundant_closure_call)]
+            let __zirv_result: #output = (move || -> #output #body)();
+            ::zirv_macros::__zirv_record_result!(__zirv_span, __zirv_result);
+            __zirv_result
+        }},
+        (true, false) => syn::parse_quote! {{
+            #span
+            ::zirv_macros::__private::tracing::Instrument::instrument(
+                async move #body,
+                __zirv_span,
+            )
+            .await
+        }},
+        (true, true) => syn::parse_quote! {{
+            #span
+            let __zirv_result: #output =
+                ::zirv_macros::__private::tracing::Instrument::instrument(
+                    async move #body,
+                    __zirv_span.clone(),
+                )
+                .await;
+            ::zirv_macros::__zirv_record_result!(__zirv_span, __zirv_result);
+            __zirv_result
+        }},
+    };
+    quote!(#function).into()
+}